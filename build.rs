@@ -25,7 +25,17 @@ fn get_git_hash() -> Option<String> {
     }
 }
 
+/// 使用 protox 编译 proto, 不依赖 protoc
+fn compile_protos() {
+    let fds = protox::compile(["proto/ssequeue.proto"], ["proto"]).expect("compile proto/ssequeue.proto");
+    tonic_build::configure()
+        .build_client(false)
+        .compile_fds(fds)
+        .expect("generate grpc service");
+}
+
 fn main() {
+    compile_protos();
     if let Some(git) = get_git_hash() {
         // env::set_var("GIT_HASH", git);
         println!("cargo:rustc-env=GIT_HASH={}",&git);
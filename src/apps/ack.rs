@@ -0,0 +1,81 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use super::groups;
+use super::state::WebCache;
+
+/// 已投递但还没有确认的消息
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub queue: String,
+    pub msg: Vec<u8>,
+    /// 消息所在的组, 确认之前组内的下一条消息不会投递
+    pub group: Option<String>,
+}
+
+/// 分配一个投递 id, 不需要确认的消息也使用同一个序列
+pub fn next_id(cache: &WebCache) -> u64 {
+    cache.delivery_seq.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// 从队列中取出一条消息并记录投递, 返回投递 id 和消息
+/// - ack_timeout 为 Some 时超时没有确认的消息放回队列, None 时由调用方负责确认或放回
+pub async fn pop_deliver(cache: &WebCache, queue: &str, ack_timeout: Option<Duration>) -> Option<(u64, Vec<u8>)> {
+    let entry = cache.queue_pop_raw(queue).await?;
+    let group = groups::popped(cache, queue, entry.group.as_deref(), false).await;
+    let msg = entry.msg;
+    let id = next_id(cache);
+    let delivery = Delivery { queue: queue.to_string(), msg: msg.clone(), group };
+    cache.deliveries.lock().await.insert(id, delivery);
+    if let Some(ack_timeout) = ack_timeout {
+        expire_after(cache, id, ack_timeout);
+    }
+    Some((id, msg))
+}
+
+/// 超时没有确认时放回队列, 已经确认的消息 nack 不做任何处理
+fn expire_after(cache: &WebCache, id: u64, ack_timeout: Duration) {
+    let cache = cache.clone();
+    rocket::tokio::spawn(async move {
+        rocket::tokio::time::sleep(ack_timeout).await;
+        if nack(&cache, id, true).await.is_some() {
+            log::debug!("delivery {id} ack timeout, requeued");
+        }
+    });
+}
+
+/// 确认消息已处理, 返回确认的消息
+pub async fn ack(cache: &WebCache, id: u64) -> Option<Delivery> {
+    let delivery = cache.deliveries.lock().await.remove(&id)?;
+    if let Some(group) = &delivery.group {
+        groups::finish(cache, &delivery.queue, group).await;
+    }
+    Some(delivery)
+}
+
+/// 拒绝消息, requeue 为 true 时放回队列, 下一次获取时最先取出, 否则丢弃
+pub async fn nack(cache: &WebCache, id: u64, requeue: bool) -> Option<Delivery> {
+    let delivery = cache.deliveries.lock().await.remove(&id)?;
+    match (&delivery.group, requeue) {
+        (Some(group), true) => groups::requeue(cache, &delivery.queue, group, delivery.msg.clone()).await,
+        (Some(group), false) => groups::finish(cache, &delivery.queue, group).await,
+        (None, true) => cache.queue_requeue_msg(&delivery.queue, delivery.msg.clone()).await,
+        (None, false) => {}
+    }
+    Some(delivery)
+}
+
+/// 连接断开时将所有未确认的消息放回队列, 保持原来的投递顺序
+pub async fn requeue_all(cache: &WebCache, ids: impl IntoIterator<Item = u64>) {
+    let mut ids = ids.into_iter().collect::<Vec<_>>();
+    ids.sort_unstable_by(|a, b| b.cmp(a));
+    for id in ids {
+        nack(cache, id, true).await;
+    }
+}
+
+/// 删除已经确认或已经放回队列的投递 id
+pub async fn retain_pending(cache: &WebCache, ids: &mut std::collections::BTreeSet<u64>) {
+    let deliveries = cache.deliveries.lock().await;
+    ids.retain(|id| deliveries.contains_key(id));
+}
@@ -0,0 +1,531 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use super::auth::TokenAuth;
+use super::objects;
+use super::state::WebCache;
+use super::types::ResultBase;
+use super::{blobs, checksum, notify, quota, versions};
+use crate::config::StorageAction;
+
+use async_zip::tokio::read::fs::ZipFileReader;
+use async_zip::tokio::write::ZipFileWriter;
+use async_zip::{Compression, ZipDateTime, ZipEntryBuilder};
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::ContentType;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::tokio::io::DuplexStream;
+use rocket::tokio::{self, fs};
+use rocket::{get, post, response, Request, Response, State};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, FuturesAsyncWriteCompatExt};
+use tokio_util::io::SyncIoBridge;
+
+/// 存储空间内保存解包中间文件的隐藏目录
+const TMP_DIR: &str = ".tmp";
+
+/// 打包和发送之间的缓冲区大小
+const PIPE_BUFFER: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+enum ArchiveFormat {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    fn parse(format: &str) -> super::WebResult<Self> {
+        match format {
+            "tar" => Ok(Self::Tar),
+            "tar.gz" | "tgz" => Ok(Self::TarGz),
+            "zip" => Ok(Self::Zip),
+            _ => Err(super::WebError::new(format!("unsupported archive format: {format}"))),
+        }
+    }
+    /// 未指定格式时根据文件头判断
+    fn detect(head: &[u8]) -> Self {
+        if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+            Self::Zip
+        } else if head.starts_with(&[0x1f, 0x8b]) {
+            Self::TarGz
+        } else {
+            Self::Tar
+        }
+    }
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Tar => "tar",
+            Self::TarGz => "tar.gz",
+            Self::Zip => "zip",
+        }
+    }
+    fn content_type(self) -> ContentType {
+        match self {
+            Self::Tar => ContentType::new("application", "x-tar"),
+            Self::TarGz => ContentType::GZIP,
+            Self::Zip => ContentType::ZIP,
+        }
+    }
+}
+
+/// 打包下载, 边打包边发送, 不在内存中缓存整个压缩包
+pub struct ArchiveStream {
+    format: ArchiveFormat,
+    file_name: String,
+    reader: DuplexStream,
+}
+
+impl<'r> response::Responder<'r, 'static> for ArchiveStream {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .header(self.format.content_type())
+            .raw_header("Content-Disposition", format!("attachment; filename=\"{}\"", self.file_name))
+            .streamed_body(self.reader)
+            .ok()
+    }
+}
+
+/// 需要打包的对象 (name, path), 按名称排序
+async fn archive_objects(cache: &WebCache, bucket: &str, prefix: &str) -> super::WebResult<Vec<(String, PathBuf)>> {
+    let bucket_dir = cache.open_data_dir(bucket)?;
+    if !fs::try_exists(&bucket_dir).await.unwrap_or(false) {
+        return Err(super::WebError::new(format!("bucket not found: {bucket}")));
+    }
+    let mut files = objects::walk_objects(&bucket_dir, &bucket_dir)
+        .await?
+        .into_iter()
+        .filter(|(name, _)| name.starts_with(prefix))
+        .map(|(name, _)| {
+            let path = bucket_dir.join(&name);
+            (name, path)
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+/// 写入 tar, 大小以打开文件时为准, 避免打包过程中追加写入导致格式错误
+fn write_tar<W: Write>(writer: W, files: Vec<(String, PathBuf)>) -> std::io::Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    for (name, path) in files {
+        let file = match std::fs::File::open(&path) {
+            Ok(file) => file,
+            Err(err) => {
+                log::warn!("archive skip {name}: {err}");
+                continue;
+            }
+        };
+        let meta = file.metadata()?;
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&meta);
+        builder.append_data(&mut header, &name, file.take(meta.len()))?;
+    }
+    builder.into_inner()
+}
+
+async fn write_zip(writer: DuplexStream, files: Vec<(String, PathBuf)>) -> super::WebResult<()> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    for (name, path) in files {
+        let file = match fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(err) => {
+                log::warn!("archive skip {name}: {err}");
+                continue;
+            }
+        };
+        let meta = file.metadata().await?;
+        let mut entry = ZipEntryBuilder::new(name.into(), Compression::Deflate);
+        if let Ok(mtime) = meta.modified() {
+            entry = entry.last_modification_date(ZipDateTime::from_chrono(&mtime.into()));
+        }
+        let mut writer = zip.write_entry_stream(entry).await?.compat_write();
+        tokio::io::copy(&mut tokio::io::AsyncReadExt::take(file, meta.len()), &mut writer).await?;
+        writer.into_inner().close().await?;
+    }
+    zip.close().await?;
+    Ok(())
+}
+
+/// 在后台打包 bucket 中的对象, 返回读取压缩包的数据流
+async fn archive(cache: &WebCache, bucket: &str, format: ArchiveFormat, prefix: &str) -> super::WebResult<ArchiveStream> {
+    let files = archive_objects(cache, bucket, prefix).await?;
+    let (writer, reader) = tokio::io::duplex(PIPE_BUFFER);
+    let label = format!("{bucket}/{prefix}");
+    match format {
+        ArchiveFormat::Zip => {
+            tokio::spawn(async move {
+                if let Err(err) = write_zip(writer, files).await {
+                    log::warn!("archive {label} error: {err}");
+                }
+            });
+        }
+        ArchiveFormat::Tar | ArchiveFormat::TarGz => {
+            let bridge = SyncIoBridge::new(writer);
+            tokio::task::spawn_blocking(move || {
+                let written = match format {
+                    ArchiveFormat::TarGz => {
+                        let gz = flate2::write::GzEncoder::new(bridge, flate2::Compression::default());
+                        write_tar(gz, files).and_then(|gz| gz.finish())
+                    }
+                    _ => write_tar(bridge, files),
+                };
+                if let Err(err) = written.and_then(|mut bridge| bridge.shutdown()) {
+                    log::warn!("archive {label} error: {err}");
+                }
+            });
+        }
+    }
+    Ok(ArchiveStream { format, file_name: format!("{bucket}.{}", format.extension()), reader })
+}
+
+/// 解包结果
+#[derive(Debug, Serialize, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct ExtractReport {
+    /// 写入 bucket 的对象
+    pub extracted: Vec<String>,
+    /// 被忽略的条目: 链接, 设备文件, 不合法的路径等
+    pub skipped: Vec<String>,
+    pub bytes: u64,
+}
+
+/// 压缩包内的路径转换为对象名称, 绝对路径和包含 ".." 的路径返回 None
+fn entry_key(prefix: &str, path: &Path) -> Option<String> {
+    let mut parts = vec![];
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    if parts.is_empty() {
+        return None;
+    }
+    let key = format!("{prefix}{}", parts.join("/"));
+    objects::check_object_key(&key).ok()?;
+    Some(key)
+}
+
+/// 一次解包剩余可以写出的字节数和条目数量, 超出时中断解包
+#[derive(Debug, Clone, Copy)]
+struct ExtractBudget {
+    max_bytes: u64,
+    max_entries: u64,
+    bytes: u64,
+    entries: u64,
+}
+
+impl ExtractBudget {
+    /// 取配置的上限和 bucket 配额中较小的一个
+    fn new(cache: &WebCache, bucket: &str) -> Self {
+        let quota = cache.quota_policy(bucket);
+        let max_bytes = quota
+            .and_then(|q| q.max_bytes)
+            .map_or(cache.extract_limits.max_bytes, |max| max.min(cache.extract_limits.max_bytes));
+        let max_entries = quota
+            .and_then(|q| q.max_objects)
+            .map_or(cache.extract_limits.max_entries, |max| max.min(cache.extract_limits.max_entries));
+        Self { max_bytes, max_entries, bytes: 0, entries: 0 }
+    }
+    /// 压缩包中的每个条目都计数, 包括被忽略的条目
+    fn entry(&mut self) -> std::io::Result<()> {
+        self.entries += 1;
+        if self.entries > self.max_entries {
+            return Err(std::io::Error::other(format!("archive has more than {} entries", self.max_entries)));
+        }
+        Ok(())
+    }
+    /// 单个文件最多读取的字节数, 多读一个字节用于判断是否超出
+    fn remaining(&self) -> u64 {
+        (self.max_bytes - self.bytes).saturating_add(1)
+    }
+    fn written(&mut self, n: u64) -> std::io::Result<()> {
+        self.bytes += n;
+        if self.bytes > self.max_bytes {
+            return Err(std::io::Error::other(format!("archive expands to more than {} bytes", self.max_bytes)));
+        }
+        Ok(())
+    }
+}
+
+/// 解包 tar 中的普通文件到 staging 目录, 文件按序号命名, 不使用压缩包内的路径
+fn unpack_tar<R: Read>(
+    reader: R,
+    staging: &Path,
+    prefix: &str,
+    budget: &mut ExtractBudget,
+    report: &mut ExtractReport,
+) -> std::io::Result<BTreeMap<String, PathBuf>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut staged = BTreeMap::new();
+    for (idx, entry) in archive.entries()?.enumerate() {
+        let mut entry = entry?;
+        budget.entry()?;
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_dir() {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        let Some(key) = entry_key(prefix, &path).filter(|_| entry_type.is_file()) else {
+            report.skipped.push(path.to_string_lossy().into_owned());
+            continue;
+        };
+        let file_path = staging.join(idx.to_string());
+        let mut file = std::fs::File::create(&file_path)?;
+        budget.written(std::io::copy(&mut (&mut entry).take(budget.remaining()), &mut file)?)?;
+        staged.insert(key, file_path);
+    }
+    Ok(staged)
+}
+
+/// 解包 zip 中的普通文件到 staging 目录, 读取完成后校验 CRC32
+async fn unpack_zip(
+    archive: &Path,
+    staging: &Path,
+    prefix: &str,
+    budget: &mut ExtractBudget,
+    report: &mut ExtractReport,
+) -> super::WebResult<BTreeMap<String, PathBuf>> {
+    let zip = ZipFileReader::new(archive).await?;
+    let mut staged = BTreeMap::new();
+    for (idx, entry) in zip.file().entries().iter().enumerate() {
+        budget.entry()?;
+        let Ok(name) = entry.filename().as_str() else {
+            report.skipped.push(format!("#{idx}"));
+            continue;
+        };
+        if entry.dir()? {
+            continue;
+        }
+        let is_symlink = entry.unix_permissions().is_some_and(|mode| mode & 0o170000 == 0o120000);
+        let Some(key) = entry_key(prefix, Path::new(name)).filter(|_| !is_symlink) else {
+            report.skipped.push(name.to_string());
+            continue;
+        };
+        let mut reader = zip.reader_with_entry(idx).await?;
+        let file_path = staging.join(idx.to_string());
+        let mut file = fs::File::create(&file_path).await?;
+        let mut limited = tokio::io::AsyncReadExt::take((&mut reader).compat(), budget.remaining());
+        budget.written(tokio::io::copy(&mut limited, &mut file).await?)?;
+        if reader.compute_hash() != reader.entry().crc32() {
+            return Err(super::WebError::new(format!("crc32 mismatch: {name}")));
+        }
+        staged.insert(key, file_path);
+    }
+    Ok(staged)
+}
+
+/// 将解包出的文件写入 bucket, 与上传一样检查配额, 归档历史版本并保存摘要
+async fn ingest(cache: &WebCache, bucket: &str, name: &str, staged: &Path) -> super::WebResult<u64> {
+    let path = cache.open_object_path(bucket, name)?;
+    if cache.is_appending(bucket, name).await {
+        return Err(super::WebError::Conflict(format!("{bucket}/{name} is held open for append")));
+    }
+    let size = fs::metadata(staged).await?.len();
+    let bucket_dir = cache.open_data_dir(bucket)?;
+    let (replaced, replaced_digest) = match fs::metadata(&path).await {
+        Ok(meta) if meta.is_file() => {
+            let digest = checksum::load(&bucket_dir, name, &meta).await.map(|c| c.sha256);
+            (Some(meta.len()), digest)
+        }
+        Ok(_) => return Err(super::WebError::Conflict(format!("{bucket}/{name} is a directory"))),
+        Err(_) => (None, None),
+    };
+    if let Some(limit) = quota::write_limit(cache, bucket, replaced).await?.filter(|limit| size > *limit) {
+        return Err(quota::exceeded(bucket, limit));
+    }
+    if let Some(data_dir) = path.parent() {
+        fs::create_dir_all(data_dir).await?;
+    }
+    versions::archive_current(cache, bucket, name).await?;
+    fs::rename(staged, &path).await?;
+    let bytes = size as i64 - replaced.unwrap_or_default() as i64;
+    quota::record(cache, bucket, bytes, replaced.map_or(1, |_| 0)).await;
+    if let Some(sha256) = replaced_digest {
+        blobs::release(cache, &sha256).await;
+    }
+    checksum::remove(&bucket_dir, name).await;
+    let checksums = checksum::checksum_of(cache, bucket, name).await?;
+    if cache.dedup {
+        blobs::dedup(cache, &path, &checksums.sha256).await?;
+    }
+    notify::publish(cache, bucket, name, size, StorageAction::Put).await;
+    Ok(size)
+}
+
+async fn extract_staged(
+    cache: &WebCache,
+    bucket: &str,
+    format: Option<&str>,
+    prefix: &str,
+    overwrite: bool,
+    data: Data<'_>,
+    staging: &Path,
+) -> super::WebResult<ExtractReport> {
+    fs::create_dir_all(staging).await?;
+    let archive = staging.join("archive");
+    let received = data.open(4.gibibytes()).into_file(&archive).await?;
+    if !received.is_complete() {
+        return Err(super::WebError::new("archive too large"));
+    }
+    let format = match format {
+        Some(format) => ArchiveFormat::parse(format)?,
+        None => {
+            let mut head = [0u8; 4];
+            let n = tokio::io::AsyncReadExt::read(&mut fs::File::open(&archive).await?, &mut head).await?;
+            ArchiveFormat::detect(&head[..n])
+        }
+    };
+
+    let mut report = ExtractReport::default();
+    let mut budget = ExtractBudget::new(cache, bucket);
+    let staged = match format {
+        ArchiveFormat::Zip => unpack_zip(&archive, staging, prefix, &mut budget, &mut report).await?,
+        ArchiveFormat::Tar | ArchiveFormat::TarGz => {
+            let (archive, staging, prefix) = (archive.clone(), staging.to_path_buf(), prefix.to_string());
+            let (staged, skipped) = tokio::task::spawn_blocking(move || {
+                let file = std::fs::File::open(&archive)?;
+                let mut report = ExtractReport::default();
+                let staged = match format {
+                    ArchiveFormat::TarGz => {
+                        unpack_tar(flate2::read::GzDecoder::new(file), &staging, &prefix, &mut budget, &mut report)?
+                    }
+                    _ => unpack_tar(file, &staging, &prefix, &mut budget, &mut report)?,
+                };
+                std::io::Result::Ok((staged, report.skipped))
+            })
+            .await
+            .map_err(super::WebError::new)??;
+            report.skipped = skipped;
+            staged
+        }
+    };
+
+    // 不允许覆盖时先检查所有对象, 避免只写入一部分
+    if !overwrite {
+        for name in staged.keys() {
+            if fs::try_exists(cache.open_object_path(bucket, name)?).await.unwrap_or(false) {
+                return Err(super::WebError::Conflict(format!("{bucket}/{name} already exists")));
+            }
+        }
+    }
+    for (name, file_path) in staged {
+        report.bytes += ingest(cache, bucket, &name, &file_path).await?;
+        report.extracted.push(name);
+    }
+    Ok(report)
+}
+
+/// 上传压缩包并解包到 bucket
+/// - 只解出普通文件, 链接和不合法的路径(绝对路径, "..", 隐藏文件)被忽略
+async fn extract(
+    cache: &WebCache,
+    bucket: &str,
+    format: Option<&str>,
+    prefix: Option<&str>,
+    overwrite: Option<bool>,
+    data: Data<'_>,
+) -> super::WebResult<ExtractReport> {
+    let mut staging = cache.data_workspace.join(TMP_DIR);
+    staging.push(format!("extract-{:016x}", rand::random::<u64>()));
+    let report = extract_staged(
+        cache,
+        bucket,
+        format,
+        prefix.unwrap_or_default(),
+        overwrite.unwrap_or_default(),
+        data,
+        &staging,
+    )
+    .await;
+    let _ = fs::remove_dir_all(&staging).await;
+    let report = report?;
+    log::debug!("extract {} objects into {bucket}", report.extracted.len());
+    Ok(report)
+}
+
+/// 1. archive
+/// - url
+///     - "/storage/archive"
+///     - "/storage/archive/{bucket}"
+/// - method: GET
+/// - params:
+///     - bucket: string, required
+///     - format: string, optional, tar | tar.gz | zip, 默认 tar
+///     - prefix: string, optional, 只打包名称以 prefix 开头的对象
+/// - response status: 200 body: 压缩包数据流
+#[get("/archive?<bucket>&<format>&<prefix>")]
+async fn archive1(
+    bucket: &str,
+    format: Option<&str>,
+    prefix: Option<&str>,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<ArchiveStream> {
+    auth.check_pass_root()?;
+    let format = ArchiveFormat::parse(format.unwrap_or("tar"))?;
+    archive(cache, bucket, format, prefix.unwrap_or_default()).await
+}
+
+#[get("/archive/<bucket>?<format>&<prefix>")]
+async fn archive2(
+    bucket: &str,
+    format: Option<&str>,
+    prefix: Option<&str>,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<ArchiveStream> {
+    auth.check_pass_root()?;
+    let format = ArchiveFormat::parse(format.unwrap_or("tar"))?;
+    archive(cache, bucket, format, prefix.unwrap_or_default()).await
+}
+
+/// 2. extract
+/// - url
+///     - "/storage/extract"
+///     - "/storage/extract/{bucket}"
+/// - method: POST
+/// - params:
+///     - bucket: string, required
+///     - format: string, optional, tar | tar.gz | zip, 默认根据文件头判断
+///     - prefix: string, optional, 对象名称前缀
+///     - overwrite: bool, optional, 对象已存在时是否覆盖, 默认 false
+/// - body: 压缩包
+/// - response json
+///     - {"code":0,"msg":"ok","ok":true,"data":{"extracted":["a.txt","dir/b.txt"],"skipped":["../c.txt"],"bytes":1024}}
+#[post("/extract?<bucket>&<format>&<prefix>&<overwrite>", data = "<data>")]
+async fn extract1(
+    bucket: &str,
+    format: Option<&str>,
+    prefix: Option<&str>,
+    overwrite: Option<bool>,
+    data: Data<'_>,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<ExtractReport>>> {
+    auth.check_pass_root()?;
+    let report = extract(cache, bucket, format, prefix, overwrite, data).await?;
+    Ok(Json(ResultBase::ok(report)))
+}
+
+#[post("/extract/<bucket>?<format>&<prefix>&<overwrite>", data = "<data>")]
+async fn extract2(
+    bucket: &str,
+    format: Option<&str>,
+    prefix: Option<&str>,
+    overwrite: Option<bool>,
+    data: Data<'_>,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<ExtractReport>>> {
+    auth.check_pass_root()?;
+    let report = extract(cache, bucket, format, prefix, overwrite, data).await?;
+    Ok(Json(ResultBase::ok(report)))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![archive1, archive2, extract1, extract2]
+}
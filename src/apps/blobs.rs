@@ -0,0 +1,162 @@
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use super::auth::TokenAuth;
+use super::objects;
+use super::state::WebCache;
+use super::types::ResultBase;
+
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::tokio::fs;
+use rocket::{get, State};
+
+/// 存储空间内按 sha256 保存去重内容的隐藏目录
+/// bucket 中的对象是 blob 的硬链接, 链接数减一即为引用计数
+pub const BLOBS_DIR: &str = ".blobs";
+
+/// blob 路径: {workspace}/.blobs/{sha256[..2]}/{sha256}
+fn blob_path(workspace: &Path, sha256: &str) -> PathBuf {
+    let mut path = workspace.join(BLOBS_DIR);
+    path.push(&sha256[..2.min(sha256.len())]);
+    path.push(sha256);
+    path
+}
+
+/// 同目录下的隐藏临时文件, 用于原子替换
+pub fn temp_path(path: &Path, suffix: &str) -> PathBuf {
+    let file_name = path.file_name().and_then(|s| s.to_str()).unwrap_or_default();
+    path.with_file_name(format!(".{file_name}.{suffix}"))
+}
+
+/// 对象是否为 sha256 对应 blob 的引用
+pub async fn is_blob_of(workspace: &Path, sha256: &str, meta: &std::fs::Metadata) -> bool {
+    fs::metadata(blob_path(workspace, sha256))
+        .await
+        .is_ok_and(|blob| blob.dev() == meta.dev() && blob.ino() == meta.ino())
+}
+
+/// 上传完成后将对象转换为 blob 的引用
+/// - 已存在相同内容的 blob 时, 对象替换为该 blob 的硬链接
+/// - 否则对象本身成为新的 blob
+/// - blob 的修改时间由所有引用共享, 不随上传更新, 对象的上传时间保存在摘要中, 见 [`super::checksum::modified`]
+pub async fn dedup(cache: &WebCache, path: &Path, sha256: &str) -> std::io::Result<()> {
+    let blob = blob_path(&cache.data_workspace, sha256);
+    let _guard = cache.blob_lock.lock().await;
+    if fs::try_exists(&blob).await.unwrap_or(false) {
+        let tmp = temp_path(path, "dedup");
+        let _ = fs::remove_file(&tmp).await;
+        fs::hard_link(&blob, &tmp).await?;
+        fs::rename(&tmp, path).await?;
+    } else {
+        if let Some(dir) = blob.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        match fs::hard_link(path, &blob).await {
+            Ok(_) => {}
+            // 并发上传了相同内容, 保留为独立文件
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// 追加写入前复制出独立的文件, 避免修改共享的内容
+pub async fn unshare(path: &Path) -> std::io::Result<()> {
+    if fs::metadata(path).await.is_ok_and(|m| m.nlink() > 1) {
+        let tmp = temp_path(path, "unshare");
+        fs::copy(path, &tmp).await?;
+        fs::rename(&tmp, path).await?;
+    }
+    Ok(())
+}
+
+/// 对象删除后释放 blob, 没有其他引用时删除 blob
+pub async fn release(cache: &WebCache, sha256: &str) {
+    let blob = blob_path(&cache.data_workspace, sha256);
+    let _guard = cache.blob_lock.lock().await;
+    if fs::metadata(&blob).await.is_ok_and(|m| m.nlink() == 1) && fs::remove_file(&blob).await.is_ok() {
+        log::debug!("release blob {sha256}");
+        objects::remove_empty_parents(&cache.data_workspace.join(BLOBS_DIR), &blob).await;
+    }
+}
+
+/// 列出 bucket 中引用 blob 的对象的 sha256, 删除 bucket 之后用于释放 blob
+pub async fn bucket_digests(cache: &WebCache, bucket: &str) -> Vec<String> {
+    let Ok(bucket_dir) = cache.open_data_dir(bucket) else {
+        return vec![];
+    };
+    let Ok(objects) = objects::walk_objects(&bucket_dir, &bucket_dir).await else {
+        return vec![];
+    };
+    let mut digests = vec![];
+    for (name, meta) in objects {
+        if meta.nlink() > 1 {
+            if let Some(checksums) = super::checksum::load(&bucket_dir, &name, &meta).await {
+                digests.push(checksums.sha256);
+            }
+        }
+    }
+    digests
+}
+
+/// 垃圾回收结果
+#[derive(Debug, Serialize, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct GcReport {
+    /// 仍被引用的 blob 数量
+    pub blobs: u64,
+    /// 删除的 blob 数量
+    pub removed: u64,
+    pub freed_bytes: u64,
+}
+
+/// 删除没有被任何对象引用的 blob
+pub async fn gc(workspace: &Path) -> std::io::Result<GcReport> {
+    let store = workspace.join(BLOBS_DIR);
+    let mut report = GcReport::default();
+    let mut shards = match fs::read_dir(&store).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(report),
+        Err(err) => return Err(err),
+    };
+    while let Some(shard) = shards.next_entry().await? {
+        if !shard.file_type().await?.is_dir() {
+            continue;
+        }
+        let mut blobs = fs::read_dir(shard.path()).await?;
+        while let Some(blob) = blobs.next_entry().await? {
+            let meta = blob.metadata().await?;
+            if meta.nlink() > 1 {
+                report.blobs += 1;
+                continue;
+            }
+            fs::remove_file(blob.path()).await?;
+            report.removed += 1;
+            report.freed_bytes += meta.len();
+        }
+        let _ = fs::remove_dir(shard.path()).await;
+    }
+    log::info!("blob gc: {report:?}");
+    Ok(report)
+}
+
+/// 1. blob gc
+/// - url: "/storage/blobs/gc"
+/// - method: GET
+/// - response json
+///     - {"code":0,"msg":"ok","ok":true,"data":{"blobs":10,"removed":2,"freed_bytes":1024}}
+#[get("/blobs/gc")]
+async fn gc_blobs(auth: TokenAuth, cache: &State<WebCache>) -> super::WebResult<Json<ResultBase<GcReport>>> {
+    auth.check_pass_root()?;
+    let report = {
+        let _guard = cache.blob_lock.lock().await;
+        gc(&cache.data_workspace).await?
+    };
+    Ok(Json(ResultBase::ok(report)))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![gc_blobs]
+}
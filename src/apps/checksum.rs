@@ -0,0 +1,289 @@
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::UNIX_EPOCH;
+
+use super::auth::{Headers, TokenAuth};
+use super::objects;
+use super::state::WebCache;
+use super::types::ResultBase;
+
+use base64::Engine;
+use md5::Md5;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::fs;
+use rocket::tokio::io::{AsyncReadExt, AsyncWrite};
+use rocket::{get, response, Request, State};
+use sha2::{Digest, Sha256};
+
+/// bucket 内保存对象摘要的隐藏目录
+pub const CHECKSUMS_DIR: &str = ".checksums";
+
+/// 对象内容的摘要
+#[derive(Debug, Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Checksums {
+    /// md5, hex 编码
+    pub md5: String,
+    /// sha256, hex 编码
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl Checksums {
+    /// Content-MD5 header 使用 base64 编码
+    pub fn md5_base64(&self) -> String {
+        let bytes = (0..self.md5.len())
+            .step_by(2)
+            .filter_map(|i| u8::from_str_radix(&self.md5[i..i + 2], 16).ok())
+            .collect::<Vec<_>>();
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    }
+}
+
+/// 摘要文件内容, 与对象的大小和修改时间一起保存
+/// 对象被追加或覆盖后大小/修改时间变化, 保存的摘要随之失效
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ChecksumRecord {
+    md5: String,
+    sha256: String,
+    size: u64,
+    mtime_nanos: u64,
+}
+
+fn mtime_nanos(meta: &std::fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|t| t.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// 摘要文件路径: {bucket}/.checksums/{name}.json
+fn checksum_path(bucket_dir: &Path, name: &str) -> PathBuf {
+    let mut path = bucket_dir.join(CHECKSUMS_DIR);
+    path.push(format!("{name}.json"));
+    path
+}
+
+/// 写入文件的同时计算摘要
+pub struct HashWriter<W> {
+    inner: W,
+    md5: Md5,
+    sha256: Sha256,
+}
+
+impl<W> HashWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, md5: Md5::new(), sha256: Sha256::new() }
+    }
+    /// 返回写入的文件和 (md5, sha256)
+    pub fn finish(self) -> (W, String, String) {
+        (self.inner, to_hex(&self.md5.finalize()), to_hex(&self.sha256.finalize()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashWriter<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = &poll {
+            self.md5.update(&buf[..*n]);
+            self.sha256.update(&buf[..*n]);
+        }
+        poll
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// 上传请求中携带的摘要, 统一转换为 hex 编码
+/// - Content-MD5: base64 编码
+/// - x-checksum-sha256: hex 或 base64 编码
+#[derive(Debug, Default)]
+pub struct ExpectedDigest {
+    md5: Option<String>,
+    sha256: Option<String>,
+}
+
+fn decode_digest(value: &str, len: usize) -> Option<String> {
+    let value = value.trim();
+    if value.len() == len * 2 && value.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Some(value.to_ascii_lowercase());
+    }
+    base64::engine::general_purpose::STANDARD
+        .decode(value)
+        .ok()
+        .filter(|bytes| bytes.len() == len)
+        .map(|bytes| to_hex(&bytes))
+}
+
+impl ExpectedDigest {
+    pub fn from_headers(headers: &Headers) -> super::WebResult<Self> {
+        Self::parse(
+            headers.kv.get("content-md5").map(String::as_str),
+            headers.kv.get("x-checksum-sha256").map(String::as_str),
+        )
+    }
+    /// 解析 Content-MD5 / x-checksum-sha256 的值, grpc 从 metadata 中读取
+    pub fn parse(md5: Option<&str>, sha256: Option<&str>) -> super::WebResult<Self> {
+        let md5 = match md5 {
+            Some(v) => Some(decode_digest(v, 16).ok_or_else(|| super::WebError::new("invalid Content-MD5 header"))?),
+            None => None,
+        };
+        let sha256 = match sha256 {
+            Some(v) => Some(decode_digest(v, 32).ok_or_else(|| super::WebError::new("invalid x-checksum-sha256 header"))?),
+            None => None,
+        };
+        Ok(Self { md5, sha256 })
+    }
+    /// 校验计算得到的摘要
+    pub fn verify(&self, md5: &str, sha256: &str) -> super::WebResult<()> {
+        if self.md5.as_deref().is_some_and(|v| v != md5) {
+            return Err(super::WebError::new(format!("Content-MD5 mismatch, received content md5 is {md5}")));
+        }
+        if self.sha256.as_deref().is_some_and(|v| v != sha256) {
+            return Err(super::WebError::new(format!("x-checksum-sha256 mismatch, received content sha256 is {sha256}")));
+        }
+        Ok(())
+    }
+}
+
+/// 保存对象的摘要, 文件需要已经写入完成
+pub async fn save(cache: &WebCache, bucket: &str, name: &str, md5: String, sha256: String) -> std::io::Result<Checksums> {
+    let meta = fs::metadata(cache.open_object_path(bucket, name)?).await?;
+    let record = ChecksumRecord { md5, sha256, size: meta.len(), mtime_nanos: mtime_nanos(&meta) };
+    let path = checksum_path(&cache.open_data_dir(bucket)?, name);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    fs::write(path, serde_json::to_vec(&record)?).await?;
+    Ok(Checksums { md5: record.md5, sha256: record.sha256, size: record.size })
+}
+
+/// 读取对象保存的摘要记录, 对象已经变化时返回 None
+async fn load_record(bucket_dir: &Path, name: &str, meta: &std::fs::Metadata) -> Option<ChecksumRecord> {
+    let bytes = fs::read(checksum_path(bucket_dir, name)).await.ok()?;
+    let record: ChecksumRecord = serde_json::from_slice(&bytes).ok()?;
+    if record.size != meta.len() {
+        return None;
+    }
+    // 去重后对象与 blob 共享修改时间, 与保存摘要时的修改时间不同
+    if record.mtime_nanos != mtime_nanos(meta) {
+        let workspace = bucket_dir.parent()?;
+        if !super::blobs::is_blob_of(workspace, &record.sha256, meta).await {
+            return None;
+        }
+    }
+    Some(record)
+}
+
+/// 读取对象保存的摘要, 对象已经变化时返回 None
+pub async fn load(bucket_dir: &Path, name: &str, meta: &std::fs::Metadata) -> Option<Checksums> {
+    let record = load_record(bucket_dir, name, meta).await?;
+    Some(Checksums { md5: record.md5, sha256: record.sha256, size: record.size })
+}
+
+/// 对象最近一次写入的时间, unix 时间戳(秒)
+/// - 摘要在去重之前保存, 记录的修改时间就是对象自己的写入时间
+/// - 引用 blob 的对象与其他对象共享修改时间, 优先使用摘要中的记录
+pub async fn modified(bucket_dir: &Path, name: &str, meta: &std::fs::Metadata) -> u64 {
+    let nanos = match load_record(bucket_dir, name, meta).await {
+        Some(record) if meta.nlink() > 1 => record.mtime_nanos,
+        _ => mtime_nanos(meta),
+    };
+    nanos / 1_000_000_000
+}
+
+/// 删除对象时一并删除摘要
+pub async fn remove(bucket_dir: &Path, name: &str) {
+    let path = checksum_path(bucket_dir, name);
+    if fs::remove_file(&path).await.is_ok() {
+        objects::remove_empty_parents(bucket_dir, &path).await;
+    }
+}
+
+/// 读取对象的摘要, 没有保存或已经失效时重新计算
+pub async fn checksum_of(cache: &WebCache, bucket: &str, name: &str) -> super::WebResult<Checksums> {
+    let path = cache.open_object_path(bucket, name)?;
+    let meta = fs::metadata(&path).await?;
+    if let Some(checksums) = load(&cache.open_data_dir(bucket)?, name, &meta).await {
+        return Ok(checksums);
+    }
+    let mut file = fs::File::open(&path).await?;
+    let (mut md5, mut sha256) = (Md5::new(), Sha256::new());
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        md5.update(&buf[..n]);
+        sha256.update(&buf[..n]);
+    }
+    let checksums = save(cache, bucket, name, to_hex(&md5.finalize()), to_hex(&sha256.finalize())).await?;
+    Ok(checksums)
+}
+
+/// 下载时在响应中附带对象摘要
+/// - Content-MD5: base64 编码的 md5
+/// - x-checksum-sha256: hex 编码的 sha256
+pub struct WithChecksum<R>(pub R, pub Option<Checksums>);
+
+impl<'r, 'o: 'r, R: response::Responder<'r, 'o>> response::Responder<'r, 'o> for WithChecksum<R> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'o> {
+        let mut res = self.0.respond_to(req)?;
+        if let Some(checksums) = self.1 {
+            res.set_raw_header("Content-MD5", checksums.md5_base64());
+            res.set_raw_header("x-checksum-sha256", checksums.sha256);
+        }
+        Ok(res)
+    }
+}
+
+/// 1. checksum
+/// - url
+///     - "/storage/checksum"
+///     - "/storage/checksum/{bucket}/{name}"
+/// - method: GET
+/// - params:
+///     - bucket: string, required
+///     - name: string, required
+/// - response json
+///     - {"code":0,"msg":"ok","ok":true,"data":{"md5":"...","sha256":"...","size":12}}
+#[get("/checksum?<bucket>&<name>")]
+async fn checksum1(
+    bucket: &str,
+    name: &str,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<Checksums>>> {
+    auth.check_pass_root()?;
+    Ok(Json(ResultBase::ok(checksum_of(cache, bucket, name).await?)))
+}
+
+#[get("/checksum/<bucket>/<name..>")]
+async fn checksum2(
+    bucket: &str,
+    name: PathBuf,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<Checksums>>> {
+    auth.check_pass_root()?;
+    let name = objects::object_key(&name)?;
+    Ok(Json(ResultBase::ok(checksum_of(cache, bucket, &name).await?)))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![checksum1, checksum2]
+}
@@ -4,6 +4,10 @@ use std::fmt::Display;
 pub enum WebError {
     Io(std::io::Error),
     Other(String),
+    /// 超出 bucket 容量配额
+    Quota(String),
+    /// 对象状态与请求冲突, 例如目标已存在或正在被追加写入
+    Conflict(String),
     // Timeout,
 }
 
@@ -12,6 +16,8 @@ impl Display for WebError {
         match self {
             WebError::Io(err) => write!(f, "io error: {}", err),
             WebError::Other(err) => write!(f, "other error: {}", err),
+            WebError::Quota(err) => write!(f, "quota exceeded: {}", err),
+            WebError::Conflict(err) => write!(f, "conflict: {}", err),
             // WebError::Timeout => write!(f, "timeout error"),
         }
     }
@@ -56,6 +62,12 @@ impl From<toml::ser::Error> for WebError {
     }
 }
 
+impl From<async_zip::error::ZipError> for WebError {
+    fn from(err: async_zip::error::ZipError) -> Self {
+        Self::Other(format!("zip error: {err}"))
+    }
+}
+
 impl From<std::io::Error> for WebError {
     fn from(err: std::io::Error) -> Self {
         Self::Io(err)
@@ -96,11 +108,29 @@ use rocket::{
 #[rocket::async_trait]
 impl<'r> Responder<'r, 'static> for super::error::WebError {
     fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let status = match &self {
+            WebError::Quota(_) => http::Status::PayloadTooLarge,
+            WebError::Conflict(_) => http::Status::Conflict,
+            _ => http::Status::InternalServerError,
+        };
         let body = serde_json::to_string(&ResultError::err(self)).unwrap();
         log::warn!("error occurrs: {body}");
         Response::build()
             .sized_body(body.len(), std::io::Cursor::new(body))
-            .status(http::Status::InternalServerError)
+            .status(status)
             .ok()
     }
 }
+
+/// grpc 接口返回的错误
+impl From<WebError> for tonic::Status {
+    fn from(err: WebError) -> Self {
+        match &err {
+            WebError::Io(io) if io.kind() == std::io::ErrorKind::NotFound => tonic::Status::not_found(err.to_string()),
+            WebError::Io(io) if io.kind() == std::io::ErrorKind::InvalidInput => tonic::Status::invalid_argument(err.to_string()),
+            WebError::Quota(_) => tonic::Status::resource_exhausted(err.to_string()),
+            WebError::Conflict(_) => tonic::Status::failed_precondition(err.to_string()),
+            _ => tonic::Status::internal(err.to_string()),
+        }
+    }
+}
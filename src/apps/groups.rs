@@ -0,0 +1,81 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use super::state::{QueueEntry, WebCache};
+
+/// 消息组, 同一组的消息按顺序投递, 同时只有一条在队列中或正在投递
+#[derive(Debug, Default)]
+pub struct Group {
+    /// 组内还没有完成的消息, 第一条已经放入队列或正在投递
+    msgs: VecDeque<Vec<u8>>,
+    /// 第一条消息是否在队列中等待取出
+    queued: bool,
+}
+
+/// 放入一条消息, 组内有未完成的消息时暂存, 等前一条确认后再放入队列
+pub async fn push(cache: &WebCache, queue: &str, group: Option<&str>, msg: Vec<u8>) {
+    let Some(group) = group.filter(|g| !g.is_empty()) else {
+        cache.queue_push_msg(queue, msg).await;
+        return;
+    };
+    // 持有组的锁放入队列, 取出消息时一定能找到对应的组
+    let mut groups = cache.groups.lock().await;
+    let groups = groups.entry(queue.to_string()).or_default();
+    if let Some(group) = groups.get_mut(group) {
+        group.msgs.push_back(msg);
+        return;
+    }
+    groups.insert(group.to_string(), Group { msgs: VecDeque::from([msg.clone()]), queued: true });
+    cache.queue_push_entry(queue, QueueEntry { msg, group: Some(group.to_string()) }).await;
+}
+
+/// 消息从队列中取出, group 为队列中消息带的组名, 返回消息所在的组
+///
+/// finish 为 true 时不需要确认, 立即放入组内的下一条消息; 否则等待 finish
+pub async fn popped(cache: &WebCache, queue: &str, group: Option<&str>, finish: bool) -> Option<String> {
+    let name = group?;
+    let mut all = cache.groups.lock().await;
+    let group = all.get_mut(queue)?.get_mut(name)?;
+    group.queued = false;
+    if finish {
+        release_next(cache, &mut all, queue, name).await;
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// 组内正在投递的消息已经确认或丢弃, 放入下一条消息
+pub async fn finish(cache: &WebCache, queue: &str, group: &str) {
+    let mut all = cache.groups.lock().await;
+    release_next(cache, &mut all, queue, group).await;
+}
+
+/// 未确认的消息放回队列, 下一次获取时最先取出, 组内的下一条消息继续等待
+pub async fn requeue(cache: &WebCache, queue: &str, group: &str, msg: Vec<u8>) {
+    let mut all = cache.groups.lock().await;
+    if let Some(group) = all.get_mut(queue).and_then(|groups| groups.get_mut(group)) {
+        group.queued = true;
+    }
+    cache.queue_requeue_entry(queue, QueueEntry { msg, group: Some(group.to_string()) }).await;
+}
+
+async fn release_next(cache: &WebCache, all: &mut BTreeMap<String, BTreeMap<String, Group>>, queue: &str, name: &str) {
+    let Some(groups) = all.get_mut(queue) else {
+        return;
+    };
+    let Some(group) = groups.get_mut(name) else {
+        return;
+    };
+    group.msgs.pop_front();
+    match group.msgs.front() {
+        Some(next) => {
+            group.queued = true;
+            cache.queue_push_entry(queue, QueueEntry { msg: next.clone(), group: Some(name.to_string()) }).await;
+        }
+        None => {
+            groups.remove(name);
+            if groups.is_empty() {
+                all.remove(queue);
+            }
+        }
+    }
+}
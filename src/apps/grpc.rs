@@ -0,0 +1,281 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use super::ack;
+use super::checksum::ExpectedDigest;
+use super::objects;
+use super::state::WebCache;
+use super::storage;
+
+use rocket::futures::{stream, StreamExt};
+use rocket::tokio::fs;
+use rocket::tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+use rocket::tokio::net::TcpListener;
+use rocket::tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status, Streaming};
+
+mod pb {
+    tonic::include_proto!("ssequeue.v1");
+}
+
+use pb::queue_server::{Queue, QueueServer};
+use pb::storage_server::{Storage, StorageServer};
+
+/// 单条 grpc 消息的最大长度, 大文件使用 Upload 分块上传
+const MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+/// Download 每块的大小
+const CHUNK_SIZE: usize = 64 * 1024;
+/// 没有可投递的消息时检查队列的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// 在 tonic 接口中直接用 ? 返回 Status
+#[allow(clippy::result_large_err)]
+fn check_queue(queue: &str) -> Result<(), Status> {
+    if queue.is_empty() {
+        return Err(Status::invalid_argument("queue is required"));
+    }
+    Ok(())
+}
+
+/// metadata 中的 token, 与 http 相同支持 token / _token, 也支持 authorization: Bearer {token}
+#[allow(clippy::result_large_err)]
+fn check_token(token: &Option<std::sync::Arc<String>>, request: &Request<()>) -> Result<(), Status> {
+    let Some(token) = token else {
+        return Ok(());
+    };
+    let metadata = request.metadata();
+    let given = metadata
+        .get("token")
+        .or(metadata.get("_token"))
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| {
+            metadata
+                .get("authorization")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+        });
+    if given == Some(token.as_str()) {
+        Ok(())
+    } else {
+        Err(Status::unauthenticated("token error"))
+    }
+}
+
+struct QueueService {
+    cache: WebCache,
+}
+
+impl QueueService {
+    /// 从多个队列中轮流取出消息并发送, 直到客户端关闭流
+    async fn subscription(
+        cache: WebCache,
+        queues: Vec<String>,
+        manual_ack: bool,
+        prefetch: usize,
+        tx: mpsc::Sender<Result<pb::Message, Status>>,
+    ) {
+        let mut outstanding = BTreeSet::new();
+        let mut next_queue = 0;
+        'deliver: loop {
+            ack::retain_pending(&cache, &mut outstanding).await;
+            let mut delivered = false;
+            if !manual_ack || outstanding.len() < prefetch {
+                for _ in 0..queues.len() {
+                    let idx = next_queue % queues.len();
+                    next_queue = idx + 1;
+                    let queue = &queues[idx];
+                    let popped = if manual_ack {
+                        ack::pop_deliver(&cache, queue, Some(cache.ack_timeout)).await
+                    } else {
+                        cache.queue_pop_msg(queue).await.map(|data| (0, data))
+                    };
+                    let Some((id, data)) = popped else {
+                        continue;
+                    };
+                    if manual_ack {
+                        outstanding.insert(id);
+                    }
+                    let message = pb::Message { id, queue: queue.clone(), data: data.clone() };
+                    if tx.send(Ok(message)).await.is_err() {
+                        if !manual_ack {
+                            cache.queue_requeue_msg(queue, data).await;
+                        }
+                        break 'deliver;
+                    }
+                    delivered = true;
+                    break;
+                }
+            }
+            if !delivered {
+                rocket::tokio::select! {
+                    _ = tx.closed() => break,
+                    _ = rocket::tokio::time::sleep(POLL_INTERVAL) => {}
+                }
+            }
+        }
+        ack::requeue_all(&cache, outstanding).await;
+    }
+}
+
+#[tonic::async_trait]
+impl Queue for QueueService {
+    async fn put(&self, request: Request<pb::PutRequest>) -> Result<Response<pb::PutReply>, Status> {
+        let request = request.into_inner();
+        check_queue(&request.queue)?;
+        self.cache.queue_push_msg(&request.queue, request.data).await;
+        let len = self.cache.queue_len(&request.queue).await as u64;
+        Ok(Response::new(pb::PutReply { len }))
+    }
+
+    async fn get(&self, request: Request<pb::GetRequest>) -> Result<Response<pb::GetReply>, Status> {
+        let request = request.into_inner();
+        check_queue(&request.queue)?;
+        let until = Instant::now() + Duration::from_secs(request.timeout as u64);
+        let ack_timeout = match request.ack_timeout {
+            0 => self.cache.ack_timeout,
+            n => Duration::from_secs(n as u64),
+        };
+        let popped = loop {
+            let popped = if request.manual_ack {
+                ack::pop_deliver(&self.cache, &request.queue, Some(ack_timeout)).await
+            } else {
+                self.cache.queue_pop_msg(&request.queue).await.map(|data| (0, data))
+            };
+            match popped {
+                Some(popped) => break Some(popped),
+                None if Instant::now() >= until => break None,
+                None => rocket::tokio::time::sleep(POLL_INTERVAL).await,
+            }
+        };
+        let Some((id, data)) = popped else {
+            return Ok(Response::new(pb::GetReply { message: None }));
+        };
+        let message = pb::Message { id, queue: request.queue, data };
+        Ok(Response::new(pb::GetReply { message: Some(message) }))
+    }
+
+    async fn ack(&self, request: Request<pb::AckRequest>) -> Result<Response<pb::AckReply>, Status> {
+        let found = ack::ack(&self.cache, request.into_inner().id).await.is_some();
+        Ok(Response::new(pb::AckReply { found }))
+    }
+
+    async fn nack(&self, request: Request<pb::NackRequest>) -> Result<Response<pb::AckReply>, Status> {
+        let request = request.into_inner();
+        let found = ack::nack(&self.cache, request.id, !request.discard).await.is_some();
+        Ok(Response::new(pb::AckReply { found }))
+    }
+
+    type SubscribeStream = ReceiverStream<Result<pb::Message, Status>>;
+
+    async fn subscribe(&self, request: Request<pb::SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        let request = request.into_inner();
+        if request.queues.is_empty() {
+            return Err(Status::invalid_argument("queues is required"));
+        }
+        for queue in &request.queues {
+            check_queue(queue)?;
+        }
+        let prefetch = (request.prefetch as usize).max(1);
+        // 容量为 1, 客户端断开时尽量少丢失已经取出的消息
+        let (tx, rx) = mpsc::channel(1);
+        rocket::tokio::spawn(Self::subscription(self.cache.clone(), request.queues, request.manual_ack, prefetch, tx));
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+struct StorageService {
+    cache: WebCache,
+}
+
+#[tonic::async_trait]
+impl Storage for StorageService {
+    async fn upload(&self, request: Request<Streaming<pb::UploadRequest>>) -> Result<Response<pb::UploadReply>, Status> {
+        let metadata = request.metadata();
+        let expected = ExpectedDigest::parse(
+            metadata.get("content-md5").and_then(|v| v.to_str().ok()),
+            metadata.get("x-checksum-sha256").and_then(|v| v.to_str().ok()),
+        )?;
+        let mut chunks = request.into_inner();
+        let first = chunks
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("empty upload"))?;
+        if first.bucket.is_empty() || first.name.is_empty() {
+            return Err(Status::invalid_argument("bucket and name are required in the first message"));
+        }
+        let name = objects::object_key(Path::new(&first.name)).map_err(super::WebError::from)?;
+        let data = stream::iter([Ok(std::io::Cursor::new(first.data))]).chain(chunks.map(|chunk| {
+            chunk
+                .map(|chunk| std::io::Cursor::new(chunk.data))
+                .map_err(|status| std::io::Error::other(status.message().to_string()))
+        }));
+        let reader = tokio_util::io::StreamReader::new(data);
+        let size = storage::put_object(&self.cache, &first.bucket, &name, expected, reader).await?;
+        Ok(Response::new(pb::UploadReply { size }))
+    }
+
+    type DownloadStream = ReceiverStream<Result<pb::Chunk, Status>>;
+
+    async fn download(&self, request: Request<pb::DownloadRequest>) -> Result<Response<Self::DownloadStream>, Status> {
+        let request = request.into_inner();
+        let name = objects::object_key(Path::new(&request.name)).map_err(super::WebError::from)?;
+        let path = self.cache.open_object_path(&request.bucket, &name).map_err(super::WebError::from)?;
+        let mut file = fs::File::open(path).await.map_err(super::WebError::from)?;
+        if request.offset > 0 {
+            file.seek(SeekFrom::Start(request.offset)).await.map_err(super::WebError::from)?;
+        }
+        let length = if request.length == 0 { u64::MAX } else { request.length };
+        let (tx, rx) = mpsc::channel(4);
+        rocket::tokio::spawn(async move {
+            let mut file = file.take(length);
+            loop {
+                let mut data = vec![0u8; CHUNK_SIZE];
+                let chunk = match file.read(&mut data).await {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        data.truncate(n);
+                        Ok(pb::Chunk { data })
+                    }
+                    Err(err) => Err(Status::from(super::WebError::from(err))),
+                };
+                let failed = chunk.is_err();
+                if tx.send(chunk).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// 启动 grpc 服务, 绑定失败时只记录错误, 不影响 http 服务
+// tonic 的拦截器固定返回 Result<_, Status>
+#[allow(clippy::result_large_err)]
+pub async fn serve(cache: WebCache, bind: String) {
+    let listener = match TcpListener::bind(&bind).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("grpc listen on {bind} error: {err}");
+            return;
+        }
+    };
+    log::info!("grpc on: {bind}");
+    let token = cache.token.clone();
+    let queue = QueueServer::new(QueueService { cache: cache.clone() })
+        .max_decoding_message_size(MAX_MESSAGE_LEN)
+        .max_encoding_message_size(MAX_MESSAGE_LEN);
+    let storage = StorageServer::new(StorageService { cache }).max_decoding_message_size(MAX_MESSAGE_LEN);
+    let result = tonic::transport::Server::builder()
+        .layer(tonic::service::interceptor(move |request: Request<()>| {
+            check_token(&token, &request).map(|_| request)
+        }))
+        .add_service(queue)
+        .add_service(storage)
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await;
+    if let Err(err) = result {
+        log::error!("grpc serve on {bind} error: {err}");
+    }
+}
@@ -0,0 +1,184 @@
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::auth::TokenAuth;
+use super::notify;
+use super::state::WebCache;
+use super::types::ResultBase;
+use crate::config::StorageAction;
+
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{get, State};
+
+/// 缓存的文件句柄类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "kebab-case")]
+pub enum HandleKind {
+    /// "/storage/append" 打开的对象
+    Append,
+    /// "/onlinelog/upload" 打开的日志
+    Log,
+}
+
+/// 打开句柄时登记, 已登记的句柄保留原来的写入时间, 超过 max-open 时关闭最久没有写入的句柄
+pub async fn register(cache: &WebCache, kind: HandleKind, key: &str) {
+    let total = {
+        let mut activity = cache.handle_activity.lock().await;
+        activity.entry((kind, key.to_string())).or_insert_with(SystemTime::now);
+        activity.len()
+    };
+    if let Some(max_open) = cache.handle_limits.max_open {
+        if total > max_open {
+            evict_lru(cache, max_open).await;
+        }
+    }
+}
+
+/// 写入完成后记录句柄最后一次写入的时间
+pub async fn touch(cache: &WebCache, kind: HandleKind, key: &str) {
+    if let Some(last_write) = cache.handle_activity.lock().await.get_mut(&(kind, key.to_string())) {
+        *last_write = SystemTime::now();
+    }
+}
+
+/// 句柄关闭后删除记录
+pub async fn forget(cache: &WebCache, kind: HandleKind, key: &str) {
+    cache.handle_activity.lock().await.remove(&(kind, key.to_string()));
+}
+
+/// 正在使用句柄的请求数量, 句柄已关闭时为 None
+async fn references(cache: &WebCache, kind: HandleKind, key: &str) -> Option<usize> {
+    match kind {
+        HandleKind::Append => cache.cache_append_fs.lock().await.get(key).map(|f| f.0.load(Ordering::Relaxed)),
+        HandleKind::Log => cache.cache_logs.lock().await.get(key).map(|f| f.0.load(Ordering::Relaxed)),
+    }
+}
+
+/// 关闭没有请求正在使用的句柄, 返回是否关闭
+/// - 追加写入的对象关闭时发送 close-append 通知
+async fn evict(cache: &WebCache, kind: HandleKind, key: &str, reason: &str) -> bool {
+    let closed = match kind {
+        HandleKind::Append => {
+            let mut files = cache.cache_append_fs.lock().await;
+            match files.get(key) {
+                Some(f) if f.0.load(Ordering::Relaxed) > 0 => false,
+                _ => files.remove(key).is_some(),
+            }
+        }
+        HandleKind::Log => {
+            let mut files = cache.cache_logs.lock().await;
+            match files.get(key) {
+                Some(f) if f.0.load(Ordering::Relaxed) > 0 => false,
+                _ => files.remove(key).is_some(),
+            }
+        }
+    };
+    let busy = !closed && references(cache, kind, key).await.is_some();
+    if !busy {
+        forget(cache, kind, key).await;
+    }
+    if closed {
+        log::info!("close {kind:?} handle {key}: {reason}");
+        if kind == HandleKind::Append {
+            if let Some((bucket, name)) = key.split_once('/') {
+                let size = match cache.open_object_path(bucket, name) {
+                    Ok(path) => rocket::tokio::fs::metadata(path).await.map(|m| m.len()).unwrap_or_default(),
+                    Err(_) => 0,
+                };
+                notify::publish(cache, bucket, name, size, StorageAction::CloseAppend).await;
+            }
+        }
+    }
+    closed
+}
+
+/// 按最后写入时间从旧到新排列的句柄
+async fn by_last_write(cache: &WebCache) -> Vec<((HandleKind, String), SystemTime)> {
+    let mut handles = cache
+        .handle_activity
+        .lock()
+        .await
+        .iter()
+        .map(|(key, t)| (key.clone(), *t))
+        .collect::<Vec<_>>();
+    handles.sort_by_key(|(_, t)| *t);
+    handles
+}
+
+/// 关闭最久没有写入的句柄, 直到数量不超过 max_open, 正在使用的句柄跳过
+async fn evict_lru(cache: &WebCache, max_open: usize) {
+    let handles = by_last_write(cache).await;
+    let mut open = handles.len();
+    for ((kind, key), _) in handles {
+        if open <= max_open {
+            break;
+        }
+        if evict(cache, kind, &key, "max open handles exceeded").await {
+            open -= 1;
+        }
+    }
+}
+
+/// 关闭超过 idle_timeout 没有写入的句柄
+pub async fn sweep(cache: &WebCache, idle_timeout: Duration) -> usize {
+    let expire_before = SystemTime::now() - idle_timeout;
+    let mut closed = 0;
+    for ((kind, key), last_write) in by_last_write(cache).await {
+        if last_write >= expire_before {
+            break;
+        }
+        if evict(cache, kind, &key, "idle timeout").await {
+            closed += 1;
+        }
+    }
+    closed
+}
+
+/// 后台定期关闭空闲句柄
+pub async fn run(cache: WebCache, idle_timeout: u64, interval: u64) {
+    let idle_timeout = Duration::from_secs(idle_timeout);
+    let mut interval = rocket::tokio::time::interval(Duration::from_secs(interval.max(1)));
+    loop {
+        interval.tick().await;
+        let closed = sweep(&cache, idle_timeout).await;
+        if closed > 0 {
+            log::debug!("closed {closed} idle handles");
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct HandleEntry {
+    kind: HandleKind,
+    /// append: {bucket}/{name}, log: {channel}/{name}
+    key: String,
+    /// 最后一次写入请求的时间, unix 时间戳(毫秒)
+    last_write: u64,
+    /// 正在使用句柄的请求数量
+    references: usize,
+}
+
+/// 1. open handles
+/// - url: "/storage/handles"
+/// - method: GET
+/// - response json
+///     - {"code":0,"msg":"ok","ok":true,"data":[{"kind":"append","key":"bucket/name","last_write":1718000000000,"references":0}]}
+#[get("/handles")]
+async fn list_handles(auth: TokenAuth, cache: &State<WebCache>) -> super::WebResult<Json<ResultBase<Vec<HandleEntry>>>> {
+    auth.check_pass_root()?;
+    let mut entries = vec![];
+    for ((kind, key), last_write) in by_last_write(cache).await.into_iter().rev() {
+        let Some(references) = references(cache, kind, &key).await else {
+            continue;
+        };
+        let last_write = last_write.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        entries.push(HandleEntry { kind, key, last_write, references });
+    }
+    Ok(Json(ResultBase::ok(entries)))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![list_handles]
+}
@@ -0,0 +1,72 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use super::ack;
+use super::groups;
+use super::state::WebCache;
+
+use rocket::serde::Serialize;
+
+/// 一个队列在窗口内见过的 key
+#[derive(Debug, Default)]
+pub struct SeenKeys {
+    ids: HashMap<String, u64>,
+    /// 按记录时间排列, 用于过期清理
+    order: VecDeque<(Instant, String)>,
+}
+
+impl SeenKeys {
+    fn expire(&mut self, window: Duration, max_keys: usize) {
+        let now = Instant::now();
+        while let Some((at, key)) = self.order.front() {
+            if now.duration_since(*at) < window && self.order.len() <= max_keys {
+                break;
+            }
+            self.ids.remove(key);
+            self.order.pop_front();
+        }
+    }
+}
+
+/// 带 key 的 put 的结果
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PutResult {
+    /// 去重标识, 重复的 put 返回第一次放入时的 id, 与投递时的消息 id 无关
+    pub id: u64,
+    pub duplicate: bool,
+}
+
+/// 窗口内第一次见到 key 时放入队列, 否则返回原来的去重 id
+pub async fn put_once(cache: &WebCache, queue: &str, key: &str, group: Option<&str>, msg: Vec<u8>) -> PutResult {
+    let window = Duration::from_secs(cache.idempotency.window);
+    let id = {
+        let mut seen = cache.idempotency_keys.lock().await;
+        let keys = seen.entry(queue.to_string()).or_default();
+        // 先清理超出窗口的 key, 后台清理之前过期的 key 不会被当作重复
+        keys.expire(window, cache.idempotency.max_keys);
+        if let Some(id) = keys.ids.get(key) {
+            return PutResult { id: *id, duplicate: true };
+        }
+        let id = ack::next_id(cache);
+        keys.ids.insert(key.to_string(), id);
+        keys.order.push_back((Instant::now(), key.to_string()));
+        keys.expire(window, cache.idempotency.max_keys);
+        id
+    };
+    groups::push(cache, queue, group, msg).await;
+    PutResult { id, duplicate: false }
+}
+
+/// 后台定期清理所有队列过期的 key, 长时间没有 put 的队列也能释放
+pub async fn run(cache: WebCache, interval: u64) {
+    let window = Duration::from_secs(cache.idempotency.window);
+    let mut interval = rocket::tokio::time::interval(Duration::from_secs(interval.max(1)));
+    loop {
+        interval.tick().await;
+        cache.idempotency_keys.lock().await.retain(|_, keys| {
+            keys.expire(window, cache.idempotency.max_keys);
+            !keys.ids.is_empty()
+        });
+    }
+}
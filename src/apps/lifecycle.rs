@@ -0,0 +1,232 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::auth::TokenAuth;
+use super::objects;
+use super::state::WebCache;
+use super::types::ResultBase;
+use crate::config::StorageAction;
+
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::tokio::fs;
+use rocket::{get, State};
+
+/// 对象被删除的原因
+#[derive(Debug, Serialize, Clone, Copy)]
+#[serde(crate = "rocket::serde", rename_all = "kebab-case")]
+pub enum LifecycleReason {
+    Expired,
+    MaxObjects,
+    MaxBytes,
+}
+
+/// 生命周期规则计划删除的一个对象
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct LifecycleAction {
+    pub name: String,
+    pub size: u64,
+    /// 修改时间, unix 时间戳(秒)
+    pub mtime: u64,
+    pub reason: LifecycleReason,
+}
+
+/// 一个 bucket 的生命周期执行计划
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct LifecycleReport {
+    pub bucket: String,
+    pub actions: Vec<LifecycleAction>,
+    /// 执行后释放的字节数
+    pub freed_bytes: u64,
+}
+
+/// 列出存储空间中所有 bucket
+pub async fn list_buckets(cache: &WebCache) -> std::io::Result<Vec<String>> {
+    let mut entries = fs::read_dir(cache.data_workspace.as_ref()).await?;
+    let mut buckets = vec![];
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_type().await?.is_dir() {
+            continue;
+        }
+        if let Some(name) = entry.file_name().to_str() {
+            if !name.starts_with('.') {
+                buckets.push(name.to_string());
+            }
+        }
+    }
+    buckets.sort();
+    Ok(buckets)
+}
+
+/// 计算一个 bucket 需要删除的对象, 不做实际删除
+/// 正在追加写入的对象不会被删除
+async fn plan_bucket(cache: &WebCache, bucket: &str) -> std::io::Result<Option<LifecycleReport>> {
+    let rules = cache.lifecycle_rules(bucket);
+    if rules.is_empty() {
+        return Ok(None);
+    }
+    let bucket_dir = cache.open_data_dir(bucket)?;
+    let appending = {
+        let prefix = format!("{bucket}/");
+        cache
+            .cache_append_fs
+            .lock()
+            .await
+            .keys()
+            .filter_map(|k| k.strip_prefix(&prefix).map(|s| s.to_string()))
+            .collect::<Vec<_>>()
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    // (name, size, mtime), 最新的对象在前
+    let mut objects = vec![];
+    for (name, meta) in objects::walk_objects(&bucket_dir, &bucket_dir).await? {
+        if appending.contains(&name) {
+            continue;
+        }
+        let mtime = super::checksum::modified(&bucket_dir, &name, &meta).await;
+        objects.push((name, meta.len(), mtime));
+    }
+    objects.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+
+    let mut actions = vec![];
+    for rule in rules {
+        let mut kept = vec![];
+        let mut total_bytes = 0u64;
+        // 从最新的对象开始累计, 超出 max-bytes 后更旧的对象全部删除
+        let mut over_bytes = false;
+        for (name, size, mtime) in objects {
+            let reason = if rule.expire_days.is_some_and(|days| mtime.saturating_add(days.saturating_mul(24 * 3600)) < now) {
+                Some(LifecycleReason::Expired)
+            } else if rule.max_objects.is_some_and(|max| kept.len() >= max) {
+                Some(LifecycleReason::MaxObjects)
+            } else if over_bytes || rule.max_bytes.is_some_and(|max| total_bytes.saturating_add(size) > max) {
+                over_bytes = true;
+                Some(LifecycleReason::MaxBytes)
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                actions.push(LifecycleAction { name, size, mtime, reason });
+            } else {
+                total_bytes += size;
+                kept.push((name, size, mtime));
+            }
+        }
+        objects = kept;
+    }
+    let freed_bytes = actions.iter().map(|a| a.size).sum();
+    Ok(Some(LifecycleReport { bucket: bucket.to_string(), actions, freed_bytes }))
+}
+
+/// 计算生命周期规则的执行计划, bucket 为空时检查所有 bucket
+pub async fn plan(cache: &WebCache, bucket: Option<&str>) -> std::io::Result<Vec<LifecycleReport>> {
+    let buckets = match bucket {
+        Some(bucket) => vec![bucket.to_string()],
+        None => list_buckets(cache).await?,
+    };
+    let mut reports = vec![];
+    for bucket in buckets {
+        if let Some(report) = plan_bucket(cache, &bucket).await? {
+            reports.push(report);
+        }
+    }
+    Ok(reports)
+}
+
+/// 按照执行计划删除对象
+/// 计划之后开始追加写入或者大小 / 修改时间已经变化的对象跳过, 等待下一次检查
+async fn apply(cache: &WebCache, report: &LifecycleReport) {
+    let Ok(bucket_dir) = cache.open_data_dir(&report.bucket) else {
+        return;
+    };
+    for action in &report.actions {
+        if cache.is_appending(&report.bucket, &action.name).await {
+            continue;
+        }
+        let path = bucket_dir.join(&action.name);
+        let Ok(meta) = fs::metadata(&path).await else {
+            continue;
+        };
+        let mtime = super::checksum::modified(&bucket_dir, &action.name, &meta).await;
+        if meta.len() != action.size || mtime != action.mtime {
+            log::debug!("lifecycle skip {}/{}: changed since planned", report.bucket, action.name);
+            continue;
+        }
+        let digest = super::checksum::load(&bucket_dir, &action.name, &meta).await.map(|c| c.sha256);
+        match fs::remove_file(&path).await {
+            Ok(_) => {
+                if let Some(sha256) = digest {
+                    super::blobs::release(cache, &sha256).await;
+                }
+                log::info!("lifecycle delete {}/{} ({:?})", report.bucket, action.name, action.reason);
+                objects::remove_empty_parents(&bucket_dir, &path).await;
+                super::quota::record(cache, &report.bucket, -(action.size as i64), -1).await;
+                super::checksum::remove(&bucket_dir, &action.name).await;
+                super::notify::publish(cache, &report.bucket, &action.name, action.size, StorageAction::Delete).await;
+            }
+            Err(err) => log::warn!("lifecycle delete {}/{} error: {err}", report.bucket, action.name),
+        }
+    }
+}
+
+/// 后台定期执行生命周期规则, 同时清理超过 max-age-days 的历史版本
+pub async fn run(cache: WebCache, interval: u64) {
+    let mut interval = rocket::tokio::time::interval(Duration::from_secs(interval.max(1)));
+    loop {
+        interval.tick().await;
+        match plan(&cache, None).await {
+            Ok(reports) => {
+                for report in reports.iter().filter(|r| !r.actions.is_empty()) {
+                    apply(&cache, report).await;
+                }
+            }
+            Err(err) => log::warn!("lifecycle error: {err}"),
+        }
+        match list_buckets(&cache).await {
+            Ok(buckets) => {
+                for bucket in buckets {
+                    if let Err(err) = super::versions::prune_bucket(&cache, &bucket).await {
+                        log::warn!("prune versions {bucket} error: {err}");
+                    }
+                }
+            }
+            Err(err) => log::warn!("lifecycle error: {err}"),
+        }
+    }
+}
+
+/// 1. lifecycle report
+/// - url
+///     - "/storage/lifecycle/report"
+///     - "/storage/lifecycle/report/{bucket}"
+/// - method: GET
+/// - params:
+///     - bucket: string, optional, 为空时检查所有 bucket
+/// - response json, 只计算不删除
+///     - {"code":0,"msg":"ok","ok":true,"data":[{"bucket":"tmp-a","actions":[{"name":"a.txt","size":12,"mtime":1718000000,"reason":"expired"}],"freed_bytes":12}]}
+#[get("/lifecycle/report?<bucket>")]
+async fn lifecycle_report1(
+    bucket: Option<&str>,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<Vec<LifecycleReport>>>> {
+    auth.check_pass_root()?;
+    let reports = plan(cache, bucket).await?;
+    Ok(Json(ResultBase::ok(reports)))
+}
+
+#[get("/lifecycle/report/<bucket>")]
+async fn lifecycle_report2(
+    bucket: &str,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<Vec<LifecycleReport>>>> {
+    auth.check_pass_root()?;
+    let reports = plan(cache, Some(bucket)).await?;
+    Ok(Json(ResultBase::ok(reports)))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![lifecycle_report1, lifecycle_report2]
+}
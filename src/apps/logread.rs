@@ -0,0 +1,364 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use super::auth::TokenAuth;
+use super::{lifecycle, logrotate};
+use super::state::WebCache;
+use super::types::ResultBase;
+
+use regex::{Regex, RegexBuilder};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::tokio::fs;
+use rocket::tokio::io::{AsyncBufReadExt, BufReader};
+use rocket::{get, State};
+
+/// 每次默认/最多返回的行数
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+/// 按行读取日志, 行号从 1 开始, 不包含换行符
+struct LineReader {
+    reader: BufReader<fs::File>,
+    buf: Vec<u8>,
+    line: usize,
+}
+
+impl LineReader {
+    async fn open(path: &Path) -> std::io::Result<Self> {
+        let reader = BufReader::new(fs::File::open(path).await?);
+        Ok(Self { reader, buf: Vec::new(), line: 0 })
+    }
+
+    async fn next_line(&mut self) -> std::io::Result<Option<(usize, String)>> {
+        self.buf.clear();
+        if self.reader.read_until(b'\n', &mut self.buf).await? == 0 {
+            return Ok(None);
+        }
+        self.line += 1;
+        let line = self.buf.strip_suffix(b"\n").unwrap_or(&self.buf);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        Ok(Some((self.line, String::from_utf8_lossy(line).into_owned())))
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct LogLine {
+    line: usize,
+    text: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ReadPage {
+    lines: Vec<LogLine>,
+    /// 下一页的起始行号, 没有更多内容时为空
+    next: Option<usize>,
+}
+
+/// 读取日志的一页
+/// - last: 最后 N 行
+/// - from: 从第 N 行开始, 默认 1
+async fn read_lines(path: &Path, from: Option<usize>, last: Option<usize>, limit: usize) -> std::io::Result<ReadPage> {
+    let mut reader = LineReader::open(path).await?;
+    if let Some(last) = last {
+        let mut window = VecDeque::with_capacity(last.min(MAX_LIMIT));
+        while let Some((line, text)) = reader.next_line().await? {
+            if window.len() == last.min(MAX_LIMIT) {
+                window.pop_front();
+            }
+            window.push_back(LogLine { line, text });
+        }
+        return Ok(ReadPage { lines: window.into(), next: None });
+    }
+    let from = from.unwrap_or(1).max(1);
+    let mut lines = vec![];
+    while let Some((line, text)) = reader.next_line().await? {
+        if line < from {
+            continue;
+        }
+        if lines.len() == limit {
+            return Ok(ReadPage { lines, next: Some(line) });
+        }
+        lines.push(LogLine { line, text });
+    }
+    Ok(ReadPage { lines, next: None })
+}
+
+/// 1. read log
+/// - url
+///     - "/onlinelog/read"
+///     - "/onlinelog/read/{channel}/{name}"
+/// - method: GET
+/// - params:
+///     - channel: string, required
+///     - name: string, required
+///     - from: usize, optional, 起始行号, 从 1 开始, 默认 1
+///     - last: usize, optional, 返回最后 N 行, 优先于 from
+///     - limit: usize, optional, 默认 100, 最大 1000
+/// - response json
+///     - {"code":0,"msg":"ok","ok":true,"data":{"lines":[{"line":1,"text":"..."}],"next":101}}
+#[allow(clippy::too_many_arguments)]
+#[get("/read?<channel>&<name>&<from>&<last>&<limit>")]
+async fn read_log1(
+    channel: &str,
+    name: &str,
+    from: Option<usize>,
+    last: Option<usize>,
+    limit: Option<usize>,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<ReadPage>>> {
+    auth.check_pass_root()?;
+    let path = cache.open_object_path(channel, name)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    Ok(Json(ResultBase::ok(read_lines(&path, from, last, limit).await?)))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/read/<channel>/<name>?<from>&<last>&<limit>")]
+async fn read_log2(
+    channel: &str,
+    name: &str,
+    from: Option<usize>,
+    last: Option<usize>,
+    limit: Option<usize>,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<ReadPage>>> {
+    auth.check_pass_root()?;
+    let path = cache.open_object_path(channel, name)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    Ok(Json(ResultBase::ok(read_lines(&path, from, last, limit).await?)))
+}
+
+/// 搜索条件, 子串或正则
+enum Matcher {
+    Substring { pattern: String, ignore_case: bool },
+    Regex(Regex),
+}
+
+impl Matcher {
+    fn new(pattern: &str, regex: bool, ignore_case: bool) -> super::WebResult<Self> {
+        if pattern.is_empty() {
+            return Err(super::WebError::new("pattern is empty"));
+        }
+        if regex {
+            let regex = RegexBuilder::new(pattern)
+                .case_insensitive(ignore_case)
+                .build()
+                .map_err(|err| super::WebError::new(format!("invalid regex: {err}")))?;
+            return Ok(Self::Regex(regex));
+        }
+        let pattern = if ignore_case { pattern.to_lowercase() } else { pattern.to_string() };
+        Ok(Self::Substring { pattern, ignore_case })
+    }
+
+    fn is_match(&self, text: &str) -> bool {
+        match self {
+            Self::Substring { pattern, ignore_case: true } => text.to_lowercase().contains(pattern.as_str()),
+            Self::Substring { pattern, ignore_case: false } => text.contains(pattern.as_str()),
+            Self::Regex(regex) => regex.is_match(text),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct GrepMatch {
+    name: String,
+    line: usize,
+    text: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct GrepResult {
+    matches: Vec<GrepMatch>,
+    /// 达到 limit 后停止搜索
+    truncated: bool,
+}
+
+/// channel 下的日志名称, 不包含隐藏文件、子目录和切分出的历史分段
+async fn log_names(channel_dir: &Path) -> std::io::Result<Vec<String>> {
+    let mut entries = fs::read_dir(channel_dir).await?;
+    let mut names = vec![];
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+        if let Some(name) = entry.file_name().to_str() {
+            if !name.starts_with('.') && logrotate::segment_base(name).is_none() {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// 在 channel 的一个或所有日志中搜索, 按日志名称和行号排序
+async fn grep(cache: &WebCache, channel: &str, name: Option<&str>, matcher: &Matcher, limit: usize) -> super::WebResult<GrepResult> {
+    let channel_dir = cache.open_data_dir(channel)?;
+    let names = match name {
+        Some(name) => {
+            cache.open_object_path(channel, name)?;
+            vec![name.to_string()]
+        }
+        None => log_names(&channel_dir).await?,
+    };
+    let mut matches = vec![];
+    for name in names {
+        let mut reader = LineReader::open(&channel_dir.join(&name)).await?;
+        while let Some((line, text)) = reader.next_line().await? {
+            if !matcher.is_match(&text) {
+                continue;
+            }
+            if matches.len() == limit {
+                return Ok(GrepResult { matches, truncated: true });
+            }
+            matches.push(GrepMatch { name: name.clone(), line, text });
+        }
+    }
+    Ok(GrepResult { matches, truncated: false })
+}
+
+/// 2. grep log
+/// - url
+///     - "/onlinelog/grep"
+///     - "/onlinelog/grep/{channel}"
+/// - method: GET
+/// - params:
+///     - channel: string, required
+///     - name: string, optional, 只搜索指定的日志, 默认搜索 channel 下所有日志(不包含历史分段)
+///     - pattern: string, required
+///     - regex: bool, optional, pattern 是否为正则表达式, 默认 false 按子串匹配
+///     - ignore_case: bool, optional, 默认 false
+///     - limit: usize, optional, 默认 100, 最大 1000
+/// - response json
+///     - {"code":0,"msg":"ok","ok":true,"data":{"matches":[{"name":"app.log","line":3,"text":"..."}],"truncated":false}}
+#[allow(clippy::too_many_arguments)]
+#[get("/grep?<channel>&<name>&<pattern>&<regex>&<ignore_case>&<limit>")]
+async fn grep_log1(
+    channel: &str,
+    name: Option<&str>,
+    pattern: &str,
+    regex: Option<bool>,
+    ignore_case: Option<bool>,
+    limit: Option<usize>,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<GrepResult>>> {
+    auth.check_pass_root()?;
+    let matcher = Matcher::new(pattern, regex.unwrap_or_default(), ignore_case.unwrap_or_default())?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    Ok(Json(ResultBase::ok(grep(cache, channel, name, &matcher, limit).await?)))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/grep/<channel>?<name>&<pattern>&<regex>&<ignore_case>&<limit>")]
+async fn grep_log2(
+    channel: &str,
+    name: Option<&str>,
+    pattern: &str,
+    regex: Option<bool>,
+    ignore_case: Option<bool>,
+    limit: Option<usize>,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<GrepResult>>> {
+    auth.check_pass_root()?;
+    let matcher = Matcher::new(pattern, regex.unwrap_or_default(), ignore_case.unwrap_or_default())?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    Ok(Json(ResultBase::ok(grep(cache, channel, name, &matcher, limit).await?)))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct LogEntry {
+    name: String,
+    size: u64,
+    /// 最后写入时间, unix 时间戳(秒)
+    mtime: Option<u64>,
+    /// 是否被 "/onlinelog/upload" 打开
+    open: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ChannelEntry {
+    channel: String,
+    logs: Vec<LogEntry>,
+}
+
+async fn list_channel(cache: &WebCache, channel: &str) -> std::io::Result<ChannelEntry> {
+    let channel_dir = cache.open_data_dir(channel)?;
+    let mut logs = vec![];
+    for name in log_names(&channel_dir).await? {
+        let meta = fs::metadata(channel_dir.join(&name)).await?;
+        let mtime = meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|t| t.as_secs());
+        let open = cache.is_log_open(channel, &name).await;
+        logs.push(LogEntry { name, size: meta.len(), mtime, open });
+    }
+    Ok(ChannelEntry { channel: channel.to_string(), logs })
+}
+
+/// bucket 是否为日志 channel: 上传过日志, 有打开的日志, 或者匹配切分规则
+async fn is_log_channel(cache: &WebCache, channel: &str) -> bool {
+    if cache.log_rotate_policy(channel).is_some() {
+        return true;
+    }
+    let prefix = format!("{channel}/");
+    if cache.cache_logs.lock().await.keys().any(|k| k.starts_with(&prefix)) {
+        return true;
+    }
+    let Ok(channel_dir) = cache.open_data_dir(channel) else {
+        return false;
+    };
+    fs::try_exists(channel_dir.join(super::onlinelog::CHANNEL_MARKER))
+        .await
+        .unwrap_or(false)
+}
+
+/// 3. list logs
+/// - url
+///     - "/onlinelog/list"
+///     - "/onlinelog/list?channel={channel}"
+///     - "/onlinelog/list/{channel}"
+/// - method: GET
+/// - params:
+///     - channel: string, optional, 默认列出所有上传过日志的 channel, 不包含普通的存储 bucket
+/// - response json
+///     - {"code":0,"msg":"ok","ok":true,"data":[{"channel":"app","logs":[{"name":"info.log","size":1024,"mtime":1718000000,"open":true}]}]}
+#[get("/list?<channel>")]
+async fn list_logs1(channel: Option<&str>, auth: TokenAuth, cache: &State<WebCache>) -> super::WebResult<Json<ResultBase<Vec<ChannelEntry>>>> {
+    auth.check_pass_root()?;
+    let mut channels = vec![];
+    match channel {
+        Some(channel) => channels.push(list_channel(cache, channel).await?),
+        None => {
+            for channel in lifecycle::list_buckets(cache).await? {
+                if !is_log_channel(cache, &channel).await {
+                    continue;
+                }
+                let entry = list_channel(cache, &channel).await?;
+                if !entry.logs.is_empty() {
+                    channels.push(entry);
+                }
+            }
+        }
+    }
+    Ok(Json(ResultBase::ok(channels)))
+}
+
+#[get("/list/<channel>")]
+async fn list_logs2(channel: &str, auth: TokenAuth, cache: &State<WebCache>) -> super::WebResult<Json<ResultBase<Vec<ChannelEntry>>>> {
+    auth.check_pass_root()?;
+    Ok(Json(ResultBase::ok(vec![list_channel(cache, channel).await?])))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![read_log1, read_log2, grep_log1, grep_log2, list_logs1, list_logs2]
+}
@@ -0,0 +1,270 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use super::onlinelog::CHANNEL_MARKER;
+use super::state::WebCache;
+use crate::config::ConfigLogRotate;
+
+use chrono::{DateTime, Local};
+use rocket::tokio::fs::{self, File};
+use rocket::tokio::io::AsyncWriteExt;
+
+/// 历史分段名称中的时间格式: {name}.{YYYYMMDD-HHMMSS}[-N][.gz]
+const STAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+const STAMP_LEN: usize = 15;
+
+/// file_name 是否为日志 name 的历史分段
+pub fn is_segment_of(name: &str, file_name: &str) -> bool {
+    let Some(rest) = file_name.strip_prefix(name).and_then(|s| s.strip_prefix('.')) else {
+        return false;
+    };
+    let stamp = rest.strip_suffix(".gz").unwrap_or(rest);
+    stamp.len() >= STAMP_LEN
+        && stamp.bytes().take(STAMP_LEN).enumerate().all(|(i, b)| if i == 8 { b == b'-' } else { b.is_ascii_digit() })
+        && (stamp.len() == STAMP_LEN || stamp[STAMP_LEN..].strip_prefix('-').is_some_and(|n| n.parse::<u32>().is_ok()))
+}
+
+/// 历史分段对应的日志名称, 不是历史分段时返回 None
+/// 日志本身已经被切分或删除时同样可以识别
+pub fn segment_base(file_name: &str) -> Option<&str> {
+    file_name
+        .match_indices('.')
+        .map(|(idx, _)| &file_name[..idx])
+        .find(|name| is_segment_of(name, file_name))
+}
+
+/// 日志文件是否需要切分
+/// - 超过 max-size
+/// - daily: 最后一次写入不是今天
+async fn should_rotate(policy: &ConfigLogRotate, file: &File) -> std::io::Result<bool> {
+    let meta = file.metadata().await?;
+    if meta.len() == 0 {
+        return Ok(false);
+    }
+    let by_size = policy.max_size.is_some_and(|max| meta.len() >= max);
+    let by_day = policy.daily
+        && meta
+            .modified()
+            .is_ok_and(|t| DateTime::<Local>::from(t).date_naive() != Local::now().date_naive());
+    Ok(by_size || by_day)
+}
+
+/// 历史分段的路径, 时间为日志最后一次写入的时间
+async fn segment_path(path: &Path, modified: SystemTime) -> PathBuf {
+    let file_name = path.file_name().and_then(|s| s.to_str()).unwrap_or_default();
+    let stamp = DateTime::<Local>::from(modified).format(STAMP_FORMAT).to_string();
+    let mut n = 0;
+    loop {
+        let segment = match n {
+            0 => path.with_file_name(format!("{file_name}.{stamp}")),
+            n => path.with_file_name(format!("{file_name}.{stamp}-{n}")),
+        };
+        let compressed = segment.with_file_name(format!("{}.gz", segment.file_name().and_then(|s| s.to_str()).unwrap_or_default()));
+        if !fs::try_exists(&segment).await.unwrap_or(false) && !fs::try_exists(&compressed).await.unwrap_or(false) {
+            return segment;
+        }
+        n += 1;
+    }
+}
+
+/// 写入日志前检查切分规则, 需要时将当前文件切分为历史分段, 并打开新的文件继续写入
+/// raw 格式的上传在写入每个数据块前都会检查, 文件最多超出 max-size 一个数据块
+/// 历史分段的压缩和清理在后台执行
+pub async fn rotate_if_needed(cache: &WebCache, channel: &str, name: &str, file: &mut File) -> std::io::Result<()> {
+    let Some(policy) = cache.log_rotate_policy(channel) else {
+        return Ok(());
+    };
+    if !should_rotate(policy, file).await? {
+        return Ok(());
+    }
+    let path = cache.open_data_dir(channel)?.join(name);
+    file.flush().await?;
+    let modified = file.metadata().await?.modified().unwrap_or_else(|_| SystemTime::now());
+    let segment = segment_path(&path, modified).await;
+    fs::rename(&path, &segment).await?;
+    *file = fs::OpenOptions::new().append(true).create(true).open(&path).await?;
+    log::info!("rotate log {channel}/{name} -> {}", segment.display());
+    finish_segment(path, segment, policy.clone());
+    Ok(())
+}
+
+/// 没有被打开的日志按规则切分, 不创建新的文件, 下一次上传时再创建
+/// 持有日志句柄表的锁, 切分期间上传不能打开该日志
+async fn rotate_closed(cache: &WebCache, policy: &ConfigLogRotate, path: &Path) -> std::io::Result<()> {
+    let (Some(channel), Some(name)) = (
+        path.parent().and_then(|p| p.file_name()).and_then(|s| s.to_str()),
+        path.file_name().and_then(|s| s.to_str()),
+    ) else {
+        return Ok(());
+    };
+    let logs = cache.cache_logs.lock().await;
+    if logs.contains_key(&format!("{channel}/{name}")) {
+        return Ok(());
+    }
+    let file = match File::open(path).await {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        file => file?,
+    };
+    if !should_rotate(policy, &file).await? {
+        return Ok(());
+    }
+    let modified = file.metadata().await?.modified().unwrap_or_else(|_| SystemTime::now());
+    let segment = segment_path(path, modified).await;
+    fs::rename(path, &segment).await?;
+    drop(logs);
+    log::info!("rotate closed log {channel}/{name} -> {}", segment.display());
+    finish_segment(path.to_path_buf(), segment, policy.clone());
+    Ok(())
+}
+
+/// 后台压缩新的历史分段并清理旧的分段
+fn finish_segment(path: PathBuf, segment: PathBuf, policy: ConfigLogRotate) {
+    rocket::tokio::spawn(async move {
+        if policy.compress {
+            if let Err(err) = compress(&segment).await {
+                log::warn!("compress log segment {} error: {err}", segment.display());
+            }
+        }
+        if let Err(err) = prune(&path, &policy).await {
+            log::warn!("prune log segments of {} error: {err}", path.display());
+        }
+    });
+}
+
+/// gzip 压缩历史分段, 保留原来的修改时间
+async fn compress(segment: &Path) -> std::io::Result<()> {
+    let segment = segment.to_path_buf();
+    rocket::tokio::task::spawn_blocking(move || {
+        let file_name = segment.file_name().and_then(|s| s.to_str()).unwrap_or_default();
+        let tmp = segment.with_file_name(format!(".{file_name}.gz.tmp"));
+        let target = segment.with_file_name(format!("{file_name}.gz"));
+        let mut src = std::fs::File::open(&segment)?;
+        let modified = src.metadata()?.modified()?;
+        let mut gz = flate2::write::GzEncoder::new(std::fs::File::create(&tmp)?, flate2::Compression::default());
+        std::io::copy(&mut src, &mut gz)?;
+        let dst = gz.finish()?;
+        dst.set_modified(modified)?;
+        std::fs::rename(&tmp, &target)?;
+        std::fs::remove_file(&segment)
+    })
+    .await?
+}
+
+/// 按照 max-segments / max-age-days 清理日志 path 的历史分段, 最新的分段在前
+pub async fn prune(path: &Path, policy: &ConfigLogRotate) -> std::io::Result<()> {
+    if policy.max_segments.is_none() && policy.max_age_days.is_none() {
+        return Ok(());
+    }
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name().and_then(|s| s.to_str())) else {
+        return Ok(());
+    };
+    let mut entries = fs::read_dir(dir).await?;
+    let mut segments = vec![];
+    while let Some(entry) = entries.next_entry().await? {
+        let Some(file_name) = entry.file_name().to_str().map(|s| s.to_string()) else {
+            continue;
+        };
+        if !is_segment_of(name, &file_name) {
+            continue;
+        }
+        // 压缩完成后原分段会被删除
+        if let Ok(meta) = entry.metadata().await {
+            segments.push((file_name, meta.modified().unwrap_or(SystemTime::UNIX_EPOCH)));
+        }
+    }
+    segments.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| b.0.cmp(&a.0)));
+    let expire_before = policy
+        .max_age_days
+        .and_then(|days| SystemTime::now().checked_sub(Duration::from_secs(days.saturating_mul(24 * 3600))));
+    for (idx, (file_name, modified)) in segments.iter().enumerate() {
+        let too_many = policy.max_segments.is_some_and(|max| idx >= max);
+        let too_old = expire_before.is_some_and(|t| *modified < t);
+        if too_many || too_old {
+            log::info!("remove log segment {}/{file_name}", dir.display());
+            match fs::remove_file(dir.join(file_name)).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 列出 channel 目录中所有日志 (不包含历史分段和隐藏文件)
+/// 只剩下历史分段的日志同样列出, 用于清理过期的分段
+async fn channel_logs(channel_dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = fs::read_dir(channel_dir).await?;
+    let mut names = BTreeSet::new();
+    while let Some(file) = files.next_entry().await? {
+        if let Some(name) = file.file_name().to_str() {
+            if !name.starts_with('.') && file.file_type().await?.is_file() {
+                names.insert(segment_base(name).unwrap_or(name).to_string());
+            }
+        }
+    }
+    Ok(names.into_iter().map(|name| channel_dir.join(name)).collect())
+}
+
+/// 列出配置了切分规则的 channel 中所有日志, 以及 channel 匹配的第一条规则
+/// - 只检查 "/onlinelog/upload" 创建的 channel, 同名的存储 bucket 不受影响
+/// - 单个 channel 读取失败时跳过, 不影响其他 channel
+async fn list_logs(cache: &WebCache) -> std::io::Result<Vec<(&ConfigLogRotate, PathBuf)>> {
+    let mut channels = fs::read_dir(cache.data_workspace.as_ref()).await?;
+    let mut logs = vec![];
+    while let Some(channel) = channels.next_entry().await? {
+        let Some(channel_name) = channel.file_name().to_str().map(|s| s.to_string()) else {
+            continue;
+        };
+        if channel_name.starts_with('.') {
+            continue;
+        }
+        let Some(policy) = cache.log_rotate_policy(&channel_name) else {
+            continue;
+        };
+        if !fs::try_exists(channel.path().join(CHANNEL_MARKER)).await.unwrap_or(false) {
+            continue;
+        }
+        match channel_logs(&channel.path()).await {
+            Ok(paths) => logs.extend(paths.into_iter().map(|path| (policy, path))),
+            Err(err) => log::warn!("list logs of {channel_name} error: {err}"),
+        }
+    }
+    Ok(logs)
+}
+
+/// 后台定期执行切分和历史分段清理
+/// - 打开的日志: 没有正在写入时检查切分
+/// - 没有打开的日志: 扫描 channel 目录, 按天或超过大小时切分, 没有写入的日志同样会被切分
+pub async fn run(cache: WebCache, interval: u64) {
+    let mut interval = rocket::tokio::time::interval(Duration::from_secs(interval.max(1)));
+    loop {
+        interval.tick().await;
+        let opened = cache.cache_logs.lock().await.clone();
+        for (key, file) in opened {
+            let Some((channel, name)) = key.split_once('/') else {
+                continue;
+            };
+            // 正在写入的日志由写入请求检查切分
+            let Ok(mut file) = file.1.try_lock() else {
+                continue;
+            };
+            if let Err(err) = rotate_if_needed(&cache, channel, name, &mut file).await {
+                log::warn!("rotate log {key} error: {err}");
+            }
+        }
+        match list_logs(&cache).await {
+            Ok(logs) => {
+                for (policy, path) in logs {
+                    if let Err(err) = rotate_closed(&cache, policy, &path).await {
+                        log::warn!("rotate log {} error: {err}", path.display());
+                    }
+                    if let Err(err) = prune(&path, policy).await {
+                        log::warn!("prune log segments of {} error: {err}", path.display());
+                    }
+                }
+            }
+            Err(err) => log::warn!("list logs error: {err}"),
+        }
+    }
+}
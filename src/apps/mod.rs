@@ -1,14 +1,40 @@
+mod ack;
+mod archive;
 mod auth;
+mod blobs;
+mod checksum;
 
 mod error;
+mod groups;
+mod grpc;
+mod handles;
+mod idempotency;
 mod init;
+mod lifecycle;
+mod logread;
+mod logrotate;
 mod msg;
+mod mqtt;
+mod msgmulti;
+mod msgws;
+mod notify;
+mod objects;
 mod state;
+mod stomp;
 // mod tools;
 mod seekstream;
 mod storage;
+mod structlog;
+mod tail;
+mod topic;
+mod transfer;
 mod onlinelog;
+mod quota;
+mod resp;
+mod rpc;
 mod types;
+mod versions;
+mod webhook;
 use rocket::tokio::runtime::Runtime;
 use rocket::{config::TlsConfig, Config};
 use rocket::fs::FileServer;
@@ -17,8 +43,26 @@ use std::str::FromStr;
 use crate::config::Config as CliConfig;
 use error::{WebError, WebResult};
 
+/// 清理去重存储中没有被引用的 blob
+pub fn gc_blobs(cfg: &CliConfig) -> anyhow::Result<()> {
+    let workspace = cfg.data_workspace()?;
+    let rt = Runtime::new()?;
+    let report = rt.block_on(blobs::gc(&workspace))?;
+    println!("blobs: {}, removed: {}, freed bytes: {}", report.blobs, report.removed, report.freed_bytes);
+    Ok(())
+}
+
 pub fn server_up(cfg: &CliConfig) -> anyhow::Result<()> {
     let cache = state::WebCache::new(cfg)?;
+    let lifecycle_cache = cache.clone();
+    let logrotate_cache = cache.clone();
+    let handles_cache = cache.clone();
+    let idempotency_cache = cache.clone();
+    let resp_cache = cache.clone();
+    let stomp_cache = cache.clone();
+    let grpc_cache = cache.clone();
+    let mqtt_cache = cache.clone();
+    let webhook_cache = cache.clone();
 
     let mut server_config = Config {
         workers: cfg.server.workers,
@@ -79,6 +123,35 @@ pub fn server_up(cfg: &CliConfig) -> anyhow::Result<()> {
         // if let Err(err) = cache_clone.load_stateful().await {
         //     log::error!("load stateful error: {:?}", err);
         // }
+        if !cfg.storage.lifecycle.is_empty() || cfg.storage.versioning.iter().any(|v| v.max_age_days.is_some()) {
+            log::info!("lifecycle rules on, interval {}s", cfg.storage.lifecycle_interval);
+            rocket::tokio::spawn(lifecycle::run(lifecycle_cache, cfg.storage.lifecycle_interval));
+        }
+        if !cfg.onlinelog.rotate.is_empty() {
+            rocket::tokio::spawn(logrotate::run(logrotate_cache, cfg.onlinelog.rotate_interval));
+        }
+        if let Some(idle_timeout) = cfg.handles.idle_timeout {
+            log::info!("close idle handles after {idle_timeout}s, interval {}s", cfg.handles.sweep_interval);
+            rocket::tokio::spawn(handles::run(handles_cache, idle_timeout, cfg.handles.sweep_interval));
+        }
+        rocket::tokio::spawn(idempotency::run(idempotency_cache, cfg.idempotency.sweep_interval));
+        if let Some(resp) = &cfg.resp {
+            rocket::tokio::spawn(resp::serve(resp_cache, resp.bind.clone()));
+        }
+        if let Some(stomp) = &cfg.stomp {
+            rocket::tokio::spawn(stomp::serve(stomp_cache, stomp.bind.clone()));
+        }
+        if let Some(grpc) = &cfg.grpc {
+            rocket::tokio::spawn(grpc::serve(grpc_cache, grpc.bind.clone()));
+        }
+        if let Some(mqtt) = &cfg.mqtt {
+            rocket::tokio::spawn(mqtt::serve(mqtt_cache, mqtt.bind.clone()));
+        }
+        for hook in &cfg.webhooks {
+            if let Err(err) = webhook::register(&webhook_cache, hook.clone()).await {
+                log::error!("webhook {} -> {} error: {err}", hook.queue, hook.url);
+            }
+        }
         let _ = build.launch().await;
     });
     Ok(())
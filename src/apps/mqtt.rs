@@ -0,0 +1,717 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+
+use super::ack;
+use super::state::WebCache;
+use super::topic;
+
+use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use rocket::tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use rocket::tokio::net::{TcpListener, TcpStream};
+use rocket::tokio::sync::{broadcast, mpsc};
+use rocket::tokio::task::JoinHandle;
+
+/// 单个报文的最大长度, 消息体与 "/msg/{queue}/put" 的限制相同
+const MAX_PACKET_LEN: usize = 10 * 1024 * 1024 + 64 * 1024;
+/// 连接后等待 CONNECT 的时间
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// 没有可投递的消息时检查队列的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// QoS 1 最多同时未收到 PUBACK 的消息数量, MQTT 5 客户端的 Receive Maximum 更小时以客户端为准
+const MAX_INFLIGHT: usize = 16;
+/// 每一轮最多投递的消息数量
+const DELIVER_BATCH: usize = 64;
+/// "queue/{name}" 对应 WebCache 中的队列, 其他主题为发布订阅
+const QUEUE_PREFIX: &str = "queue/";
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+fn protocol_error(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("mqtt protocol error: {msg}"))
+}
+
+/// 固定头和剩余部分
+struct Packet {
+    kind: u8,
+    flags: u8,
+    body: Vec<u8>,
+}
+
+/// 读取一个报文, 连接关闭时返回 None
+async fn read_packet(reader: &mut BufReader<OwnedReadHalf>) -> std::io::Result<Option<Packet>> {
+    let header = match reader.read_u8().await {
+        Ok(header) => header,
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut len = 0usize;
+    for shift in (0..28).step_by(7) {
+        let byte = reader.read_u8().await?;
+        len |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if shift == 21 {
+            return Err(protocol_error("malformed remaining length"));
+        }
+    }
+    if len > MAX_PACKET_LEN {
+        return Err(protocol_error("packet too large"));
+    }
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(Packet { kind: header >> 4, flags: header & 0x0f, body }))
+}
+
+/// 按顺序读取报文中的字段
+struct Body<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Body<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn bytes(&mut self, n: usize) -> std::io::Result<&'a [u8]> {
+        if self.data.len() - self.pos < n {
+            return Err(protocol_error("unexpected end of packet"));
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> std::io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> std::io::Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn varint(&mut self) -> std::io::Result<usize> {
+        let mut value = 0usize;
+        for shift in (0..28).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(protocol_error("malformed variable byte integer"))
+    }
+
+    fn binary(&mut self) -> std::io::Result<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+
+    fn string(&mut self) -> std::io::Result<String> {
+        let bytes = self.binary()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| protocol_error("invalid utf-8 string"))
+    }
+
+    /// MQTT 5 的属性, 返回 (标识, 数值), 只有整数属性带数值
+    fn properties(&mut self) -> std::io::Result<Vec<(u8, u32)>> {
+        let len = self.varint()?;
+        let mut props = Body::new(self.bytes(len)?);
+        let mut values = vec![];
+        while !props.is_empty() {
+            let id = props.u8()?;
+            let value = match id {
+                0x01 | 0x17 | 0x19 | 0x24 | 0x25 | 0x28 | 0x29 | 0x2a => props.u8()? as u32,
+                0x13 | 0x21 | 0x22 | 0x23 => props.u16()? as u32,
+                0x02 | 0x11 | 0x18 | 0x27 => u32::from_be_bytes(props.bytes(4)?.try_into().unwrap_or_default()),
+                0x0b => props.varint()? as u32,
+                0x03 | 0x08 | 0x12 | 0x15 | 0x1a | 0x1c | 0x1f | 0x09 | 0x16 => {
+                    props.binary()?;
+                    0
+                }
+                0x26 => {
+                    props.binary()?;
+                    props.binary()?;
+                    0
+                }
+                _ => return Err(protocol_error(&format!("unknown property 0x{id:02x}"))),
+            };
+            values.push((id, value));
+        }
+        Ok(values)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.pos..];
+        self.pos = self.data.len();
+        rest
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, value: &str) {
+    put_u16(out, value.len() as u16);
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let mut byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+fn encode(kind: u8, flags: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len() + 5);
+    out.push(kind << 4 | flags);
+    put_varint(&mut out, body.len());
+    out.extend_from_slice(body);
+    out
+}
+
+/// 发布到 "queue/{name}" 时放入队列, 其他主题发送给当前的订阅者
+async fn route_publish(cache: &WebCache, topic_name: &str, payload: Vec<u8>, retain: bool) {
+    match topic_name.strip_prefix(QUEUE_PREFIX).filter(|q| !q.is_empty()) {
+        // 空的 retain 消息用于清除保留消息, 队列中不放入空消息
+        Some(_) if retain && payload.is_empty() => {}
+        Some(queue) => cache.queue_push_msg(queue, payload).await,
+        None => {
+            topic::publish(cache, topic_name, payload).await;
+        }
+    }
+}
+
+/// CONNECT 中需要的字段
+struct Connect {
+    v5: bool,
+    client_id: String,
+    keep_alive: u16,
+    receive_maximum: Option<u16>,
+    will: Option<(String, Vec<u8>, bool)>,
+    username: Option<String>,
+    password: Option<Vec<u8>>,
+}
+
+/// 解析 CONNECT, 协议版本不支持时返回 Ok(None)
+fn parse_connect(body: &[u8]) -> std::io::Result<Option<Connect>> {
+    let mut body = Body::new(body);
+    let name = body.string()?;
+    let level = body.u8()?;
+    if name != "MQTT" || !matches!(level, 4 | 5) {
+        return Ok(None);
+    }
+    let v5 = level == 5;
+    let flags = body.u8()?;
+    let keep_alive = body.u16()?;
+    let mut receive_maximum = None;
+    if v5 {
+        receive_maximum = body.properties()?.into_iter().find(|(id, _)| *id == 0x21).map(|(_, v)| v as u16);
+    }
+    let client_id = body.string()?;
+    let will = if flags & 0x04 != 0 {
+        if v5 {
+            body.properties()?;
+        }
+        let topic_name = body.string()?;
+        let payload = body.binary()?.to_vec();
+        Some((topic_name, payload, flags & 0x20 != 0))
+    } else {
+        None
+    };
+    let username = if flags & 0x80 != 0 { Some(body.string()?) } else { None };
+    let password = if flags & 0x40 != 0 { Some(body.binary()?.to_vec()) } else { None };
+    Ok(Some(Connect {
+        v5,
+        client_id,
+        keep_alive,
+        receive_maximum,
+        will,
+        username,
+        password,
+    }))
+}
+
+enum Target {
+    Queue(String),
+    /// 主题消息由单独的任务转发
+    Topic(JoinHandle<()>),
+}
+
+struct Subscription {
+    target: Target,
+    /// 授予的 QoS, 队列订阅最高为 1, 主题订阅为 0
+    qos: u8,
+}
+
+/// 一个客户端连接
+struct Connection {
+    cache: WebCache,
+    writer: OwnedWriteHalf,
+    v5: bool,
+    /// 以 topic filter 为 key
+    subscriptions: BTreeMap<String, Subscription>,
+    next_queue: usize,
+    /// QoS 1 已发送未收到 PUBACK 的消息, packet id -> 投递 id
+    inflight: BTreeMap<u16, u64>,
+    max_inflight: usize,
+    next_packet_id: u16,
+    /// QoS 2 已收到 PUBLISH 还没有收到 PUBREL 的 packet id, 重复的 PUBLISH 不再放入队列
+    pending_release: BTreeSet<u16>,
+    /// 异常断开时发布的遗嘱消息
+    will: Option<(String, Vec<u8>, bool)>,
+    topic_tx: mpsc::UnboundedSender<(String, Vec<u8>)>,
+}
+
+impl Connection {
+    async fn write(&mut self, kind: u8, flags: u8, body: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(&encode(kind, flags, body)).await
+    }
+
+    /// 带 packet id 的确认报文, MQTT 5 省略成功的 reason code
+    async fn write_ack(&mut self, kind: u8, flags: u8, packet_id: u16) -> std::io::Result<()> {
+        self.write(kind, flags, &packet_id.to_be_bytes()).await
+    }
+
+    async fn write_publish(&mut self, topic_name: &str, payload: &[u8], qos: u8, packet_id: u16, retain: bool) -> std::io::Result<()> {
+        let mut body = Vec::with_capacity(topic_name.len() + payload.len() + 8);
+        put_str(&mut body, topic_name);
+        if qos > 0 {
+            put_u16(&mut body, packet_id);
+        }
+        if self.v5 {
+            body.push(0);
+        }
+        body.extend_from_slice(payload);
+        self.write(PUBLISH, qos << 1 | retain as u8, &body).await
+    }
+
+    fn alloc_packet_id(&mut self) -> u16 {
+        loop {
+            self.next_packet_id = self.next_packet_id.wrapping_add(1);
+            if self.next_packet_id != 0 && !self.inflight.contains_key(&self.next_packet_id) {
+                return self.next_packet_id;
+            }
+        }
+    }
+
+    /// 从订阅的队列中轮流取出消息发送给客户端, 返回发送的数量
+    async fn deliver(&mut self) -> std::io::Result<usize> {
+        let queues = self
+            .subscriptions
+            .iter()
+            .filter_map(|(filter, sub)| match &sub.target {
+                Target::Queue(queue) => Some((filter.clone(), queue.clone(), sub.qos)),
+                Target::Topic(_) => None,
+            })
+            .collect::<Vec<_>>();
+        if queues.is_empty() {
+            return Ok(0);
+        }
+        let mut sent = 0;
+        for _ in 0..DELIVER_BATCH {
+            let mut delivered = false;
+            for _ in 0..queues.len() {
+                let idx = self.next_queue % queues.len();
+                self.next_queue = idx + 1;
+                let (filter, queue, qos) = &queues[idx];
+                if *qos > 0 && self.inflight.len() >= self.max_inflight {
+                    continue;
+                }
+                let popped = if *qos > 0 {
+                    ack::pop_deliver(&self.cache, queue, Some(self.cache.ack_timeout)).await
+                } else {
+                    self.cache.queue_pop_msg(queue).await.map(|msg| (0, msg))
+                };
+                let Some((id, msg)) = popped else {
+                    continue;
+                };
+                if *qos > 0 {
+                    let packet_id = self.alloc_packet_id();
+                    self.inflight.insert(packet_id, id);
+                    self.write_publish(filter, &msg, 1, packet_id, false).await?;
+                } else if let Err(err) = self.write_publish(filter, &msg, 0, 0, false).await {
+                    // 客户端已经断开, 消息放回队列
+                    self.cache.queue_requeue_msg(queue, msg).await;
+                    return Err(err);
+                }
+                delivered = true;
+                sent += 1;
+                break;
+            }
+            if !delivered {
+                break;
+            }
+        }
+        Ok(sent)
+    }
+
+    async fn subscribe(&mut self, packet: Packet) -> std::io::Result<()> {
+        if packet.flags != 0x02 {
+            return Err(protocol_error("invalid SUBSCRIBE flags"));
+        }
+        let mut body = Body::new(&packet.body);
+        let packet_id = body.u16()?;
+        if self.v5 {
+            body.properties()?;
+        }
+        let mut codes = vec![];
+        // 队列订阅成功后发送的保留消息
+        let mut retained = vec![];
+        while !body.is_empty() {
+            let filter = body.string()?;
+            let options = body.u8()?;
+            let qos = options & 0x03;
+            let retain_handling = (options >> 4) & 0x03;
+            if filter.is_empty() || filter.contains(['+', '#']) || filter.starts_with("$share/") {
+                // 不支持通配符和共享订阅, 多个客户端订阅同一个队列时本来就是轮流取出消息
+                codes.push(if !self.v5 {
+                    0x80
+                } else if filter.starts_with("$share/") {
+                    0x9e
+                } else {
+                    0xa2
+                });
+                continue;
+            }
+            let existed = self.subscriptions.contains_key(&filter);
+            let subscription = match filter.strip_prefix(QUEUE_PREFIX).filter(|q| !q.is_empty()) {
+                Some(queue) => {
+                    if retain_handling == 0 || (retain_handling == 1 && !existed) {
+                        retained.push((filter.clone(), queue.to_string()));
+                    }
+                    Subscription { target: Target::Queue(queue.to_string()), qos: qos.min(1) }
+                }
+                None => {
+                    let mut receiver = topic::subscribe(&self.cache, &filter).await;
+                    let tx = self.topic_tx.clone();
+                    let name = filter.clone();
+                    let task = rocket::tokio::spawn(async move {
+                        loop {
+                            match receiver.recv().await {
+                                Ok(msg) => {
+                                    if tx.send((name.clone(), msg)).is_err() {
+                                        break;
+                                    }
+                                }
+                                Err(broadcast::error::RecvError::Lagged(n)) => log::warn!("mqtt subscriber of {name} lagged {n} messages"),
+                                Err(broadcast::error::RecvError::Closed) => break,
+                            }
+                        }
+                    });
+                    Subscription { target: Target::Topic(task), qos: 0 }
+                }
+            };
+            codes.push(subscription.qos);
+            if let Some(Subscription { target: Target::Topic(task), .. }) = self.subscriptions.insert(filter, subscription) {
+                task.abort();
+            }
+        }
+        if codes.is_empty() {
+            return Err(protocol_error("SUBSCRIBE without topic filters"));
+        }
+        let mut reply = packet_id.to_be_bytes().to_vec();
+        if self.v5 {
+            reply.push(0);
+        }
+        reply.extend_from_slice(&codes);
+        self.write(SUBACK, 0, &reply).await?;
+        // 保留消息为队列中最新放入的一条, 不从队列中取出
+        for (filter, queue) in retained {
+            if let Some(msg) = self.cache.queue_last(&queue).await {
+                self.write_publish(&filter, &msg, 0, 0, true).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn unsubscribe(&mut self, packet: Packet) -> std::io::Result<()> {
+        if packet.flags != 0x02 {
+            return Err(protocol_error("invalid UNSUBSCRIBE flags"));
+        }
+        let mut body = Body::new(&packet.body);
+        let packet_id = body.u16()?;
+        if self.v5 {
+            body.properties()?;
+        }
+        let mut codes = vec![];
+        while !body.is_empty() {
+            let filter = body.string()?;
+            match self.subscriptions.remove(&filter) {
+                Some(sub) => {
+                    if let Target::Topic(task) = sub.target {
+                        task.abort();
+                    }
+                    codes.push(0x00);
+                }
+                None => codes.push(0x11),
+            }
+        }
+        let mut reply = packet_id.to_be_bytes().to_vec();
+        if self.v5 {
+            reply.push(0);
+            reply.extend_from_slice(&codes);
+        }
+        self.write(UNSUBACK, 0, &reply).await
+    }
+
+    async fn publish(&mut self, packet: Packet) -> std::io::Result<()> {
+        let qos = (packet.flags >> 1) & 0x03;
+        let retain = packet.flags & 0x01 != 0;
+        let mut body = Body::new(&packet.body);
+        let topic_name = body.string()?;
+        if topic_name.is_empty() || topic_name.contains(['+', '#']) {
+            return Err(protocol_error("invalid topic name"));
+        }
+        let packet_id = if qos > 0 { body.u16()? } else { 0 };
+        if self.v5 {
+            body.properties()?;
+        }
+        let payload = body.rest().to_vec();
+        match qos {
+            0 => route_publish(&self.cache, &topic_name, payload, retain).await,
+            1 => {
+                route_publish(&self.cache, &topic_name, payload, retain).await;
+                self.write_ack(PUBACK, 0, packet_id).await?;
+            }
+            2 => {
+                if self.pending_release.insert(packet_id) {
+                    route_publish(&self.cache, &topic_name, payload, retain).await;
+                }
+                self.write_ack(PUBREC, 0, packet_id).await?;
+            }
+            _ => return Err(protocol_error("invalid QoS")),
+        }
+        Ok(())
+    }
+
+    /// 处理一个报文, 返回 false 时关闭连接
+    async fn execute(&mut self, packet: Packet) -> std::io::Result<bool> {
+        match packet.kind {
+            PUBLISH => self.publish(packet).await?,
+            PUBACK => {
+                let mut body = Body::new(&packet.body);
+                let packet_id = body.u16()?;
+                let reason = if body.is_empty() { 0 } else { body.u8()? };
+                if let Some(id) = self.inflight.remove(&packet_id) {
+                    // MQTT 5 客户端返回错误码时放回队列重新投递
+                    if reason >= 0x80 {
+                        ack::nack(&self.cache, id, true).await;
+                    } else {
+                        ack::ack(&self.cache, id).await;
+                    }
+                }
+            }
+            PUBREL => {
+                let packet_id = Body::new(&packet.body).u16()?;
+                self.pending_release.remove(&packet_id);
+                self.write_ack(PUBCOMP, 0, packet_id).await?;
+            }
+            // 服务端只发送 QoS 0 / 1 的消息
+            PUBREC | PUBCOMP => {}
+            SUBSCRIBE => self.subscribe(packet).await?,
+            UNSUBSCRIBE => self.unsubscribe(packet).await?,
+            PINGREQ => self.write(PINGRESP, 0, &[]).await?,
+            DISCONNECT => {
+                // MQTT 5 reason code 0x04 表示断开时仍然发布遗嘱消息
+                if packet.body.first() != Some(&0x04) {
+                    self.will = None;
+                }
+                return Ok(false);
+            }
+            CONNECT => return Err(protocol_error("duplicate CONNECT")),
+            kind => return Err(protocol_error(&format!("unsupported packet type {kind}"))),
+        }
+        Ok(true)
+    }
+
+    async fn run(
+        &mut self,
+        packets: &mut mpsc::Receiver<std::io::Result<Packet>>,
+        topic_rx: &mut mpsc::UnboundedReceiver<(String, Vec<u8>)>,
+        keep_alive: u16,
+    ) -> std::io::Result<()> {
+        // 超过 1.5 倍 keep alive 没有收到报文时断开连接
+        let idle_timeout = (keep_alive > 0).then(|| Duration::from_millis(keep_alive as u64 * 1500));
+        let mut last_packet = Instant::now();
+        loop {
+            let delivered = self.deliver().await?;
+            let wait = if delivered > 0 { Duration::ZERO } else { POLL_INTERVAL };
+            rocket::tokio::select! {
+                packet = packets.recv() => match packet {
+                    Some(Ok(packet)) => {
+                        last_packet = Instant::now();
+                        if !self.execute(packet).await? {
+                            return Ok(());
+                        }
+                    }
+                    Some(Err(err)) => return Err(err),
+                    None => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                },
+                Some((filter, msg)) = topic_rx.recv() => {
+                    if self.subscriptions.contains_key(&filter) {
+                        self.write_publish(&filter, &msg, 0, 0, false).await?;
+                    }
+                }
+                _ = rocket::tokio::time::sleep(wait) => {}
+            }
+            if idle_timeout.is_some_and(|timeout| last_packet.elapsed() > timeout) {
+                return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "keep alive timeout"));
+            }
+        }
+    }
+}
+
+/// 检查 CONNECT 中的用户名密码, 密码为 token, 没有密码时用户名为 token
+fn authorized(cache: &WebCache, connect: &Connect) -> bool {
+    let Some(token) = &cache.token else {
+        return true;
+    };
+    let given = match &connect.password {
+        Some(password) => std::str::from_utf8(password).ok(),
+        None => connect.username.as_deref(),
+    };
+    given == Some(token.as_str())
+}
+
+/// 处理一个连接, 读取报文在单独的任务中执行, 同时可以推送订阅的消息
+async fn handle(cache: WebCache, stream: TcpStream) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let (packet_tx, mut packets) = mpsc::channel::<std::io::Result<Packet>>(64);
+    let reader_task = rocket::tokio::spawn(async move {
+        let mut reader = BufReader::new(reader);
+        loop {
+            let packet = read_packet(&mut reader).await;
+            let stop = !matches!(packet, Ok(Some(_)));
+            let sent = match packet {
+                Ok(Some(packet)) => packet_tx.send(Ok(packet)).await.is_ok(),
+                Ok(None) => true,
+                Err(err) => packet_tx.send(Err(err)).await.is_ok(),
+            };
+            if stop || !sent {
+                break;
+            }
+        }
+    });
+    let first = rocket::tokio::time::timeout(CONNECT_TIMEOUT, packets.recv()).await;
+    let packet = match first {
+        Ok(Some(Ok(packet))) if packet.kind == CONNECT => packet,
+        Ok(Some(Err(err))) => {
+            reader_task.abort();
+            return Err(err);
+        }
+        _ => {
+            reader_task.abort();
+            return Err(protocol_error("expected CONNECT"));
+        }
+    };
+    let Some(connect) = parse_connect(&packet.body)? else {
+        // 不支持的协议版本, 按 3.1.1 的格式回复
+        writer.write_all(&encode(CONNACK, 0, &[0, 0x01])).await?;
+        reader_task.abort();
+        return Ok(());
+    };
+    if connect.receive_maximum == Some(0) {
+        // MQTT 5 规定 Receive Maximum 为 0 是协议错误
+        writer.write_all(&encode(CONNACK, 0, &[0, 0x82, 0])).await?;
+        reader_task.abort();
+        return Err(protocol_error("Receive Maximum must not be 0"));
+    }
+    if !authorized(&cache, &connect) {
+        let code = if connect.v5 { vec![0, 0x86, 0] } else { vec![0, 0x04] };
+        writer.write_all(&encode(CONNACK, 0, &code)).await?;
+        reader_task.abort();
+        return Ok(());
+    }
+    let mut connack = vec![0, 0];
+    if connect.v5 {
+        let mut props = vec![0x24, 1, 0x28, 0, 0x29, 0, 0x2a, 0];
+        if connect.client_id.is_empty() {
+            props.push(0x12);
+            put_str(&mut props, &format!("sse-queue-{}", ack::next_id(&cache)));
+        }
+        put_varint(&mut connack, props.len());
+        connack.extend_from_slice(&props);
+    }
+    writer.write_all(&encode(CONNACK, 0, &connack)).await?;
+    log::debug!("mqtt client {} connected, v5: {}", connect.client_id, connect.v5);
+    let (topic_tx, mut topic_rx) = mpsc::unbounded_channel();
+    let max_inflight = connect.receive_maximum.map_or(MAX_INFLIGHT, |n| (n as usize).min(MAX_INFLIGHT));
+    let mut conn = Connection {
+        cache,
+        writer,
+        v5: connect.v5,
+        subscriptions: BTreeMap::new(),
+        next_queue: 0,
+        inflight: BTreeMap::new(),
+        max_inflight,
+        next_packet_id: 0,
+        pending_release: BTreeSet::new(),
+        will: connect.will,
+        topic_tx,
+    };
+    let result = conn.run(&mut packets, &mut topic_rx, connect.keep_alive).await;
+    for (_, sub) in std::mem::take(&mut conn.subscriptions) {
+        if let Target::Topic(task) = sub.target {
+            task.abort();
+        }
+    }
+    ack::requeue_all(&conn.cache, std::mem::take(&mut conn.inflight).into_values()).await;
+    if let Some((topic_name, payload, retain)) = conn.will.take() {
+        route_publish(&conn.cache, &topic_name, payload, retain).await;
+    }
+    reader_task.abort();
+    result
+}
+
+/// 启动 MQTT 服务, 绑定失败时只记录错误, 不影响 http 服务
+pub async fn serve(cache: WebCache, bind: String) {
+    let listener = match TcpListener::bind(&bind).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("mqtt listen on {bind} error: {err}");
+            return;
+        }
+    };
+    log::info!("mqtt on: {bind}");
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                let cache = cache.clone();
+                rocket::tokio::spawn(async move {
+                    if let Err(err) = handle(cache, stream).await {
+                        log::debug!("mqtt client {addr} closed: {err}");
+                    }
+                });
+            }
+            Err(err) => log::warn!("mqtt accept error: {err}"),
+        }
+    }
+}
@@ -1,17 +1,47 @@
-use super::auth::TokenAuth;
+use super::auth::{Headers, TokenAuth};
+use super::groups;
+use super::idempotency::{self, PutResult};
 use super::state::WebCache;
 use super::types::ResultBase;
 use rocket::serde::json::Json;
+use rocket::serde::Serialize;
 use rocket::tokio::io::AsyncReadExt;
 use rocket::{data::ToByteUnit, get, post, Data, State};
 
-#[post("/queue/put?<queue>", data = "<data>")]
+/// put 的结果, 没有 Idempotency-Key / dedup_id 时为 true
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde", untagged)]
+enum PutReply {
+    Queued(bool),
+    Keyed(PutResult),
+}
+
+/// 放入队列, 带 key 时窗口内重复的 put 不再放入, 返回原来的去重 id
+async fn put_msg(queue: &str, bytes: Vec<u8>, key: Option<&str>, group: Option<&str>, state: &WebCache) -> PutReply {
+    match key.filter(|key| !key.is_empty()) {
+        Some(key) => PutReply::Keyed(idempotency::put_once(state, queue, key, group, bytes).await),
+        None => {
+            groups::push(state, queue, group, bytes).await;
+            PutReply::Queued(true)
+        }
+    }
+}
+
+/// header Idempotency-Key 优先, 其次为参数 dedup_id
+fn dedup_key<'a>(headers: &'a Headers, dedup_id: Option<&'a str>) -> Option<&'a str> {
+    headers.kv.get("idempotency-key").map(String::as_str).or(dedup_id)
+}
+
+#[post("/queue/put?<queue>&<dedup_id>&<group>", data = "<data>")]
 async fn put_to_queue1<'r>(
     queue: &str,
+    dedup_id: Option<&str>,
+    group: Option<&str>,
     data: Data<'r>,
     state: &State<WebCache>,
+    headers: Headers,
     auth: TokenAuth,
-) -> super::WebResult<Json<ResultBase<bool>>> {
+) -> super::WebResult<Json<ResultBase<PutReply>>> {
     auth.check_pass_root()?;
     let mut bytes = Vec::new();
     // 限制大小为10M
@@ -23,17 +53,20 @@ async fn put_to_queue1<'r>(
     {
         return Err(super::WebError::new("data too large"));
     }
-    state.queue_push_msg(queue, bytes).await;
-    Ok(Json(ResultBase::ok(true)))
+    let reply = put_msg(queue, bytes, dedup_key(&headers, dedup_id), group, state).await;
+    Ok(Json(ResultBase::ok(reply)))
 }
 
-#[post("/<queue>/put", data = "<data>")]
+#[post("/<queue>/put?<dedup_id>&<group>", data = "<data>")]
 async fn put_to_queue2<'r>(
     queue: &str,
+    dedup_id: Option<&str>,
+    group: Option<&str>,
     data: Data<'r>,
     state: &State<WebCache>,
+    headers: Headers,
     auth: TokenAuth,
-) -> super::WebResult<Json<ResultBase<bool>>> {
+) -> super::WebResult<Json<ResultBase<PutReply>>> {
     auth.check_pass_root()?;
     // log::debug!("auth pass");
     let mut bytes = Vec::new();
@@ -47,21 +80,24 @@ async fn put_to_queue2<'r>(
         return Err(super::WebError::new("data too large"));
     }
     // log::debug!("data recviced. {}",String::from_utf8_lossy(&bytes));
-    state.queue_push_msg(queue, bytes).await;
+    let reply = put_msg(queue, bytes, dedup_key(&headers, dedup_id), group, state).await;
     // log::debug!("queue pushed.");
-    Ok(Json(ResultBase::ok(true)))
+    Ok(Json(ResultBase::ok(reply)))
 }
 
-#[get("/<queue>/put?<content>")]
-async fn put_to_queue3<'r>(
+#[get("/<queue>/put?<content>&<dedup_id>&<group>")]
+async fn put_to_queue3(
     queue: &str,
     content: String,
+    dedup_id: Option<&str>,
+    group: Option<&str>,
     state: &State<WebCache>,
+    headers: Headers,
     auth: TokenAuth,
-) -> super::WebResult<Json<ResultBase<bool>>> {
+) -> super::WebResult<Json<ResultBase<PutReply>>> {
     auth.check_pass_root()?;
-    state.queue_push_msg(queue, content.as_bytes().to_vec()).await;
-    Ok(Json(ResultBase::ok(true)))
+    let reply = put_msg(queue, content.into_bytes(), dedup_key(&headers, dedup_id), group, state).await;
+    Ok(Json(ResultBase::ok(reply)))
 }
 
 async fn wait_msg(queue: &str, timeout: Option<usize>, state: &State<WebCache>) -> Option<String> {
@@ -80,7 +116,7 @@ async fn wait_msg(queue: &str, timeout: Option<usize>, state: &State<WebCache>)
 }
 
 #[get("/queue/get?<queue>&<timeout>")]
-async fn pop_from_queue1<'r>(
+async fn pop_from_queue1(
     queue: &str,
     timeout: Option<usize>,
     state: &State<WebCache>,
@@ -92,7 +128,7 @@ async fn pop_from_queue1<'r>(
 }
 
 #[get("/<queue>/get?<timeout>")]
-async fn pop_from_queue2<'r>(
+async fn pop_from_queue2(
     queue: &str,
     timeout: Option<usize>,
     state: &State<WebCache>,
@@ -104,7 +140,7 @@ async fn pop_from_queue2<'r>(
 }
 
 #[get("/queue/pick?<queue>&<index>")]
-async fn pick_from_queue1<'r>(
+async fn pick_from_queue1(
     queue: &str,
     index: usize,
     state: &State<WebCache>,
@@ -116,7 +152,7 @@ async fn pick_from_queue1<'r>(
 }
 
 #[get("/<queue>/pick/<index>")]
-async fn pick_from_queue2<'r>(
+async fn pick_from_queue2(
     queue: &str,
     index: usize,
     state: &State<WebCache>,
@@ -128,7 +164,7 @@ async fn pick_from_queue2<'r>(
 }
 
 #[get("/<queue>/last")]
-async fn last_from_queue<'r>(
+async fn last_from_queue(
     queue: &str,
     state: &State<WebCache>,
     auth: TokenAuth,
@@ -139,7 +175,7 @@ async fn last_from_queue<'r>(
 }
 
 #[get("/<queue>/first")]
-async fn first_from_queue<'r>(
+async fn first_from_queue(
     queue: &str,
     state: &State<WebCache>,
     auth: TokenAuth,
@@ -158,12 +194,17 @@ async fn listen_from_queue1<'r>(
     auth: TokenAuth,
 ) -> super::WebResult<EventStream![Event + 'r]> {
     auth.check_pass_root()?;
+    let name = queue;
     let queue = state.queue_listen(queue).await;
     let inst_until = timeout.map(|v| std::time::Instant::now() + std::time::Duration::from_secs(v));
     Ok(EventStream! {
         let mut interval = rocket::tokio::time::interval(std::time::Duration::from_secs_f32(0.1));
         loop {
-            let msg = queue.lock().await.pop_back();
+            let entry = queue.lock().await.pop_back();
+            if let Some(entry) = &entry {
+                groups::popped(state, name, entry.group.as_deref(), true).await;
+            }
+            let msg = entry.map(|e| e.msg);
             if msg.is_some() {
                 let msg = msg.map(|s|String::from_utf8_lossy(&s).to_string());
                 yield Event::json(&ResultBase::ok(msg));
@@ -191,12 +232,17 @@ async fn listen_from_queue2<'r>(
     auth: TokenAuth,
 ) -> super::WebResult<EventStream![Event + 'r]> {
     auth.check_pass_root()?;
+    let name = queue;
     let queue = state.queue_listen(queue).await;
     let inst_until = timeout.map(|v| std::time::Instant::now() + std::time::Duration::from_secs(v));
     Ok(EventStream! {
         let mut interval = rocket::tokio::time::interval(std::time::Duration::from_secs_f32(0.1));
         loop {
-            let msg = queue.lock().await.pop_back();
+            let entry = queue.lock().await.pop_back();
+            if let Some(entry) = &entry {
+                groups::popped(state, name, entry.group.as_deref(), true).await;
+            }
+            let msg = entry.map(|e| e.msg);
             if msg.is_some() {
                 let msg = msg.map(|s|String::from_utf8_lossy(&s).to_string());
                 yield Event::json(&ResultBase::ok(msg));
@@ -217,7 +263,7 @@ async fn listen_from_queue2<'r>(
 }
 
 pub fn routes() -> Vec<rocket::Route> {
-    rocket::routes![
+    let mut routes = rocket::routes![
         put_to_queue1,
         put_to_queue2,
         put_to_queue3,
@@ -229,5 +275,11 @@ pub fn routes() -> Vec<rocket::Route> {
         listen_from_queue2,
         last_from_queue,
         first_from_queue,
-    ]
+    ];
+    routes.extend(super::msgws::routes());
+    routes.extend(super::msgmulti::routes());
+    routes.extend(super::stomp::routes());
+    routes.extend(super::webhook::routes());
+    routes.extend(super::rpc::routes());
+    routes
 }
@@ -0,0 +1,204 @@
+use std::time::{Duration, Instant};
+
+use super::auth::TokenAuth;
+use super::rpc::REPLY_PREFIX;
+use super::state::WebCache;
+use super::types::ResultBase;
+
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{get, State};
+
+/// 没有消息时检查队列的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 带来源队列的消息
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct QueueMsg {
+    queue: String,
+    data: String,
+}
+
+/// 多个队列都有消息时的选择顺序
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QueueOrder {
+    /// 轮流从每个队列中取
+    RoundRobin,
+    /// 按参数中的顺序, 前面的队列为空时才取后面的队列
+    Priority,
+    /// 按权重随机选择
+    Weighted,
+}
+
+/// 参数中的一个队列名称或 glob 通配符
+struct QueuePattern {
+    name: String,
+    glob: Option<glob::Pattern>,
+    weight: u32,
+}
+
+/// 从多个队列中选择下一条消息
+struct QueueSelector {
+    patterns: Vec<QueuePattern>,
+    order: QueueOrder,
+    next: usize,
+}
+
+impl QueueSelector {
+    /// queue 可以重复或使用逗号分隔, weights 与 queue 一一对应, 缺少时为 1
+    fn new(queue: Vec<String>, order: Option<&str>, weights: Option<&str>) -> super::WebResult<Self> {
+        let names = queue
+            .iter()
+            .flat_map(|q| q.split(','))
+            .map(str::trim)
+            .filter(|q| !q.is_empty())
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            return Err(super::WebError::new("queue is required"));
+        }
+        let weights = weights
+            .unwrap_or_default()
+            .split(',')
+            .filter(|w| !w.trim().is_empty())
+            .map(|w| match w.trim().parse::<u32>() {
+                Ok(w) if w > 0 => Ok(w),
+                _ => Err(super::WebError::new(format!("invalid weight: {w}"))),
+            })
+            .collect::<super::WebResult<Vec<_>>>()?;
+        let order = match order {
+            None if weights.is_empty() => QueueOrder::RoundRobin,
+            None | Some("weighted") => QueueOrder::Weighted,
+            Some("round-robin") => QueueOrder::RoundRobin,
+            Some("priority") => QueueOrder::Priority,
+            Some(order) => return Err(super::WebError::new(format!("unknown order: {order}"))),
+        };
+        let mut patterns = Vec::with_capacity(names.len());
+        for (idx, name) in names.into_iter().enumerate() {
+            let glob = if name.contains(['*', '?', '[']) {
+                Some(glob::Pattern::new(name).map_err(|err| super::WebError::new(format!("invalid queue pattern {name}: {err}")))?)
+            } else {
+                None
+            };
+            let weight = weights.get(idx).copied().unwrap_or(1);
+            patterns.push(QueuePattern { name: name.to_string(), glob, weight });
+        }
+        Ok(Self { patterns, order, next: rand::random::<usize>() })
+    }
+
+    /// 展开通配符, 得到按选择顺序排列的队列, 通配符不匹配 call 的临时回复队列
+    async fn candidates(&mut self, cache: &WebCache) -> Vec<String> {
+        let mut queues: Vec<(String, u32)> = Vec::new();
+        let existing = if self.patterns.iter().any(|p| p.glob.is_some()) {
+            cache.queue.lock().await.keys().cloned().collect::<Vec<_>>()
+        } else {
+            vec![]
+        };
+        for pattern in &self.patterns {
+            let matched = match &pattern.glob {
+                Some(glob) => existing
+                    .iter()
+                    .filter(|q| !q.starts_with(REPLY_PREFIX) && glob.matches(q))
+                    .cloned()
+                    .collect(),
+                None => vec![pattern.name.clone()],
+            };
+            for queue in matched {
+                if !queues.iter().any(|(q, _)| q == &queue) {
+                    queues.push((queue, pattern.weight));
+                }
+            }
+        }
+        match self.order {
+            QueueOrder::Priority => {}
+            QueueOrder::RoundRobin => {
+                if !queues.is_empty() {
+                    let start = self.next % queues.len();
+                    queues.rotate_left(start);
+                    self.next = self.next.wrapping_add(1);
+                }
+            }
+            QueueOrder::Weighted => {
+                // 按 u^(1/w) 从大到小排列, 每个队列排在第一位的概率与权重成正比
+                let mut keyed = queues
+                    .into_iter()
+                    .map(|(q, w)| (rand::random::<f64>().powf(1.0 / w as f64), q, w))
+                    .collect::<Vec<_>>();
+                keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+                queues = keyed.into_iter().map(|(_, q, w)| (q, w)).collect();
+            }
+        }
+        queues.into_iter().map(|(q, _)| q).collect()
+    }
+
+    /// 按选择顺序取出第一条消息
+    async fn pop(&mut self, cache: &WebCache) -> Option<QueueMsg> {
+        for queue in self.candidates(cache).await {
+            if let Some(msg) = cache.queue_pop_msg(&queue).await {
+                let data = String::from_utf8_lossy(&msg).to_string();
+                return Some(QueueMsg { queue, data });
+            }
+        }
+        None
+    }
+}
+
+#[get("/get?<queue>&<timeout>&<order>&<weights>")]
+async fn multi_get(
+    queue: Vec<String>,
+    timeout: Option<u64>,
+    order: Option<&str>,
+    weights: Option<&str>,
+    state: &State<WebCache>,
+    auth: TokenAuth,
+) -> super::WebResult<Json<ResultBase<Option<QueueMsg>>>> {
+    auth.check_pass_root()?;
+    let mut selector = QueueSelector::new(queue, order, weights)?;
+    // 超出 Instant 范围的 timeout 视为一直等待
+    let until = Instant::now().checked_add(Duration::from_secs(timeout.unwrap_or_default()));
+    loop {
+        if let Some(msg) = selector.pop(state).await {
+            return Ok(Json(ResultBase::ok(Some(msg))));
+        }
+        if until.is_some_and(|until| Instant::now() >= until) {
+            return Ok(Json(ResultBase::ok(None)));
+        }
+        rocket::tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[get("/listen?<queue>&<timeout>&<order>&<weights>")]
+async fn multi_listen<'r>(
+    queue: Vec<String>,
+    timeout: Option<u64>,
+    order: Option<&str>,
+    weights: Option<&str>,
+    state: &'r State<WebCache>,
+    auth: TokenAuth,
+) -> super::WebResult<EventStream![Event + 'r]> {
+    auth.check_pass_root()?;
+    let mut selector = QueueSelector::new(queue, order, weights)?;
+    // 超出 Instant 范围的 timeout 视为不超时
+    let inst_until = timeout.and_then(|v| Instant::now().checked_add(Duration::from_secs(v)));
+    Ok(EventStream! {
+        loop {
+            if let Some(msg) = selector.pop(state).await {
+                yield Event::json(&ResultBase::ok(Some(msg)));
+                continue;
+            }
+            if let Some(until) = &inst_until {
+                if &Instant::now() > until {
+                    log::info!("timeout to close sse");
+                    yield Event::data("bye");
+                    break;
+                }
+            }
+            rocket::tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    })
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![multi_get, multi_listen]
+}
@@ -0,0 +1,237 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use super::ack;
+use super::auth::TokenAuth;
+use super::state::WebCache;
+
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{get, State};
+use rocket_ws as ws;
+
+/// 没有可投递的消息时检查队列的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 默认允许的未确认消息数量
+const DEFAULT_CREDIT: usize = 1;
+
+fn default_true() -> bool {
+    true
+}
+
+/// 客户端发送的命令, 可以带上 "ref", 服务端在 ok / error 中原样返回
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "op", rename_all = "kebab-case")]
+enum Command {
+    Subscribe { queue: String },
+    Unsubscribe { queue: String },
+    Ack { id: u64 },
+    Nack {
+        id: u64,
+        #[serde(default = "default_true")]
+        requeue: bool,
+    },
+    Publish { queue: String, data: String },
+    /// 允许同时未确认的消息数量, 0 表示暂停投递
+    Credit { credit: usize },
+}
+
+/// 服务端发送的消息
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde", tag = "type", rename_all = "kebab-case")]
+enum Reply<'a> {
+    Message { id: u64, queue: &'a str, data: String },
+    Ok {
+        op: &'a str,
+        #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
+        reference: Option<serde_json::Value>,
+    },
+    Error {
+        msg: String,
+        #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
+        reference: Option<serde_json::Value>,
+    },
+}
+
+impl Reply<'_> {
+    fn into_message(self) -> ws::Message {
+        ws::Message::Text(serde_json::to_string(&self).unwrap_or_default())
+    }
+}
+
+/// 一个 WebSocket 连接的订阅状态
+struct Session {
+    cache: WebCache,
+    /// 订阅的队列, 轮流投递
+    queues: Vec<String>,
+    next_queue: usize,
+    credit: usize,
+    /// 已投递未确认的消息 id
+    outstanding: BTreeSet<u64>,
+}
+
+impl Session {
+    /// 在 credit 允许时从订阅的队列中取出一条消息
+    async fn next_delivery(&mut self) -> Option<(u64, String, Vec<u8>)> {
+        // 超时没有确认的消息已经放回队列, 不再占用 credit
+        ack::retain_pending(&self.cache, &mut self.outstanding).await;
+        if self.outstanding.len() >= self.credit {
+            return None;
+        }
+        for _ in 0..self.queues.len() {
+            let idx = self.next_queue % self.queues.len();
+            self.next_queue = idx + 1;
+            let queue = &self.queues[idx];
+            if let Some((id, msg)) = ack::pop_deliver(&self.cache, queue, Some(self.cache.ack_timeout)).await {
+                self.outstanding.insert(id);
+                return Some((id, queue.clone(), msg));
+            }
+        }
+        None
+    }
+
+    /// 执行一条命令, 返回 op 名称
+    async fn execute(&mut self, command: Command) -> Result<&'static str, String> {
+        match command {
+            Command::Subscribe { queue } => {
+                if !self.queues.contains(&queue) {
+                    self.queues.push(queue);
+                }
+                Ok("subscribe")
+            }
+            Command::Unsubscribe { queue } => {
+                self.queues.retain(|q| *q != queue);
+                Ok("unsubscribe")
+            }
+            Command::Ack { id } => {
+                if !self.outstanding.remove(&id) {
+                    return Err(format!("unknown delivery id: {id}"));
+                }
+                ack::ack(&self.cache, id).await;
+                Ok("ack")
+            }
+            Command::Nack { id, requeue } => {
+                if !self.outstanding.remove(&id) {
+                    return Err(format!("unknown delivery id: {id}"));
+                }
+                ack::nack(&self.cache, id, requeue).await;
+                Ok("nack")
+            }
+            Command::Publish { queue, data } => {
+                self.cache.queue_push_msg(&queue, data.into_bytes()).await;
+                Ok("publish")
+            }
+            Command::Credit { credit } => {
+                self.credit = credit;
+                Ok("credit")
+            }
+        }
+    }
+
+    async fn handle_text(&mut self, text: &str) -> ws::Message {
+        let value = match serde_json::from_str::<serde_json::Value>(text) {
+            Ok(value) => value,
+            Err(err) => return Reply::Error { msg: format!("invalid json: {err}"), reference: None }.into_message(),
+        };
+        let reference = value.get("ref").cloned();
+        let command = match serde_json::from_value::<Command>(value) {
+            Ok(command) => command,
+            Err(err) => return Reply::Error { msg: format!("invalid command: {err}"), reference }.into_message(),
+        };
+        match self.execute(command).await {
+            Ok(op) => Reply::Ok { op, reference }.into_message(),
+            Err(msg) => Reply::Error { msg, reference }.into_message(),
+        }
+    }
+
+    async fn serve(&mut self, stream: &mut ws::stream::DuplexStream) -> ws::result::Result<()> {
+        loop {
+            while let Some((id, queue, msg)) = self.next_delivery().await {
+                let data = String::from_utf8_lossy(&msg).into_owned();
+                stream.send(Reply::Message { id, queue: &queue, data }.into_message()).await?;
+            }
+            rocket::tokio::select! {
+                message = stream.next() => {
+                    let reply = match message {
+                        Some(Ok(ws::Message::Text(text))) => self.handle_text(&text).await,
+                        Some(Ok(ws::Message::Close(_))) | None => return Ok(()),
+                        Some(Ok(_)) => continue,
+                        Some(Err(err)) => return Err(err),
+                    };
+                    stream.send(reply).await?;
+                }
+                _ = rocket::tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    }
+}
+
+/// 1. websocket
+/// - url
+///     - "/msg/{queue}/ws"
+///     - "/msg/queue/ws?queue={queue}"
+///     - "/msg/ws"
+/// - 连接后订阅 queue, 每条消息需要 ack 或 nack, 连接断开时未确认的消息放回队列
+/// - client -> server(json text):
+///     - {"op":"subscribe","queue":"q2"}
+///     - {"op":"unsubscribe","queue":"q2"}
+///     - {"op":"ack","id":1}
+///     - {"op":"nack","id":1,"requeue":true}
+///     - {"op":"publish","queue":"q2","data":"msg"}
+///     - {"op":"credit","credit":10}
+/// - server -> client(json text):
+///     - {"type":"message","id":1,"queue":"q1","data":"msg"}
+///     - {"type":"ok","op":"ack","ref":1}
+///     - {"type":"error","msg":"unknown delivery id: 1","ref":1}
+fn open_session(ws: ws::WebSocket, queue: Option<&str>, cache: &WebCache) -> ws::Channel<'static> {
+    let mut session = Session {
+        cache: cache.clone(),
+        queues: queue.map(|q| vec![q.to_string()]).unwrap_or_default(),
+        next_queue: 0,
+        credit: DEFAULT_CREDIT,
+        outstanding: BTreeSet::new(),
+    };
+    ws.channel(move |mut stream| {
+        Box::pin(async move {
+            let result = session.serve(&mut stream).await;
+            ack::requeue_all(&session.cache, std::mem::take(&mut session.outstanding)).await;
+            if let Err(err) = &result {
+                log::debug!("websocket closed: {err}");
+            }
+            Ok(())
+        })
+    })
+}
+
+#[get("/queue/ws?<queue>")]
+async fn ws_queue1(
+    ws: ws::WebSocket,
+    queue: &str,
+    state: &State<WebCache>,
+    auth: TokenAuth,
+) -> super::WebResult<ws::Channel<'static>> {
+    auth.check_pass_root()?;
+    Ok(open_session(ws, Some(queue), state))
+}
+
+#[get("/<queue>/ws")]
+async fn ws_queue2(
+    ws: ws::WebSocket,
+    queue: &str,
+    state: &State<WebCache>,
+    auth: TokenAuth,
+) -> super::WebResult<ws::Channel<'static>> {
+    auth.check_pass_root()?;
+    Ok(open_session(ws, Some(queue), state))
+}
+
+#[get("/ws")]
+async fn ws_queue3(ws: ws::WebSocket, state: &State<WebCache>, auth: TokenAuth) -> super::WebResult<ws::Channel<'static>> {
+    auth.check_pass_root()?;
+    Ok(open_session(ws, None, state))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![ws_queue1, ws_queue2, ws_queue3]
+}
@@ -0,0 +1,51 @@
+use std::collections::BTreeSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::state::WebCache;
+use crate::config::StorageAction;
+
+use rocket::serde::Serialize;
+
+/// 对象变化时推送到队列的事件
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct StorageEvent<'a> {
+    pub bucket: &'a str,
+    /// 对象名称, delete-bucket 时为空
+    pub name: &'a str,
+    /// 变化后的对象大小, delete 时为删除前的大小
+    pub size: u64,
+    pub action: StorageAction,
+    /// unix 时间戳(毫秒)
+    pub timestamp: u64,
+}
+
+/// 按照 bucket 的通知规则向队列和主题发送事件, 多条规则指向同一个队列或主题时只发送一次
+pub async fn publish(cache: &WebCache, bucket: &str, name: &str, size: u64, action: StorageAction) {
+    let rules = cache
+        .notify_rules(bucket)
+        .into_iter()
+        .filter(|rule| rule.actions.is_empty() || rule.actions.contains(&action))
+        .filter(|rule| rule.prefix.as_deref().is_none_or(|prefix| name.starts_with(prefix)))
+        .collect::<Vec<_>>();
+    let queues = rules.iter().filter_map(|rule| rule.queue.as_deref()).collect::<BTreeSet<_>>();
+    let topics = rules.iter().filter_map(|rule| rule.topic.as_deref()).collect::<BTreeSet<_>>();
+    if queues.is_empty() && topics.is_empty() {
+        return;
+    }
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    let event = StorageEvent { bucket, name, size, action, timestamp };
+    let msg = match serde_json::to_vec(&event) {
+        Ok(msg) => msg,
+        Err(err) => {
+            log::warn!("encode storage event error: {err}");
+            return;
+        }
+    };
+    for queue in queues {
+        cache.queue_push_msg(queue, msg.clone()).await;
+    }
+    for topic in topics {
+        super::topic::publish(cache, topic, msg.clone()).await;
+    }
+}
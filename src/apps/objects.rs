@@ -0,0 +1,310 @@
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use rocket::http::ContentType;
+use rocket::serde::Serialize;
+use rocket::tokio::fs;
+
+/// 检查对象名称, 对象名称中的 `/` 映射为 bucket 下的子目录
+/// - 不能为空, 不能以 `/` 开头或结尾
+/// - 每一级名称不能为空, 也不能以 `.` 开头(隐藏目录保留给服务端使用)
+pub fn check_object_key(name: &str) -> std::io::Result<()> {
+    let invalid = name.is_empty()
+        || name
+            .split('/')
+            .any(|seg| seg.is_empty() || seg.starts_with('.') || seg.contains('\\'));
+    if invalid {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid object name: {name:?}"),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// 检查 bucket 名称, 只能是一级普通目录, 不能是 .blobs / .versions / .tmp 等隐藏目录
+pub fn check_bucket(bucket: &str) -> std::io::Result<()> {
+    let invalid = bucket.is_empty() || bucket.starts_with('.') || bucket.contains(['/', '\\']);
+    if invalid {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid bucket name: {bucket:?}"),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// 将路由中的多级路径转换为对象名称
+pub fn object_key(name: &Path) -> std::io::Result<String> {
+    let key = name
+        .iter()
+        .map(|seg| seg.to_str())
+        .collect::<Option<Vec<_>>>()
+        .map(|segs| segs.join("/"))
+        .unwrap_or_default();
+    check_object_key(&key)?;
+    Ok(key)
+}
+
+/// 删除文件后, 向上清理 bucket 内已经为空的子目录
+pub async fn remove_empty_parents(bucket_dir: &Path, path: &Path) {
+    let mut parent = path.parent();
+    while let Some(dir) = parent {
+        if dir == bucket_dir || !dir.starts_with(bucket_dir) {
+            break;
+        }
+        // 目录非空时删除失败, 直接结束
+        if fs::remove_dir(dir).await.is_err() {
+            break;
+        }
+        parent = dir.parent();
+    }
+}
+
+/// 对象列表中的一项
+#[derive(Debug, Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ObjectEntry {
+    /// 对象名称, 目录以 `/` 结尾
+    pub name: String,
+    pub size: u64,
+    /// 修改时间, unix 时间戳(秒)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+    pub is_dir: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    /// 上传时计算的摘要, hex 编码
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+impl ObjectEntry {
+    pub fn from_metadata(name: String, meta: &std::fs::Metadata) -> Self {
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+        if meta.is_dir() {
+            return Self {
+                name,
+                size: 0,
+                mtime: mtime.map(|t| t.as_secs()),
+                is_dir: true,
+                content_type: None,
+                etag: None,
+                md5: None,
+                sha256: None,
+            };
+        }
+        let content_type = Path::new(&name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(ContentType::from_extension)
+            .unwrap_or(ContentType::Binary);
+        let etag = mtime.map(|t| format!("\"{:x}-{:x}\"", t.as_nanos(), meta.len()));
+        Self {
+            size: meta.len(),
+            mtime: mtime.map(|t| t.as_secs()),
+            is_dir: false,
+            content_type: Some(content_type.to_string()),
+            etag,
+            md5: None,
+            sha256: None,
+            name,
+        }
+    }
+    fn common_prefix(name: String) -> Self {
+        Self {
+            name,
+            size: 0,
+            mtime: None,
+            is_dir: true,
+            content_type: None,
+            etag: None,
+            md5: None,
+            sha256: None,
+        }
+    }
+}
+
+/// 对象列表查询结果
+#[derive(Debug, Serialize, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct ObjectList {
+    pub entries: Vec<ObjectEntry>,
+    /// 下一页的起始标记, 为空表示没有更多数据
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_token: Option<String>,
+}
+
+/// 对象列表查询参数
+#[derive(Debug, Default)]
+pub struct ListOptions<'a> {
+    /// 对象名称前缀
+    pub prefix: &'a str,
+    /// 递归列出所有对象, 不合并子目录
+    pub recursive: bool,
+    /// 非递归模式下的分隔符, 默认 `/`
+    pub delimiter: Option<&'a str>,
+    /// 上一页返回的 next_token, 从该对象之后开始
+    pub token: Option<&'a str>,
+    /// 单页最大数量
+    pub max_keys: usize,
+}
+
+pub const DEFAULT_MAX_KEYS: usize = 1000;
+
+/// 目录不存在或者路径是一个文件, 列表为空
+fn is_missing_dir(err: &std::io::Error) -> bool {
+    matches!(err.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::NotADirectory)
+}
+
+/// 递归遍历目录, 返回 (对象名称, 元数据), 跳过隐藏文件
+pub async fn walk_objects(
+    bucket_dir: &Path,
+    start: &Path,
+) -> std::io::Result<Vec<(String, std::fs::Metadata)>> {
+    let mut objects = vec![];
+    let mut stack: Vec<PathBuf> = vec![start.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(err) if is_missing_dir(&err) && dir != bucket_dir => continue,
+            Err(err) => return Err(err),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let hidden = entry.file_name().to_str().is_none_or(|s| s.starts_with('.'));
+            if hidden {
+                continue;
+            }
+            let meta = entry.metadata().await?;
+            let path = entry.path();
+            if meta.is_dir() {
+                stack.push(path);
+            } else if let Some(key) = path
+                .strip_prefix(bucket_dir)
+                .ok()
+                .and_then(|p| p.to_str())
+            {
+                objects.push((key.to_string(), meta));
+            }
+        }
+    }
+    Ok(objects)
+}
+
+/// 读取一个目录, 按对象名称排序, 目录名称后面加上 `/` 参与排序, 深度优先遍历时对象名称有序
+async fn read_sorted(bucket_dir: &Path, dir: &Path) -> std::io::Result<Vec<(String, std::fs::Metadata)>> {
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(err) if is_missing_dir(&err) && dir != bucket_dir => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+    let mut children = vec![];
+    while let Some(entry) = entries.next_entry().await? {
+        let hidden = entry.file_name().to_str().is_none_or(|s| s.starts_with('.'));
+        if hidden {
+            continue;
+        }
+        let meta = entry.metadata().await?;
+        let path = entry.path();
+        if let Some(key) = path.strip_prefix(bucket_dir).ok().and_then(|p| p.to_str()) {
+            let key = if meta.is_dir() { format!("{key}/") } else { key.to_string() };
+            children.push((key, meta));
+        }
+    }
+    children.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(children)
+}
+
+/// 列出 bucket 中的对象
+/// - recursive: 返回前缀下所有对象
+/// - 否则按照 delimiter 将更深层的对象合并为目录项
+///
+/// 按对象名称顺序遍历, 跳过 token 之前的子目录, 取满一页后停止
+pub async fn list_objects(bucket_dir: &Path, opts: &ListOptions<'_>) -> std::io::Result<ObjectList> {
+    // 只遍历前缀所在的最深目录
+    let start = match opts.prefix.rfind('/') {
+        Some(idx) => bucket_dir.join(&opts.prefix[..idx]),
+        None => bucket_dir.to_path_buf(),
+    };
+    let delimiter = opts.delimiter.filter(|d| !d.is_empty()).unwrap_or("/");
+    let max_keys = if opts.max_keys == 0 { DEFAULT_MAX_KEYS } else { opts.max_keys };
+    let token = opts.token.unwrap_or_default();
+    let after_token = |name: &str| token.is_empty() || name > token;
+    let mut entries: Vec<(ObjectEntry, Option<std::fs::Metadata>)> = vec![];
+    let mut stack = vec![read_sorted(bucket_dir, &start).await?.into_iter()];
+    while entries.len() <= max_keys {
+        let Some(children) = stack.last_mut() else {
+            break;
+        };
+        let Some((key, meta)) = children.next() else {
+            stack.pop();
+            continue;
+        };
+        if meta.is_dir() {
+            if !key.starts_with(opts.prefix) && !opts.prefix.starts_with(&key) {
+                continue;
+            }
+            // 目录下所有对象都在 token 之前
+            if !token.is_empty() && token >= key.as_str() && !token.starts_with(&key) {
+                continue;
+            }
+            if !opts.recursive && delimiter == "/" && key.starts_with(opts.prefix) {
+                // 分隔符为 `/` 时目录就是合并后的目录项, 不需要进入
+                if after_token(&key) {
+                    entries.push((ObjectEntry::from_metadata(key, &meta), None));
+                }
+                continue;
+            }
+            stack.push(read_sorted(bucket_dir, &bucket_dir.join(&key)).await?.into_iter());
+            continue;
+        }
+        if !key.starts_with(opts.prefix) {
+            continue;
+        }
+        if !opts.recursive {
+            let rest = &key[opts.prefix.len()..];
+            if let Some(idx) = rest.find(delimiter) {
+                let dir = format!("{}{}", opts.prefix, &rest[..idx + delimiter.len()]);
+                if after_token(&dir) && entries.last().is_none_or(|e| e.0.name != dir) {
+                    let entry = match fs::metadata(bucket_dir.join(&dir)).await {
+                        Ok(m) if m.is_dir() => ObjectEntry::from_metadata(dir, &m),
+                        _ => ObjectEntry::common_prefix(dir),
+                    };
+                    entries.push((entry, None));
+                }
+                continue;
+            }
+        }
+        if after_token(&key) {
+            entries.push((ObjectEntry::from_metadata(key, &meta), Some(meta)));
+        }
+    }
+    let next_token = if entries.len() > max_keys {
+        entries.truncate(max_keys);
+        entries.last().map(|e| e.0.name.clone())
+    } else {
+        None
+    };
+    // 只读取当前页对象的摘要
+    let mut page = Vec::with_capacity(entries.len());
+    for (mut entry, meta) in entries {
+        if let Some(meta) = meta {
+            if let Some(checksums) = super::checksum::load(bucket_dir, &entry.name, &meta).await {
+                entry.md5 = Some(checksums.md5);
+                entry.sha256 = Some(checksums.sha256);
+            }
+        }
+        page.push(entry);
+    }
+    let entries = page;
+    Ok(ObjectList { entries, next_token })
+}
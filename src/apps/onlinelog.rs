@@ -1,33 +1,128 @@
-use std::ops::DerefMut;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
 use super::auth::TokenAuth;
 use super::state::WebCache;
-use rocket::tokio::io;
+use super::structlog::{self, LogFormat};
+use rocket::tokio::fs::File;
+use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use chrono::{Local, SecondsFormat};
+use rocket::data::DataStream;
 use rocket::{data::ToByteUnit,  post,get, Data, State};
 
 
-#[post("/upload?<channel>&<name>",data="<data>")]
-async fn upload_log_lines1(channel:&str,name:&str, data:Data<'_>, cache: &State<WebCache>,auth: TokenAuth) -> super::WebResult<String>{
-    auth.check_pass_root()?;
-    let file = cache.open_online_log(channel, name).await?;
-    {
-        let f = &mut file.lock().await;
+/// 按行或结构化日志一次上传的最大大小
+const BUFFERED_LIMIT_MIB: usize = 64;
+/// raw 格式上传每次写入的数据块大小
+const RAW_CHUNK: usize = 64 * 1024;
+/// channel 目录中的隐藏标记文件, 第一次上传日志时创建, 用于区分日志 channel 和普通 bucket
+pub const CHANNEL_MARKER: &str = ".onlinelog";
+
+/// 写入日志的内容
+enum Payload<'r> {
+    Raw(Box<DataStream<'r>>),
+    /// 已经整理为完整行的内容
+    Lines(Vec<u8>),
+}
+
+/// 按行整理请求的内容, 最后一行补全换行符
+/// - timestamp: 每行前加上 RFC3339 格式的接收时间
+/// - source: 每行前加上 [source]
+fn frame_lines(body: &[u8], timestamp: bool, source: Option<&str>) -> Vec<u8> {
+    let mut prefix = String::new();
+    if timestamp {
+        prefix.push_str(&Local::now().to_rfc3339_opts(SecondsFormat::Millis, false));
+        prefix.push(' ');
+    }
+    if let Some(source) = source {
+        let source = source.replace(['\r', '\n'], " ");
+        prefix.push_str(&format!("[{source}] "));
+    }
+    let body = body.strip_suffix(b"\n").unwrap_or(body);
+    let mut out = Vec::with_capacity(body.len() + 1);
+    if body.is_empty() {
+        return out;
+    }
+    for line in body.split(|b| *b == b'\n') {
+        out.extend_from_slice(prefix.as_bytes());
+        out.extend_from_slice(line.strip_suffix(b"\r").unwrap_or(line));
+        out.push(b'\n');
+    }
+    out
+}
+
+/// 持有文件锁写入日志, 写入前检查切分规则
+async fn write_payload(cache:&WebCache,channel:&str,name:&str,f:&mut File,payload:Payload<'_>) -> super::WebResult<()>{
+    super::logrotate::rotate_if_needed(cache, channel, name, f).await?;
+    match payload {
         // data.open(4.gibibytes()).stream_to(f).await?;
-        io::copy(&mut data.open(4.gibibytes()), f.deref_mut()).await?;
+        Payload::Raw(mut stream) => {
+            // 每个数据块写入前检查切分, 大的上传不会超出 max-size 太多
+            let mut buf = vec![0u8; RAW_CHUNK];
+            loop {
+                let n = stream.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                super::logrotate::rotate_if_needed(cache, channel, name, f).await?;
+                f.write_all(&buf[..n]).await?;
+            }
+            f.flush().await?;
+        }
+        Payload::Lines(lines) => {
+            f.write_all(&lines).await?;
+            f.flush().await?;
+        }
     }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn upload_log(channel:&str,name:&str,format:Option<&str>,timestamp:Option<bool>,source:Option<&str>,ip:Option<IpAddr>,data:Data<'_>,cache:&WebCache) -> super::WebResult<String>{
+    let format = LogFormat::parse(format)?;
+    // 除 raw 之外先完整读取请求, 持有文件锁时一次写入, 结构化日志任何一行不合法时不写入
+    let payload = if format == LogFormat::Raw {
+        Payload::Raw(Box::new(data.open(4.gibibytes())))
+    } else {
+        let body = data.open(BUFFERED_LIMIT_MIB.mebibytes()).into_bytes().await?;
+        if !body.is_complete() {
+            return Err(super::WebError::new(format!("log upload larger than {BUFFERED_LIMIT_MIB}MiB")));
+        }
+        match format {
+            LogFormat::Lines => Payload::Lines(frame_lines(&body, timestamp.unwrap_or_default(), source)),
+            format => Payload::Lines(structlog::encode(format, &body, ip)?),
+        }
+    };
+    let file = cache.open_online_log(channel, name).await?;
+    let written = {
+        let mut f = file.1.lock().await;
+        write_payload(cache, channel, name, &mut f, payload).await
+    };
+    // 写入完成后记录时间, 空闲清理按最后一次写入完成的时间计算
+    super::handles::touch(cache, super::handles::HandleKind::Log, &format!("{channel}/{name}")).await;
+    file.0.fetch_sub(1, Ordering::Relaxed);
+    written?;
     Ok("ok".to_string())
 }
 
+/// 1. post log stream
+/// - format: raw | lines | json | logfmt, 默认 raw
+///     - lines: 整行一次写入, 多个客户端同时写入时不会出现半行交错
+///         - timestamp: bool, 每行前加上 RFC3339 格式的接收时间
+///         - source: string, 每行前加上 [source]
+///     - json / logfmt: 每行解析为一条记录, 加上 _ts(接收时间, 毫秒) 和 _ip(客户端 ip) 后以 JSON lines 保存
+#[allow(clippy::too_many_arguments)]
+#[post("/upload?<channel>&<name>&<format>&<timestamp>&<source>",data="<data>")]
+async fn upload_log_lines1(channel:&str,name:&str,format:Option<&str>,timestamp:Option<bool>,source:Option<&str>,ip:Option<IpAddr>, data:Data<'_>, cache: &State<WebCache>,auth: TokenAuth) -> super::WebResult<String>{
+    auth.check_pass_root()?;
+    upload_log(channel, name, format, timestamp, source, ip, data, cache).await
+}
+
 
-#[post("/upload/<channel>/<name>",data="<data>")]
-async fn upload_log_lines2(channel:&str,name:&str, data:Data<'_>, cache: &State<WebCache>,auth: TokenAuth) -> super::WebResult<String>{
+#[allow(clippy::too_many_arguments)]
+#[post("/upload/<channel>/<name>?<format>&<timestamp>&<source>",data="<data>")]
+async fn upload_log_lines2(channel:&str,name:&str,format:Option<&str>,timestamp:Option<bool>,source:Option<&str>,ip:Option<IpAddr>, data:Data<'_>, cache: &State<WebCache>,auth: TokenAuth) -> super::WebResult<String>{
     auth.check_pass_root()?;
-    let file = cache.open_online_log(channel, name).await?;
-    {
-        let f = &mut file.lock().await;
-        // data.open(4.gibibytes()).stream_to(f).await?;
-        io::copy(&mut data.open(4.gibibytes()), f.deref_mut()).await?;
-    }
-    Ok("ok".to_string())
+    upload_log(channel, name, format, timestamp, source, ip, data, cache).await
 }
 
 #[get("/close?<channel>&<name>")]
@@ -45,5 +140,9 @@ async fn close_log2(channel:&str,name:&str, cache: &State<WebCache>,auth: TokenA
 }
 
 pub fn routes() -> Vec<rocket::Route>{
-    rocket::routes![upload_log_lines1,upload_log_lines2,close_log1,close_log2]
+    let mut routes = rocket::routes![upload_log_lines1,upload_log_lines2,close_log1,close_log2];
+    routes.extend(super::tail::log_routes());
+    routes.extend(structlog::routes());
+    routes.extend(super::logread::routes());
+    routes
 }
\ No newline at end of file
@@ -0,0 +1,153 @@
+use super::auth::TokenAuth;
+use super::objects;
+use super::state::WebCache;
+use super::types::ResultBase;
+
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{get, State};
+
+/// bucket 已使用的容量
+#[derive(Debug, Serialize, Clone, Copy, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct BucketUsage {
+    pub bytes: u64,
+    pub objects: u64,
+}
+
+/// bucket 容量和配额
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct QuotaStatus {
+    pub usage: BucketUsage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_objects: Option<u64>,
+}
+
+async fn scan_usage(cache: &WebCache, bucket: &str) -> std::io::Result<BucketUsage> {
+    let bucket_dir = cache.open_data_dir(bucket)?;
+    let objects = match objects::walk_objects(&bucket_dir, &bucket_dir).await {
+        Ok(objects) => objects,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(err) => return Err(err),
+    };
+    Ok(BucketUsage {
+        bytes: objects.iter().map(|(_, meta)| meta.len()).sum(),
+        objects: objects.len() as u64,
+    })
+}
+
+/// 获取 bucket 已使用的容量, 首次访问时扫描 bucket 目录
+pub async fn usage(cache: &WebCache, bucket: &str) -> std::io::Result<BucketUsage> {
+    if let Some(usage) = cache.usage.lock().await.get(bucket) {
+        return Ok(*usage);
+    }
+    let scanned = scan_usage(cache, bucket).await?;
+    Ok(*cache.usage.lock().await.entry(bucket.to_string()).or_insert(scanned))
+}
+
+/// 增量更新 bucket 已使用的容量, 未统计过的 bucket 不做处理
+pub async fn record(cache: &WebCache, bucket: &str, bytes: i64, objects: i64) {
+    if let Some(usage) = cache.usage.lock().await.get_mut(bucket) {
+        usage.bytes = usage.bytes.saturating_add_signed(bytes);
+        usage.objects = usage.objects.saturating_add_signed(objects);
+    }
+}
+
+/// 删除 bucket 后清除统计
+pub async fn forget(cache: &WebCache, bucket: &str) {
+    cache.usage.lock().await.remove(bucket);
+}
+
+/// 本次写入最多允许的字节数, 未配置配额时返回 None
+/// - replaced: 被覆盖对象释放的字节数, 新建对象为 None
+pub async fn write_limit(cache: &WebCache, bucket: &str, replaced: Option<u64>) -> super::WebResult<Option<u64>> {
+    let Some(quota) = cache.quota_policy(bucket) else {
+        return Ok(None);
+    };
+    let usage = usage(cache, bucket).await?;
+    if let Some(max) = quota.max_objects {
+        if replaced.is_none() && usage.objects >= max {
+            return Err(super::WebError::Quota(format!(
+                "bucket {bucket} holds {} of {max} objects",
+                usage.objects
+            )));
+        }
+    }
+    Ok(quota
+        .max_bytes
+        .map(|max| (max + replaced.unwrap_or_default()).saturating_sub(usage.bytes)))
+}
+
+/// 写入的数据超出 write_limit 时返回的错误
+pub fn exceeded(bucket: &str, limit: u64) -> super::WebError {
+    super::WebError::Quota(format!("bucket {bucket} has {limit} bytes left"))
+}
+
+/// 写入完成后提交用量变化
+/// 并发写入导致超出配额时返回错误, 此时不记录用量, 由调用方回滚写入的数据
+pub async fn commit(cache: &WebCache, bucket: &str, bytes: i64, objects: i64) -> super::WebResult<()> {
+    let quota = cache.quota_policy(bucket);
+    let mut usages = cache.usage.lock().await;
+    let Some(usage) = usages.get_mut(bucket) else {
+        return Ok(());
+    };
+    let new_bytes = usage.bytes.saturating_add_signed(bytes);
+    let new_objects = usage.objects.saturating_add_signed(objects);
+    if let Some(quota) = quota {
+        if bytes > 0 && quota.max_bytes.is_some_and(|max| new_bytes > max) {
+            return Err(super::WebError::Quota(format!("bucket {bucket} exceeds {} bytes", new_bytes)));
+        }
+        if objects > 0 && quota.max_objects.is_some_and(|max| new_objects > max) {
+            return Err(super::WebError::Quota(format!("bucket {bucket} exceeds {} objects", new_objects)));
+        }
+    }
+    usage.bytes = new_bytes;
+    usage.objects = new_objects;
+    Ok(())
+}
+
+async fn quota_status(cache: &WebCache, bucket: &str) -> super::WebResult<QuotaStatus> {
+    let usage = usage(cache, bucket).await?;
+    let quota = cache.quota_policy(bucket);
+    Ok(QuotaStatus {
+        usage,
+        max_bytes: quota.and_then(|q| q.max_bytes),
+        max_objects: quota.and_then(|q| q.max_objects),
+    })
+}
+
+/// 1. usage
+/// - url
+///     - "/storage/usage"
+///     - "/storage/usage/{bucket}"
+/// - method: GET
+/// - params:
+///     - bucket: string, required
+/// - response json
+///     - {"code":0,"msg":"ok","ok":true,"data":{"usage":{"bytes":1024,"objects":3},"max_bytes":1048576,"max_objects":100}}
+#[get("/usage?<bucket>")]
+async fn bucket_usage1(
+    bucket: &str,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<QuotaStatus>>> {
+    auth.check_pass_root()?;
+    Ok(Json(ResultBase::ok(quota_status(cache, bucket).await?)))
+}
+
+#[get("/usage/<bucket>")]
+async fn bucket_usage2(
+    bucket: &str,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<QuotaStatus>>> {
+    auth.check_pass_root()?;
+    Ok(Json(ResultBase::ok(quota_status(cache, bucket).await?)))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![bucket_usage1, bucket_usage2]
+}
@@ -0,0 +1,461 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::state::WebCache;
+use super::topic;
+
+use rocket::tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use rocket::tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use rocket::tokio::net::{TcpListener, TcpStream};
+use rocket::tokio::sync::{broadcast, mpsc};
+use rocket::tokio::task::JoinHandle;
+
+/// 单个参数的最大长度, 与 "/msg/{queue}/put" 的限制相同
+const MAX_BULK_LEN: usize = 10 * 1024 * 1024;
+/// 单个命令的最大参数数量
+const MAX_ARGS: usize = 1024 * 1024;
+/// inline 命令一行的最大长度
+const MAX_INLINE_LEN: u64 = 64 * 1024;
+/// BRPOP / BLPOP 检查队列的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// RESP 回复
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Int(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Self::Simple("OK")
+    }
+    fn bulk(data: impl Into<Vec<u8>>) -> Self {
+        Self::Bulk(Some(data.into()))
+    }
+    fn err(msg: impl ToString) -> Self {
+        Self::Error(msg.to_string())
+    }
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(s) => out.extend_from_slice(format!("+{s}\r\n").as_bytes()),
+            Reply::Error(e) => out.extend_from_slice(format!("-{}\r\n", e.replace(['\r', '\n'], " ")).as_bytes()),
+            Reply::Int(n) => out.extend_from_slice(format!(":{n}\r\n").as_bytes()),
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(data)) => {
+                out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+                out.extend_from_slice(data);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(None) => out.extend_from_slice(b"*-1\r\n"),
+            Reply::Array(Some(items)) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+}
+
+fn protocol_error(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Protocol error: {msg}"))
+}
+
+async fn read_line(reader: &mut BufReader<OwnedReadHalf>, line: &mut Vec<u8>) -> std::io::Result<bool> {
+    line.clear();
+    if (&mut *reader).take(MAX_INLINE_LEN).read_until(b'\n', line).await? == 0 {
+        return Ok(false);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(protocol_error("too big inline request"));
+    }
+    while line.last().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+        line.pop();
+    }
+    Ok(true)
+}
+
+fn parse_len(line: &[u8], prefix: u8, max: usize) -> std::io::Result<usize> {
+    let Some(rest) = line.strip_prefix(&[prefix]) else {
+        return Err(protocol_error(&format!("expected '{}'", prefix as char)));
+    };
+    let len = std::str::from_utf8(rest)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .ok_or_else(|| protocol_error("invalid length"))?;
+    if len < 0 || len as usize > max {
+        return Err(protocol_error("invalid length"));
+    }
+    Ok(len as usize)
+}
+
+/// 读取一个命令, 支持 RESP 数组和空格分隔的 inline 命令, 连接关闭时返回 None
+async fn read_command(reader: &mut BufReader<OwnedReadHalf>) -> std::io::Result<Option<Vec<Vec<u8>>>> {
+    let mut line = Vec::new();
+    loop {
+        if !read_line(reader, &mut line).await? {
+            return Ok(None);
+        }
+        if line.is_empty() {
+            continue;
+        }
+        if line[0] != b'*' {
+            let args = line
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_vec())
+                .collect::<Vec<_>>();
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Some(args));
+        }
+        let n = parse_len(&line, b'*', MAX_ARGS)?;
+        let mut args = Vec::with_capacity(n.min(64));
+        for _ in 0..n {
+            if !read_line(reader, &mut line).await? {
+                return Ok(None);
+            }
+            let len = parse_len(&line, b'$', MAX_BULK_LEN)?;
+            let mut arg = vec![0u8; len + 2];
+            reader.read_exact(&mut arg).await?;
+            arg.truncate(len);
+            args.push(arg);
+        }
+        if !args.is_empty() {
+            return Ok(Some(args));
+        }
+    }
+}
+
+fn arg_str(arg: &[u8]) -> String {
+    String::from_utf8_lossy(arg).into_owned()
+}
+
+fn parse_arg<T: std::str::FromStr>(arg: &[u8]) -> Result<T, Reply> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| Reply::err("ERR value is not an integer or out of range"))
+}
+
+fn wrong_args(cmd: &str) -> Reply {
+    Reply::err(format!("ERR wrong number of arguments for '{}' command", cmd.to_lowercase()))
+}
+
+/// 一个客户端连接
+struct Connection {
+    cache: WebCache,
+    writer: OwnedWriteHalf,
+    authed: bool,
+    /// 客户端已经断开, 阻塞中的命令提前结束
+    closed: Arc<AtomicBool>,
+    /// 订阅的主题, 每个主题一个转发任务
+    subscriptions: BTreeMap<String, JoinHandle<()>>,
+    pubsub_tx: mpsc::UnboundedSender<(String, Vec<u8>)>,
+}
+
+impl Connection {
+    async fn write(&mut self, reply: Reply) -> std::io::Result<()> {
+        let mut out = Vec::new();
+        reply.encode(&mut out);
+        self.writer.write_all(&out).await
+    }
+
+    fn subscribe_reply(kind: &'static str, topic: Option<&str>, count: usize) -> Reply {
+        Reply::Array(Some(vec![
+            Reply::bulk(kind),
+            Reply::Bulk(topic.map(|t| t.as_bytes().to_vec())),
+            Reply::Int(count as i64),
+        ]))
+    }
+
+    async fn subscribe(&mut self, topics: &[Vec<u8>]) -> std::io::Result<()> {
+        for topic in topics.iter().map(|t| arg_str(t)) {
+            if !self.subscriptions.contains_key(&topic) {
+                let mut receiver = topic::subscribe(&self.cache, &topic).await;
+                let tx = self.pubsub_tx.clone();
+                let name = topic.clone();
+                let task = rocket::tokio::spawn(async move {
+                    loop {
+                        match receiver.recv().await {
+                            Ok(msg) => {
+                                if tx.send((name.clone(), msg)).is_err() {
+                                    break;
+                                }
+                            }
+                            Err(broadcast::error::RecvError::Lagged(n)) => log::warn!("resp subscriber of {name} lagged {n} messages"),
+                            Err(broadcast::error::RecvError::Closed) => break,
+                        }
+                    }
+                });
+                self.subscriptions.insert(topic.clone(), task);
+            }
+            let count = self.subscriptions.len();
+            self.write(Self::subscribe_reply("subscribe", Some(&topic), count)).await?;
+        }
+        Ok(())
+    }
+
+    async fn unsubscribe(&mut self, topics: &[Vec<u8>]) -> std::io::Result<()> {
+        let topics = if topics.is_empty() {
+            self.subscriptions.keys().cloned().collect::<Vec<_>>()
+        } else {
+            topics.iter().map(|t| arg_str(t)).collect()
+        };
+        if topics.is_empty() {
+            return self.write(Self::subscribe_reply("unsubscribe", None, 0)).await;
+        }
+        for topic in topics {
+            if let Some(task) = self.subscriptions.remove(&topic) {
+                task.abort();
+            }
+            let count = self.subscriptions.len();
+            self.write(Self::subscribe_reply("unsubscribe", Some(&topic), count)).await?;
+        }
+        Ok(())
+    }
+
+    /// BRPOP / BLPOP, timeout 为 0 或超出时间范围时一直等待
+    async fn blocking_pop(&mut self, keys: &[Vec<u8>], timeout: Duration, newest: bool) -> std::io::Result<()> {
+        let keys = keys.iter().map(|k| arg_str(k)).collect::<Vec<_>>();
+        let until = if timeout.is_zero() { None } else { Instant::now().checked_add(timeout) };
+        loop {
+            for key in &keys {
+                let msg = if newest {
+                    self.cache.queue_pop_newest_msg(key).await
+                } else {
+                    self.cache.queue_pop_msg(key).await
+                };
+                if let Some(msg) = msg {
+                    let reply = Reply::Array(Some(vec![Reply::bulk(key.as_str()), Reply::bulk(msg.clone())]));
+                    if let Err(err) = self.write(reply).await {
+                        // 客户端已经断开, 消息放回队列
+                        if newest {
+                            self.cache.queue_push_msg(key, msg).await;
+                        } else {
+                            self.cache.queue_requeue_msg(key, msg).await;
+                        }
+                        return Err(err);
+                    }
+                    return Ok(());
+                }
+            }
+            if self.closed.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            if until.is_some_and(|until| Instant::now() >= until) {
+                return self.write(Reply::Array(None)).await;
+            }
+            rocket::tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// 执行一个命令, 返回 false 时关闭连接
+    async fn execute(&mut self, args: Vec<Vec<u8>>) -> std::io::Result<bool> {
+        let cmd = arg_str(&args[0]).to_uppercase();
+        let args = &args[1..];
+        if self.cache.token.is_some() && !self.authed && !matches!(cmd.as_str(), "AUTH" | "QUIT" | "PING") {
+            self.write(Reply::err("NOAUTH Authentication required.")).await?;
+            return Ok(true);
+        }
+        if !self.subscriptions.is_empty() && !matches!(cmd.as_str(), "SUBSCRIBE" | "UNSUBSCRIBE" | "PING" | "QUIT") {
+            let msg = format!("ERR Can't execute '{}': only SUBSCRIBE / UNSUBSCRIBE / PING / QUIT are allowed in this context", cmd.to_lowercase());
+            self.write(Reply::err(msg)).await?;
+            return Ok(true);
+        }
+        let reply = match (cmd.as_str(), args.len()) {
+            ("PING", 0 | 1) if !self.subscriptions.is_empty() => {
+                let msg = args.first().cloned().unwrap_or_default();
+                Reply::Array(Some(vec![Reply::bulk("pong"), Reply::bulk(msg)]))
+            }
+            ("PING", 0) => Reply::Simple("PONG"),
+            ("PING" | "ECHO", 1) => Reply::bulk(args[0].clone()),
+            ("QUIT", _) => {
+                self.write(Reply::ok()).await?;
+                return Ok(false);
+            }
+            ("AUTH", 1 | 2) => {
+                let password = arg_str(&args[args.len() - 1]);
+                match &self.cache.token {
+                    None => Reply::err("ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?"),
+                    Some(token) if token.as_str() != password => {
+                        Reply::err("WRONGPASS invalid username-password pair or user is disabled.")
+                    }
+                    Some(_) => {
+                        self.authed = true;
+                        Reply::ok()
+                    }
+                }
+            }
+            ("SELECT", 1) | ("CLIENT", _) => Reply::ok(),
+            ("COMMAND", _) => Reply::Array(Some(vec![])),
+            ("LPUSH" | "RPUSH", n) if n >= 2 => {
+                let key = arg_str(&args[0]);
+                for msg in &args[1..] {
+                    if cmd == "LPUSH" {
+                        self.cache.queue_push_msg(&key, msg.clone()).await;
+                    } else {
+                        self.cache.queue_requeue_msg(&key, msg.clone()).await;
+                    }
+                }
+                Reply::Int(self.cache.queue_len(&key).await as i64)
+            }
+            ("RPOP" | "LPOP", 1 | 2) => {
+                let key = arg_str(&args[0]);
+                let count = match args.get(1).map(|c| parse_arg::<usize>(c)).transpose() {
+                    Ok(count) => count,
+                    Err(reply) => return self.write(reply).await.map(|_| true),
+                };
+                let mut msgs = vec![];
+                for _ in 0..count.unwrap_or(1) {
+                    let msg = if cmd == "LPOP" {
+                        self.cache.queue_pop_newest_msg(&key).await
+                    } else {
+                        self.cache.queue_pop_msg(&key).await
+                    };
+                    match msg {
+                        Some(msg) => msgs.push(Reply::bulk(msg)),
+                        None => break,
+                    }
+                }
+                match count {
+                    None => msgs.pop().unwrap_or(Reply::Bulk(None)),
+                    Some(_) if msgs.is_empty() => Reply::Array(None),
+                    Some(_) => Reply::Array(Some(msgs)),
+                }
+            }
+            ("BRPOP" | "BLPOP", n) if n >= 2 => {
+                // 负数, inf, NaN 和超出 Duration 范围的值都是错误
+                let timeout = match parse_arg::<f64>(&args[n - 1]).map(Duration::try_from_secs_f64) {
+                    Ok(Ok(timeout)) => timeout,
+                    _ => return self.write(Reply::err("ERR timeout is not a float or out of range")).await.map(|_| true),
+                };
+                self.blocking_pop(&args[..n - 1], timeout, cmd == "BLPOP").await?;
+                return Ok(true);
+            }
+            ("LLEN", 1) => Reply::Int(self.cache.queue_len(&arg_str(&args[0])).await as i64),
+            ("LINDEX", 2) => {
+                let key = arg_str(&args[0]);
+                let index = match parse_arg::<i64>(&args[1]) {
+                    Ok(index) => index,
+                    Err(reply) => return self.write(reply).await.map(|_| true),
+                };
+                let index = if index < 0 { self.cache.queue_len(&key).await as i64 + index } else { index };
+                if index < 0 {
+                    Reply::Bulk(None)
+                } else {
+                    Reply::Bulk(self.cache.queue_pick_msg(&key, index as usize).await)
+                }
+            }
+            ("PUBLISH", 2) => Reply::Int(topic::publish(&self.cache, &arg_str(&args[0]), args[1].clone()).await as i64),
+            ("SUBSCRIBE", n) if n >= 1 => {
+                self.subscribe(args).await?;
+                return Ok(true);
+            }
+            ("UNSUBSCRIBE", _) => {
+                self.unsubscribe(args).await?;
+                return Ok(true);
+            }
+            (
+                "PING" | "ECHO" | "AUTH" | "SELECT" | "LPUSH" | "RPUSH" | "RPOP" | "LPOP" | "BRPOP" | "BLPOP" | "LLEN"
+                | "LINDEX" | "PUBLISH" | "SUBSCRIBE",
+                _,
+            ) => wrong_args(&cmd),
+            _ => Reply::err(format!("ERR unknown command '{}'", cmd.to_lowercase())),
+        };
+        self.write(reply).await?;
+        Ok(true)
+    }
+}
+
+/// 处理一个连接, 读取命令在单独的任务中执行, 订阅模式下可以同时推送消息
+async fn handle(cache: WebCache, stream: TcpStream) -> std::io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let closed = Arc::new(AtomicBool::new(false));
+    let (cmd_tx, mut cmd_rx) = mpsc::channel::<std::io::Result<Vec<Vec<u8>>>>(64);
+    let reader_closed = closed.clone();
+    let reader_task = rocket::tokio::spawn(async move {
+        let mut reader = BufReader::new(reader);
+        loop {
+            let command = read_command(&mut reader).await;
+            let stop = !matches!(command, Ok(Some(_)));
+            let sent = match command {
+                Ok(Some(args)) => cmd_tx.send(Ok(args)).await.is_ok(),
+                Ok(None) => true,
+                Err(err) => cmd_tx.send(Err(err)).await.is_ok(),
+            };
+            if stop || !sent {
+                break;
+            }
+        }
+        reader_closed.store(true, Ordering::Relaxed);
+    });
+    let (pubsub_tx, mut pubsub_rx) = mpsc::unbounded_channel();
+    let mut conn = Connection {
+        cache,
+        writer,
+        authed: false,
+        closed,
+        subscriptions: BTreeMap::new(),
+        pubsub_tx,
+    };
+    let result = async {
+        loop {
+            rocket::tokio::select! {
+                command = cmd_rx.recv() => match command {
+                    Some(Ok(args)) => {
+                        if !conn.execute(args).await? {
+                            return Ok(());
+                        }
+                    }
+                    Some(Err(err)) => {
+                        conn.write(Reply::err(format!("ERR {err}"))).await?;
+                        return Err(err);
+                    }
+                    None => return Ok(()),
+                },
+                Some((topic, msg)) = pubsub_rx.recv() => {
+                    let reply = Reply::Array(Some(vec![Reply::bulk("message"), Reply::bulk(topic), Reply::bulk(msg)]));
+                    conn.write(reply).await?;
+                }
+            }
+        }
+    }
+    .await;
+    for (_, task) in std::mem::take(&mut conn.subscriptions) {
+        task.abort();
+    }
+    reader_task.abort();
+    result
+}
+
+/// 启动 RESP 服务, 绑定失败时只记录错误, 不影响 http 服务
+pub async fn serve(cache: WebCache, bind: String) {
+    let listener = match TcpListener::bind(&bind).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("resp listen on {bind} error: {err}");
+            return;
+        }
+    };
+    log::info!("resp on: {bind}");
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                let cache = cache.clone();
+                rocket::tokio::spawn(async move {
+                    if let Err(err) = handle(cache, stream).await {
+                        log::debug!("resp client {addr} closed: {err}");
+                    }
+                });
+            }
+            Err(err) => log::warn!("resp accept error: {err}"),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::time::{Duration, Instant};
+
+use super::auth::TokenAuth;
+use super::state::WebCache;
+use super::types::ResultBase;
+
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::tokio::io::AsyncReadExt;
+use rocket::{data::ToByteUnit, get, post, Data, State};
+
+/// 临时回复队列名称的前缀, 完整名称为 "_reply.{correlation_id}"
+pub(super) const REPLY_PREFIX: &str = "_reply.";
+/// 没有指定 timeout 时等待回复的秒数
+const DEFAULT_TIMEOUT: u64 = 30;
+/// 等待回复时检查回复队列的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// call 放入队列的消息, worker 处理后向 reply_to 回复
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct CallMessage<'a> {
+    correlation_id: &'a str,
+    /// 回复队列, 也可以 POST 到 "/msg/_reply/{correlation_id}"
+    reply_to: &'a str,
+    data: String,
+}
+
+fn reply_queue(correlation_id: &str) -> String {
+    format!("{REPLY_PREFIX}{correlation_id}")
+}
+
+/// 等待中的调用, 结束(包括客户端断开)时删除回复队列, 请求还没有被取走时从队列中删除
+struct PendingCall {
+    cache: WebCache,
+    queue: String,
+    reply_queue: String,
+    request: Vec<u8>,
+}
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        let cache = self.cache.clone();
+        let queue = std::mem::take(&mut self.queue);
+        let reply_queue = std::mem::take(&mut self.reply_queue);
+        let request = std::mem::take(&mut self.request);
+        rocket::tokio::spawn(async move {
+            cache.queue.lock().await.remove(&reply_queue);
+            let queue = { cache.queue.lock().await.get(&queue).cloned() };
+            if let Some(queue) = queue {
+                queue.lock().await.retain(|entry| entry.msg != request);
+            }
+        });
+    }
+}
+
+/// 向队列放入带 correlation_id 和 reply_to 的请求, 等待 worker 回复
+async fn call(cache: &WebCache, queue: &str, data: Data<'_>, timeout: Option<u64>) -> super::WebResult<Option<String>> {
+    let mut bytes = Vec::new();
+    // 限制大小为10M
+    if data.open(10.mebibytes()).read_to_end(&mut bytes).await.is_err() {
+        return Err(super::WebError::new("data too large"));
+    }
+    // 请求放在 json 的字符串中, 只支持 utf-8 文本
+    let data = String::from_utf8(bytes).map_err(|_| super::WebError::new("request body must be utf-8 text"))?;
+    let until = Instant::now()
+        .checked_add(Duration::from_secs(timeout.unwrap_or(DEFAULT_TIMEOUT)))
+        .ok_or_else(|| super::WebError::new("timeout out of range"))?;
+    let correlation_id = format!("{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>());
+    let reply_queue = reply_queue(&correlation_id);
+    let request = serde_json::to_vec(&CallMessage {
+        correlation_id: &correlation_id,
+        reply_to: &reply_queue,
+        data,
+    })?;
+    // 先创建回复队列, 回复只能发送到已经存在的回复队列
+    let replies = cache.queue_listen(&reply_queue).await;
+    let _pending = PendingCall {
+        cache: cache.clone(),
+        queue: queue.to_string(),
+        reply_queue,
+        request: request.clone(),
+    };
+    cache.queue_push_msg(queue, request).await;
+    loop {
+        if let Some(reply) = replies.lock().await.pop_back().map(|e| e.msg) {
+            return Ok(Some(String::from_utf8_lossy(&reply).to_string()));
+        }
+        if Instant::now() >= until {
+            return Ok(None);
+        }
+        rocket::tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// 回复一个等待中的调用, 调用已经超时或不存在时返回错误
+async fn reply(cache: &WebCache, correlation_id: &str, data: Vec<u8>) -> super::WebResult<bool> {
+    let queue = { cache.queue.lock().await.get(&reply_queue(correlation_id)).cloned() };
+    let Some(queue) = queue else {
+        return Err(super::WebError::new(format!("unknown or expired correlation id: {correlation_id}")));
+    };
+    queue.lock().await.push_front(data.into());
+    Ok(true)
+}
+
+#[post("/queue/call?<queue>&<timeout>", data = "<data>")]
+async fn call_queue1(
+    queue: &str,
+    timeout: Option<u64>,
+    data: Data<'_>,
+    state: &State<WebCache>,
+    auth: TokenAuth,
+) -> super::WebResult<Json<ResultBase<Option<String>>>> {
+    auth.check_pass_root()?;
+    Ok(Json(ResultBase::ok(call(state, queue, data, timeout).await?)))
+}
+
+#[post("/<queue>/call?<timeout>", data = "<data>")]
+async fn call_queue2(
+    queue: &str,
+    timeout: Option<u64>,
+    data: Data<'_>,
+    state: &State<WebCache>,
+    auth: TokenAuth,
+) -> super::WebResult<Json<ResultBase<Option<String>>>> {
+    auth.check_pass_root()?;
+    Ok(Json(ResultBase::ok(call(state, queue, data, timeout).await?)))
+}
+
+#[post("/_reply/<correlation_id>", data = "<data>", rank = 2)]
+async fn reply_call1(
+    correlation_id: &str,
+    data: Data<'_>,
+    state: &State<WebCache>,
+    auth: TokenAuth,
+) -> super::WebResult<Json<ResultBase<bool>>> {
+    auth.check_pass_root()?;
+    let mut bytes = Vec::new();
+    // 限制大小为10M
+    if data.open(10.mebibytes()).read_to_end(&mut bytes).await.is_err() {
+        return Err(super::WebError::new("data too large"));
+    }
+    Ok(Json(ResultBase::ok(reply(state, correlation_id, bytes).await?)))
+}
+
+#[get("/_reply/<correlation_id>?<content>", rank = 2)]
+async fn reply_call2(
+    correlation_id: &str,
+    content: String,
+    state: &State<WebCache>,
+    auth: TokenAuth,
+) -> super::WebResult<Json<ResultBase<bool>>> {
+    auth.check_pass_root()?;
+    Ok(Json(ResultBase::ok(reply(state, correlation_id, content.into_bytes()).await?)))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![call_queue1, call_queue2, reply_call1, reply_call2]
+}
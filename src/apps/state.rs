@@ -5,15 +5,65 @@ use std::sync::{Arc,atomic::{AtomicUsize,Ordering}};
 use rocket::tokio::fs::{self,File};
 type Locker<T> = Arc<Mutex<T>>;
 
-type SingleFile = Locker<(AtomicUsize,Option<File>)>;
+use super::handles::{self, HandleKind};
+/// 打开的文件句柄和正在使用它的请求数量, 数量不为 0 时句柄不会被空闲清理关闭
+type SingleFile = Arc<(AtomicUsize,Mutex<Option<File>>)>;
+type LogFile = Arc<(AtomicUsize,Mutex<File>)>;
+
+/// 队列中的一条消息, 消息组的消息带上组名, 取出时按组名找到对应的组
+#[derive(Debug, Clone, Default)]
+pub struct QueueEntry {
+    pub msg: Vec<u8>,
+    pub group: Option<String>,
+}
+
+impl From<Vec<u8>> for QueueEntry {
+    fn from(msg: Vec<u8>) -> Self {
+        Self { msg, group: None }
+    }
+}
 
 #[derive(Debug, Clone, Default)]
 pub struct WebCache {
     pub token: Option<Arc<String>>,
-    pub queue: Locker<BTreeMap<String, Locker<VecDeque<Vec<u8>>>>>,
+    pub queue: Locker<BTreeMap<String, Locker<VecDeque<QueueEntry>>>>,
     pub data_workspace: Arc<std::path::PathBuf>,
-    pub cache_logs: Locker<BTreeMap<String,Locker<File>>>,
+    pub cache_logs: Locker<BTreeMap<String,LogFile>>,
     pub cache_append_fs: Locker<BTreeMap<String,SingleFile>>,
+    pub versioning: Arc<Vec<crate::config::ConfigVersioning>>,
+    pub lifecycle: Arc<Vec<crate::config::ConfigLifecycle>>,
+    pub quota: Arc<Vec<crate::config::ConfigQuota>>,
+    pub notify: Arc<Vec<crate::config::ConfigNotify>>,
+    pub log_rotate: Arc<Vec<crate::config::ConfigLogRotate>>,
+    pub extract_limits: Arc<crate::config::ConfigExtract>,
+    /// 上传的对象是否写入去重的 blob 存储
+    pub dedup: bool,
+    /// blob 的引用检查与链接/删除互斥, 避免删除刚被引用的 blob
+    pub blob_lock: Locker<()>,
+    /// bucket 已使用的容量, 首次访问时统计, 之后随写入和删除增量更新
+    pub usage: Locker<BTreeMap<String,super::quota::BucketUsage>>,
+    /// 打开的追加写入和日志句柄最后一次写入的时间
+    pub handle_activity: Locker<BTreeMap<(HandleKind,String),std::time::SystemTime>>,
+    pub handle_limits: Arc<crate::config::ConfigHandles>,
+    /// 已投递但还没有确认的消息
+    pub deliveries: Locker<BTreeMap<u64,super::ack::Delivery>>,
+    pub delivery_seq: Arc<std::sync::atomic::AtomicU64>,
+    /// 投递后超过该时间没有确认时放回队列
+    pub ack_timeout: std::time::Duration,
+    /// 发布订阅的主题, 消息只发送给当前的订阅者, 不保存
+    pub topics: Locker<BTreeMap<String,rocket::tokio::sync::broadcast::Sender<Vec<u8>>>>,
+    /// 推送订阅, 每个推送订阅有一个后台投递任务
+    pub webhooks: Locker<BTreeMap<u64,Arc<super::webhook::Webhook>>>,
+    /// 每个队列在去重窗口内见过的 Idempotency-Key
+    pub idempotency_keys: Locker<BTreeMap<String,super::idempotency::SeenKeys>>,
+    pub idempotency: Arc<crate::config::ConfigIdempotency>,
+    /// 每个队列的消息组, 组内同时只有一条消息在队列中或正在投递
+    pub groups: Locker<BTreeMap<String,BTreeMap<String,super::groups::Group>>>,
+}
+
+/// bucket 名称是否匹配配置中的 glob 通配符
+fn bucket_matches(pattern: &str, bucket: &str) -> bool {
+    glob::Pattern::new(pattern).map(|p| p.matches(bucket)).unwrap_or(false)
 }
 
 impl WebCache {
@@ -25,7 +75,7 @@ impl WebCache {
     //     }
     // }
     pub fn new(cfg: &crate::config::Config) -> std::io::Result<Self> {
-        let slf = if let Some(auth) = &cfg.auth {
+        let mut slf = if let Some(auth) = &cfg.auth {
             let token = &auth.token;
             let storage_dir = cfg.data_workspace()?;
             WebCache {
@@ -40,14 +90,54 @@ impl WebCache {
                 ..Default::default()
             }
         };
+        slf.versioning = Arc::new(cfg.storage.versioning.clone());
+        slf.lifecycle = Arc::new(cfg.storage.lifecycle.clone());
+        slf.quota = Arc::new(cfg.storage.quota.clone());
+        slf.notify = Arc::new(cfg.storage.notify.clone());
+        slf.log_rotate = Arc::new(cfg.onlinelog.rotate.clone());
+        slf.extract_limits = Arc::new(cfg.storage.extract.clone());
+        slf.handle_limits = Arc::new(cfg.handles.clone());
+        slf.idempotency = Arc::new(cfg.idempotency.clone());
+        slf.ack_timeout = std::time::Duration::from_secs(cfg.ack.timeout);
+        slf.dedup = cfg.storage.backend == crate::config::StorageBackend::Dedup;
         Ok(slf)
     }
     /// 存储空间内打开一个子目录
-    pub fn open_data_dir(&self,bucket: &str) -> std::path::PathBuf {
+    pub fn open_data_dir(&self,bucket: &str) -> std::io::Result<std::path::PathBuf> {
         // log::debug!("data workspace: {}",self.data_workspace.display());
+        super::objects::check_bucket(bucket)?;
         let mut workspace = std::path::PathBuf::from(self.data_workspace.as_ref());
         workspace.push(bucket);
-        workspace
+        Ok(workspace)
+    }
+    /// 存储空间内对象的路径, 对象名称中的 `/` 映射为子目录
+    pub fn open_object_path(&self,bucket: &str,name: &str) -> std::io::Result<std::path::PathBuf> {
+        super::objects::check_object_key(name)?;
+        let mut path = self.open_data_dir(bucket)?;
+        path.push(name);
+        Ok(path)
+    }
+    /// 查找 bucket 对应的版本管理配置, 未配置表示未开启版本管理
+    pub fn versioning_policy(&self,bucket: &str) -> Option<&crate::config::ConfigVersioning> {
+        self.versioning.iter().find(|v| bucket_matches(&v.bucket, bucket))
+    }
+    /// 查找 bucket 匹配的所有生命周期规则
+    pub fn lifecycle_rules(&self,bucket: &str) -> Vec<&crate::config::ConfigLifecycle> {
+        self.lifecycle.iter().filter(|v| bucket_matches(&v.bucket, bucket)).collect()
+    }
+    /// 查找 bucket 对应的容量配额
+    pub fn quota_policy(&self,bucket: &str) -> Option<&crate::config::ConfigQuota> {
+        self.quota.iter().find(|v| bucket_matches(&v.bucket, bucket))
+    }
+
+    /// 日志 channel 的切分规则, 多条规则匹配时使用第一条
+    pub fn log_rotate_policy(&self,channel: &str) -> Option<&crate::config::ConfigLogRotate> {
+        self.log_rotate.iter().find(|v| bucket_matches(&v.channel, channel))
+    }
+
+    /// bucket 匹配的所有通知规则
+    pub fn notify_rules(&self,bucket: &str) -> Vec<&crate::config::ConfigNotify> {
+        self.notify.iter().filter(|v| bucket_matches(&v.bucket, bucket)).collect()
     }
     /// 检查指定的消息队列中是否存在消息
     #[allow(unused)]
@@ -70,8 +160,14 @@ impl WebCache {
         }
     }
 
-    /// 从指定的任务队列中推出一条消息
+    /// 从指定的任务队列中推出一条消息, 不需要确认, 消息所在的组立即放入下一条消息
     pub async fn queue_pop_msg(&self, queue: &str) -> Option<Vec<u8>> {
+        let entry = self.queue_pop_raw(queue).await?;
+        super::groups::popped(self, queue, entry.group.as_deref(), true).await;
+        Some(entry.msg)
+    }
+    /// 从指定的任务队列中推出一条消息, 不处理消息组, 需要确认的投递使用 ack::pop_deliver
+    pub async fn queue_pop_raw(&self, queue: &str) -> Option<QueueEntry> {
         let queue = { self.queue.lock().await.get(queue).cloned() };
         if let Some(queue) = queue {
             queue.lock().await.pop_back()
@@ -79,7 +175,7 @@ impl WebCache {
             None
         }
     }
-    pub async fn queue_listen(&self,queue_name:&str) -> Locker<VecDeque<Vec<u8>>> {
+    pub async fn queue_listen(&self,queue_name:&str) -> Locker<VecDeque<QueueEntry>> {
         let queue = { self.queue.lock().await.get(queue_name).cloned() };
         if let Some(queue) = queue {
             queue
@@ -93,7 +189,7 @@ impl WebCache {
     pub async fn queue_pick_msg(&self, queue: &str, index: usize) -> Option<Vec<u8>> {
         let queue = { self.queue.lock().await.get(queue).cloned() };
         if let Some(queue) = queue {
-            queue.lock().await.get(index).cloned()
+            queue.lock().await.get(index).map(|e| e.msg.clone())
         } else {
             None
         }
@@ -101,7 +197,7 @@ impl WebCache {
     pub async fn queue_last(&self,queue: &str) -> Option<Vec<u8>>{
         let queue = { self.queue.lock().await.get(queue).cloned() };
         if let Some(queue) = queue {
-            queue.lock().await.front().cloned()
+            queue.lock().await.front().map(|e| e.msg.clone())
         } else {
             None
         }
@@ -109,68 +205,126 @@ impl WebCache {
     pub async fn queue_first(&self,queue: &str) -> Option<Vec<u8>>{
         let queue = { self.queue.lock().await.get(queue).cloned() };
         if let Some(queue) = queue {
-            queue.lock().await.back().cloned()
+            queue.lock().await.back().map(|e| e.msg.clone())
         } else {
             None
         }
     }
     /// 向指定的任务队列推一条消息
     pub async fn queue_push_msg(&self, queue_name: &str, msg: Vec<u8>) {
+        self.queue_push_entry(queue_name, msg.into()).await;
+    }
+    /// 向指定的任务队列推一条消息, 消息组使用带组名的消息
+    pub async fn queue_push_entry(&self, queue_name: &str, entry: QueueEntry) {
         let queue = { self.queue.lock().await.get(queue_name).cloned() };
         if let Some(queue) = queue {
-            queue.lock().await.push_front(msg);
+            queue.lock().await.push_front(entry);
         } else {
             self.queue
                     .lock()
                     .await
                     .entry(queue_name.to_string())
-                    .or_insert_with(|| Arc::new(Mutex::new(VecDeque::new()))).lock().await.push_front(msg);
+                    .or_insert_with(|| Arc::new(Mutex::new(VecDeque::new()))).lock().await.push_front(entry);
         }
     }
-    pub async fn open_online_log(&self,channel:&str,name:&str) -> std::io::Result<Locker<File>> {
+    /// 从队列中取出最新放入的一条消息
+    pub async fn queue_pop_newest_msg(&self, queue_name: &str) -> Option<Vec<u8>> {
+        let queue = { self.queue.lock().await.get(queue_name).cloned() };
+        let entry = if let Some(queue) = queue {
+            queue.lock().await.pop_front()
+        } else {
+            None
+        }?;
+        super::groups::popped(self, queue_name, entry.group.as_deref(), true).await;
+        Some(entry.msg)
+    }
+    /// 将未确认的消息放回队列, 下一次获取时最先取出
+    pub async fn queue_requeue_msg(&self, queue_name: &str, msg: Vec<u8>) {
+        self.queue_requeue_entry(queue_name, msg.into()).await;
+    }
+    /// 将未确认的消息放回队列, 消息组使用带组名的消息
+    pub async fn queue_requeue_entry(&self, queue_name: &str, entry: QueueEntry) {
+        self.queue_listen(queue_name).await.lock().await.push_back(entry);
+    }
+    /// 打开日志, 使用完成后需要将引用数量减一
+    pub async fn open_online_log(&self,channel:&str,name:&str) -> std::io::Result<LogFile> {
         let cache_key = format!("{channel}/{name}");
-        let log = self.cache_logs.lock().await.get(&cache_key).cloned();
-        if let Some(log) = log {
-            Ok(log)
+        // 在句柄表的锁内增加引用, 避免取出后被空闲清理关闭
+        let log = self.cache_logs.lock().await.get(&cache_key).inspect(|f| { f.0.fetch_add(1, Ordering::Relaxed); }).cloned();
+        let log = if let Some(log) = log {
+            log
         } else {
-            let data_dir = self.open_data_dir(channel);
+            let data_dir = self.open_data_dir(channel)?;
             if !fs::try_exists(&data_dir).await.unwrap_or(false) {
                 fs::create_dir_all(&data_dir).await?
             }
+            let marker = data_dir.join(super::onlinelog::CHANNEL_MARKER);
+            if !fs::try_exists(&marker).await.unwrap_or(false) {
+                File::create(&marker).await?;
+            }
             let path = data_dir.join(name);
             let file = fs::OpenOptions::new().append(true).create(true).open(&path).await?;
-            let arc_file = Arc::new(Mutex::new(file));
-            let arc_file = self.cache_logs.lock().await.entry(cache_key).or_insert(arc_file).clone();
-            Ok(arc_file)
-        }
+            let arc_file = Arc::new((AtomicUsize::new(0), Mutex::new(file)));
+            let log = self.cache_logs.lock().await.entry(cache_key.clone()).or_insert(arc_file).clone();
+            log.0.fetch_add(1, Ordering::Relaxed);
+            log
+        };
+        handles::register(self, HandleKind::Log, &cache_key).await;
+        Ok(log)
+    }
+    /// 日志是否被 "/onlinelog/upload" 打开
+    pub async fn is_log_open(&self,channel:&str,name:&str) -> bool {
+        self.cache_logs.lock().await.contains_key(&format!("{channel}/{name}"))
     }
     pub async fn close_online_log(&self,channel:&str,name:&str) {
         let cache_key = format!("{channel}/{name}");
         self.cache_logs.lock().await.remove(&cache_key);
+        handles::forget(self, HandleKind::Log, &cache_key).await;
     }
+    /// 打开追加写入的对象, 使用完成后需要将引用数量减一
     pub async fn open_append_file(&self,bucket:&str,name:&str) -> std::io::Result<SingleFile> {
+        let path = self.open_object_path(bucket, name)?;
         let cache_key = format!("{bucket}/{name}");
-        let single_file = self.cache_append_fs.lock().await.entry(cache_key).or_insert_with(|| {Default::default()}).clone();
-        {
-            let mut lockf = single_file.lock().await;
-            if lockf.1.is_none() {
-                let data_dir = self.open_data_dir(bucket);
-                if !fs::try_exists(&data_dir).await.unwrap_or(false) {
-                    fs::create_dir_all(&data_dir).await?
+        // 在句柄表的锁内增加引用, 避免取出后被空闲清理关闭
+        let single_file = {
+            let mut files = self.cache_append_fs.lock().await;
+            let single_file = files.entry(cache_key.clone()).or_insert_with(|| {Default::default()}).clone();
+            single_file.0.fetch_add(1, Ordering::Relaxed);
+            single_file
+        };
+        handles::register(self, HandleKind::Append, &cache_key).await;
+        let opened = async {
+            let mut lockf = single_file.1.lock().await;
+            if lockf.is_none() {
+                if let Some(data_dir) = path.parent() {
+                    if !fs::try_exists(data_dir).await.unwrap_or(false) {
+                        fs::create_dir_all(data_dir).await?
+                    }
                 }
-                let path = data_dir.join(name);
+                super::blobs::unshare(&path).await?;
                 // let file = File::create(&path).await?;
                 let file = fs::OpenOptions::new().create(true).append(true).open(&path).await?;
-                lockf.1= Some(file);
-            } 
-            lockf.0.fetch_add(1, Ordering::Relaxed);
+                *lockf = Some(file);
+            }
+            std::io::Result::Ok(())
+        }
+        .await;
+        if let Err(err) = opened {
+            single_file.0.fetch_sub(1, Ordering::Relaxed);
+            return Err(err);
         }
         Ok(single_file)
     }
     
+    /// 对象是否被追加写入打开
+    pub async fn is_appending(&self,bucket:&str,name:&str) -> bool {
+        self.cache_append_fs.lock().await.contains_key(&format!("{bucket}/{name}"))
+    }
+
     pub async fn close_append_file(&self,bucket:&str,name:&str) -> std::io::Result<()> {
         let cache_key = format!("{bucket}/{name}");
         self.cache_append_fs.lock().await.remove(&cache_key);
+        handles::forget(self, HandleKind::Append, &cache_key).await;
         Ok(())
     }
 }
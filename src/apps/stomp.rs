@@ -0,0 +1,723 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use super::ack;
+use super::auth::TokenAuth;
+use super::state::WebCache;
+use super::topic;
+
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::response::{self, Responder};
+use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use rocket::tokio::net::{TcpListener, TcpStream};
+use rocket::tokio::sync::{broadcast, mpsc};
+use rocket::tokio::task::JoinHandle;
+use rocket::{get, Request, State};
+use rocket_ws as ws;
+
+/// 帧正文的最大长度, 与 "/msg/{queue}/put" 的限制相同
+const MAX_BODY_LEN: usize = 10 * 1024 * 1024;
+/// 命令和 header 的最大长度
+const MAX_HEADER_LEN: usize = 64 * 1024;
+/// 没有可投递的消息时检查队列的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// client / client-individual 模式默认允许的未确认消息数量
+const DEFAULT_PREFETCH: usize = 1;
+/// 每一轮从一个订阅中最多投递的消息数量
+const DELIVER_BATCH: usize = 64;
+/// 连接关闭时等待 ERROR / RECEIPT 发送完成的时间
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+/// 支持的协议版本, 从高到低
+const VERSIONS: [&str; 3] = ["1.2", "1.1", "1.0"];
+/// 浏览器客户端在 Sec-WebSocket-Protocol 中请求的子协议
+const WS_PROTOCOLS: [&str; 4] = ["v12.stomp", "v11.stomp", "v10.stomp", "stomp"];
+
+/// STOMP 帧
+#[derive(Debug, Clone)]
+struct Frame {
+    command: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Frame {
+    fn new(command: &str) -> Self {
+        Self {
+            command: command.to_string(),
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    fn header(mut self, key: &str, value: impl ToString) -> Self {
+        self.headers.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// 重复的 header 以第一个为准
+    fn get(&self, key: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    fn required(&self, key: &str) -> Result<&str, Failure> {
+        self.get(key)
+            .ok_or_else(|| Failure::Error(format!("{} frame requires a {key} header", self.command)))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        // CONNECT / CONNECTED 帧的 header 不转义
+        let escaped = self.command != "CONNECTED";
+        let mut out = Vec::with_capacity(self.body.len() + 128);
+        out.extend_from_slice(self.command.as_bytes());
+        out.push(b'\n');
+        for (key, value) in &self.headers {
+            if escaped {
+                out.extend_from_slice(format!("{}:{}\n", escape(key), escape(value)).as_bytes());
+            } else {
+                out.extend_from_slice(format!("{key}:{value}\n").as_bytes());
+            }
+        }
+        if !self.body.is_empty() || self.command == "MESSAGE" {
+            out.extend_from_slice(format!("content-length:{}\n", self.body.len()).as_bytes());
+        }
+        out.push(b'\n');
+        out.extend_from_slice(&self.body);
+        out.push(0);
+        out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+        .replace(':', "\\c")
+}
+
+fn unescape(value: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push('\\'),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some('c') => out.push(':'),
+            other => return Err(format!("invalid escape sequence in header: \\{}", other.unwrap_or_default())),
+        }
+    }
+    Ok(out)
+}
+
+/// 从字节流中解析帧, tcp 和 websocket 共用
+#[derive(Default)]
+struct FrameParser {
+    buf: Vec<u8>,
+}
+
+impl FrameParser {
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// 取出一个完整的帧, 数据不完整时返回 None
+    fn next_frame(&mut self) -> Result<Option<Frame>, String> {
+        // 帧之间的空行是心跳
+        let skip = self.buf.iter().take_while(|b| **b == b'\n' || **b == b'\r').count();
+        self.buf.drain(..skip);
+        let mut lines = vec![];
+        let mut start = 0;
+        let body_start = loop {
+            let Some(end) = self.buf[start..].iter().position(|b| *b == b'\n') else {
+                if self.buf.len() > MAX_HEADER_LEN {
+                    return Err("frame header too large".to_string());
+                }
+                return Ok(None);
+            };
+            let line = &self.buf[start..start + end];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            start += end + 1;
+            if line.is_empty() {
+                break start;
+            }
+            let line = std::str::from_utf8(line).map_err(|_| "frame header is not utf-8".to_string())?;
+            lines.push(line.to_string());
+            if start > MAX_HEADER_LEN {
+                return Err("frame header too large".to_string());
+            }
+        };
+        let command = lines.remove(0);
+        let escaped = command != "CONNECT" && command != "STOMP";
+        let mut headers = Vec::with_capacity(lines.len());
+        for line in lines {
+            let Some((key, value)) = line.split_once(':') else {
+                return Err(format!("invalid header: {line}"));
+            };
+            if escaped {
+                headers.push((unescape(key)?, unescape(value)?));
+            } else {
+                headers.push((key.to_string(), value.to_string()));
+            }
+        }
+        let content_length = match headers.iter().find(|(k, _)| k == "content-length") {
+            Some((_, len)) => match len.trim().parse::<usize>() {
+                Ok(len) if len <= MAX_BODY_LEN => Some(len),
+                Ok(_) => return Err("frame body too large".to_string()),
+                Err(_) => return Err(format!("invalid content-length: {len}")),
+            },
+            None => None,
+        };
+        let body_end = match content_length {
+            Some(len) => {
+                if self.buf.len() <= body_start + len {
+                    return Ok(None);
+                }
+                if self.buf[body_start + len] != 0 {
+                    return Err("frame body must be followed by a NULL octet".to_string());
+                }
+                body_start + len
+            }
+            None => match self.buf[body_start..].iter().position(|b| *b == 0) {
+                Some(len) => body_start + len,
+                None if self.buf.len() - body_start > MAX_BODY_LEN => return Err("frame body too large".to_string()),
+                None => return Ok(None),
+            },
+        };
+        let body = self.buf[body_start..body_end].to_vec();
+        self.buf.drain(..=body_end);
+        Ok(Some(Frame { command, headers, body }))
+    }
+}
+
+/// 处理帧失败的原因
+enum Failure {
+    /// 发送 ERROR 帧后关闭连接
+    Error(String),
+    /// 客户端已经断开
+    Closed,
+}
+
+impl From<String> for Failure {
+    fn from(msg: String) -> Self {
+        Self::Error(msg)
+    }
+}
+
+impl From<&str> for Failure {
+    fn from(msg: &str) -> Self {
+        Self::Error(msg.to_string())
+    }
+}
+
+/// 目标地址, "/queue/{name}" 或 "/topic/{name}"
+enum Destination<'a> {
+    Queue(&'a str),
+    Topic(&'a str),
+}
+
+fn parse_destination(destination: &str) -> Result<Destination<'_>, Failure> {
+    if let Some(queue) = destination.strip_prefix("/queue/").filter(|q| !q.is_empty()) {
+        Ok(Destination::Queue(queue))
+    } else if let Some(topic) = destination.strip_prefix("/topic/").filter(|t| !t.is_empty()) {
+        Ok(Destination::Topic(topic))
+    } else {
+        Err(format!("unsupported destination: {destination}, expected /queue/{{name}} or /topic/{{name}}").into())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AckMode {
+    Auto,
+    /// 确认时同时确认之前投递的所有消息
+    Client,
+    ClientIndividual,
+}
+
+enum Target {
+    Queue(String),
+    /// 主题消息由单独的任务转发
+    Topic(JoinHandle<()>),
+}
+
+struct Subscription {
+    destination: String,
+    target: Target,
+    ack: AckMode,
+    /// 允许同时未确认的消息数量, 0 表示不限制
+    prefetch: usize,
+    /// 已投递未确认的消息 id, 按投递顺序
+    outstanding: BTreeSet<u64>,
+}
+
+/// 一个 STOMP 连接的状态, 与传输方式无关
+struct Session {
+    cache: WebCache,
+    out: mpsc::Sender<Frame>,
+    /// 已经通过 http token 认证
+    authed: bool,
+    connected: bool,
+    subscriptions: BTreeMap<String, Subscription>,
+    /// 事务中暂存的 SEND / ACK / NACK
+    transactions: BTreeMap<String, Vec<Frame>>,
+    topic_tx: mpsc::UnboundedSender<(String, Vec<u8>)>,
+}
+
+impl Session {
+    async fn send(&self, frame: Frame) -> Result<(), Failure> {
+        self.out.send(frame).await.map_err(|_| Failure::Closed)
+    }
+
+    fn message(sub_id: &str, sub: &Subscription, id: u64, body: Vec<u8>) -> Frame {
+        let mut frame = Frame::new("MESSAGE")
+            .header("subscription", sub_id)
+            .header("message-id", id)
+            .header("destination", &sub.destination);
+        if sub.ack != AckMode::Auto {
+            frame = frame.header("ack", id);
+        }
+        frame.body(body)
+    }
+
+    async fn connect(&mut self, frame: &Frame) -> Result<(), Failure> {
+        let accepted = frame.get("accept-version").unwrap_or("1.0");
+        let Some(version) = VERSIONS.iter().find(|v| accepted.split(',').any(|a| a.trim() == **v)) else {
+            return Err(format!("supported protocol versions are {}", VERSIONS.join(",")).into());
+        };
+        if let Some(token) = &self.cache.token {
+            let passcode = frame.get("passcode").or(frame.get("login"));
+            if !self.authed && passcode != Some(token.as_str()) {
+                return Err("authentication failed".into());
+            }
+        }
+        self.connected = true;
+        let connected = Frame::new("CONNECTED")
+            .header("version", version)
+            .header("heart-beat", "0,0")
+            .header("server", concat!("sse-queue/", env!("CARGO_PKG_VERSION")));
+        self.send(connected).await
+    }
+
+    async fn subscribe(&mut self, frame: &Frame) -> Result<(), Failure> {
+        let id = frame.required("id")?.to_string();
+        let destination = frame.required("destination")?.to_string();
+        if self.subscriptions.contains_key(&id) {
+            return Err(format!("subscription {id} already exists").into());
+        }
+        let ack = match frame.get("ack").unwrap_or("auto") {
+            "auto" => AckMode::Auto,
+            "client" => AckMode::Client,
+            "client-individual" => AckMode::ClientIndividual,
+            other => return Err(format!("invalid ack mode: {other}").into()),
+        };
+        let prefetch = match frame.get("prefetch-count") {
+            Some(n) => n.parse().map_err(|_| format!("invalid prefetch-count: {n}"))?,
+            None => DEFAULT_PREFETCH,
+        };
+        let target = match parse_destination(&destination)? {
+            Destination::Queue(queue) => Target::Queue(queue.to_string()),
+            Destination::Topic(name) => {
+                let mut receiver = topic::subscribe(&self.cache, name).await;
+                let tx = self.topic_tx.clone();
+                let sub_id = id.clone();
+                let name = name.to_string();
+                Target::Topic(rocket::tokio::spawn(async move {
+                    loop {
+                        match receiver.recv().await {
+                            Ok(msg) => {
+                                if tx.send((sub_id.clone(), msg)).is_err() {
+                                    break;
+                                }
+                            }
+                            Err(broadcast::error::RecvError::Lagged(n)) => log::warn!("stomp subscriber of {name} lagged {n} messages"),
+                            Err(broadcast::error::RecvError::Closed) => break,
+                        }
+                    }
+                }))
+            }
+        };
+        let subscription = Subscription {
+            destination,
+            target,
+            ack,
+            prefetch,
+            outstanding: BTreeSet::new(),
+        };
+        self.subscriptions.insert(id, subscription);
+        Ok(())
+    }
+
+    /// 取消订阅, 未确认的消息放回队列
+    async fn close_subscription(&self, sub: Subscription) {
+        if let Target::Topic(task) = sub.target {
+            task.abort();
+        }
+        ack::requeue_all(&self.cache, sub.outstanding).await;
+    }
+
+    /// 确认或拒绝消息, client 模式同时处理同一订阅中更早投递的消息
+    /// - 主题消息和已经放回队列的消息直接忽略
+    async fn settle(&mut self, id: u64, ack: bool, requeue: bool) {
+        let Some(sub) = self.subscriptions.values_mut().find(|s| s.outstanding.contains(&id)) else {
+            return;
+        };
+        let ids = if sub.ack == AckMode::Client {
+            let later = sub.outstanding.split_off(&(id + 1));
+            std::mem::replace(&mut sub.outstanding, later)
+        } else {
+            sub.outstanding.remove(&id);
+            BTreeSet::from([id])
+        };
+        if ack {
+            for id in ids {
+                ack::ack(&self.cache, id).await;
+            }
+        } else if requeue {
+            ack::requeue_all(&self.cache, ids).await;
+        } else {
+            for id in ids {
+                ack::nack(&self.cache, id, false).await;
+            }
+        }
+    }
+
+    /// 执行 SEND / ACK / NACK, 事务提交时也调用
+    async fn apply(&mut self, mut frame: Frame) -> Result<(), Failure> {
+        if frame.command == "SEND" {
+            let body = std::mem::take(&mut frame.body);
+            match parse_destination(frame.required("destination")?)? {
+                Destination::Queue(queue) => self.cache.queue_push_msg(queue, body).await,
+                Destination::Topic(name) => {
+                    topic::publish(&self.cache, name, body).await;
+                }
+            }
+            return Ok(());
+        }
+        let id = frame.required("id")?;
+        let id = id.parse::<u64>().map_err(|_| format!("invalid ack id: {id}"))?;
+        let requeue = frame.get("requeue") != Some("false");
+        self.settle(id, frame.command == "ACK", requeue).await;
+        Ok(())
+    }
+
+    /// 执行一个帧, 返回 false 时关闭连接
+    async fn execute(&mut self, frame: Frame) -> Result<bool, Failure> {
+        if !self.connected {
+            return match frame.command.as_str() {
+                "CONNECT" | "STOMP" => self.connect(&frame).await.map(|_| true),
+                _ => Err("not connected".into()),
+            };
+        }
+        match frame.command.as_str() {
+            "SEND" | "ACK" | "NACK" => match frame.get("transaction").map(str::to_string) {
+                Some(tx) => match self.transactions.get_mut(&tx) {
+                    Some(frames) => frames.push(frame),
+                    None => return Err(format!("unknown transaction: {tx}").into()),
+                },
+                None => self.apply(frame).await?,
+            },
+            "SUBSCRIBE" => self.subscribe(&frame).await?,
+            "UNSUBSCRIBE" => {
+                let id = frame.required("id")?;
+                let Some(sub) = self.subscriptions.remove(id) else {
+                    return Err(format!("unknown subscription: {id}").into());
+                };
+                self.close_subscription(sub).await;
+            }
+            "BEGIN" => {
+                let tx = frame.required("transaction")?;
+                if self.transactions.contains_key(tx) {
+                    return Err(format!("transaction {tx} already exists").into());
+                }
+                self.transactions.insert(tx.to_string(), vec![]);
+            }
+            "COMMIT" | "ABORT" => {
+                let tx = frame.required("transaction")?;
+                let Some(frames) = self.transactions.remove(tx) else {
+                    return Err(format!("unknown transaction: {tx}").into());
+                };
+                if frame.command == "COMMIT" {
+                    for frame in frames {
+                        self.apply(frame).await?;
+                    }
+                }
+            }
+            "DISCONNECT" => return Ok(false),
+            "CONNECT" | "STOMP" => return Err("already connected".into()),
+            other => return Err(format!("unknown command: {other}").into()),
+        }
+        Ok(true)
+    }
+
+    /// 执行一个帧并回复 RECEIPT 或 ERROR, 返回 false 时关闭连接
+    async fn handle(&mut self, frame: Frame) -> Result<bool, Failure> {
+        let receipt = frame.get("receipt").map(str::to_string);
+        let command = frame.command.clone();
+        match self.execute(frame).await {
+            Ok(keep) => {
+                if let Some(receipt) = receipt {
+                    self.send(Frame::new("RECEIPT").header("receipt-id", receipt)).await?;
+                }
+                Ok(keep)
+            }
+            Err(Failure::Error(msg)) => {
+                log::debug!("stomp {command} error: {msg}");
+                let mut error = Frame::new("ERROR").header("message", &msg);
+                if let Some(receipt) = receipt {
+                    error = error.header("receipt-id", receipt);
+                }
+                self.send(error.header("content-type", "text/plain").body(msg.into_bytes())).await?;
+                Ok(false)
+            }
+            Err(Failure::Closed) => Err(Failure::Closed),
+        }
+    }
+
+    /// 从订阅的队列中取出消息发送给客户端, 返回发送的数量
+    async fn deliver(&mut self) -> Result<usize, Failure> {
+        let mut sent = 0;
+        let sub_ids = self.subscriptions.keys().cloned().collect::<Vec<_>>();
+        for sub_id in sub_ids {
+            for _ in 0..DELIVER_BATCH {
+                let Some(sub) = self.subscriptions.get_mut(&sub_id) else {
+                    break;
+                };
+                let Target::Queue(queue) = &sub.target else {
+                    break;
+                };
+                // 超时没有确认的消息已经放回队列, 不再占用 prefetch
+                ack::retain_pending(&self.cache, &mut sub.outstanding).await;
+                if sub.ack != AckMode::Auto && sub.prefetch > 0 && sub.outstanding.len() >= sub.prefetch {
+                    break;
+                }
+                let popped = if sub.ack == AckMode::Auto {
+                    let msg = self.cache.queue_pop_msg(queue).await;
+                    msg.map(|msg| (ack::next_id(&self.cache), msg))
+                } else {
+                    ack::pop_deliver(&self.cache, queue, Some(self.cache.ack_timeout)).await
+                };
+                let Some((id, msg)) = popped else {
+                    break;
+                };
+                if sub.ack != AckMode::Auto {
+                    sub.outstanding.insert(id);
+                }
+                let queue = queue.clone();
+                let auto = sub.ack == AckMode::Auto;
+                let frame = Self::message(&sub_id, sub, id, msg.clone());
+                if let Err(err) = self.send(frame).await {
+                    // 客户端已经断开, auto 模式的消息放回队列, 其他模式在连接关闭时放回
+                    if auto {
+                        self.cache.queue_requeue_msg(&queue, msg).await;
+                    }
+                    return Err(err);
+                }
+                sent += 1;
+            }
+        }
+        Ok(sent)
+    }
+
+    async fn run(
+        &mut self,
+        incoming: &mut mpsc::Receiver<Result<Frame, String>>,
+        topic_rx: &mut mpsc::UnboundedReceiver<(String, Vec<u8>)>,
+    ) -> Result<(), Failure> {
+        loop {
+            let delivered = if self.connected { self.deliver().await? } else { 0 };
+            let wait = if delivered > 0 { Duration::ZERO } else { POLL_INTERVAL };
+            rocket::tokio::select! {
+                frame = incoming.recv() => match frame {
+                    Some(Ok(frame)) => {
+                        if !self.handle(frame).await? {
+                            return Ok(());
+                        }
+                    }
+                    Some(Err(msg)) => {
+                        let error = Frame::new("ERROR").header("message", &msg).header("content-type", "text/plain");
+                        return self.send(error.body(msg.into_bytes())).await;
+                    }
+                    None => return Ok(()),
+                },
+                Some((sub_id, msg)) = topic_rx.recv() => {
+                    if let Some(sub) = self.subscriptions.get(&sub_id) {
+                        let frame = Self::message(&sub_id, sub, ack::next_id(&self.cache), msg);
+                        self.send(frame).await?;
+                    }
+                }
+                _ = rocket::tokio::time::sleep(wait) => {}
+            }
+        }
+    }
+}
+
+/// 运行一个会话, 结束时取消所有订阅, 未确认的消息放回队列
+async fn run_session(cache: WebCache, authed: bool, mut incoming: mpsc::Receiver<Result<Frame, String>>, out: mpsc::Sender<Frame>) {
+    let (topic_tx, mut topic_rx) = mpsc::unbounded_channel();
+    let mut session = Session {
+        cache,
+        out,
+        authed,
+        connected: false,
+        subscriptions: BTreeMap::new(),
+        transactions: BTreeMap::new(),
+        topic_tx,
+    };
+    let _ = session.run(&mut incoming, &mut topic_rx).await;
+    for (_, sub) in std::mem::take(&mut session.subscriptions) {
+        session.close_subscription(sub).await;
+    }
+}
+
+/// 解析收到的数据并交给会话, 会话已经结束或数据有误时返回 false
+async fn feed(parser: &mut FrameParser, data: &[u8], tx: &mpsc::Sender<Result<Frame, String>>) -> bool {
+    parser.push(data);
+    loop {
+        match parser.next_frame() {
+            Ok(Some(frame)) => {
+                if tx.send(Ok(frame)).await.is_err() {
+                    return false;
+                }
+            }
+            Ok(None) => return true,
+            Err(err) => {
+                let _ = tx.send(Err(err)).await;
+                return false;
+            }
+        }
+    }
+}
+
+async fn handle_tcp(cache: WebCache, stream: TcpStream) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (frame_tx, frame_rx) = mpsc::channel(64);
+    let reader_task = rocket::tokio::spawn(async move {
+        let mut parser = FrameParser::default();
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            if !feed(&mut parser, &buf[..n], &frame_tx).await {
+                break;
+            }
+        }
+    });
+    let (out_tx, mut out_rx) = mpsc::channel::<Frame>(64);
+    let writer_task = rocket::tokio::spawn(async move {
+        while let Some(frame) = out_rx.recv().await {
+            if writer.write_all(&frame.encode()).await.is_err() {
+                break;
+            }
+        }
+    });
+    run_session(cache, false, frame_rx, out_tx).await;
+    reader_task.abort();
+    let _ = rocket::tokio::time::timeout(FLUSH_TIMEOUT, writer_task).await;
+}
+
+/// 启动 STOMP tcp 服务, 绑定失败时只记录错误, 不影响 http 服务
+pub async fn serve(cache: WebCache, bind: String) {
+    let listener = match TcpListener::bind(&bind).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("stomp listen on {bind} error: {err}");
+            return;
+        }
+    };
+    log::info!("stomp on: {bind}");
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                log::debug!("stomp client {addr} connected");
+                rocket::tokio::spawn(handle_tcp(cache.clone(), stream));
+            }
+            Err(err) => log::warn!("stomp accept error: {err}"),
+        }
+    }
+}
+
+async fn handle_ws(cache: WebCache, authed: bool, stream: ws::stream::DuplexStream) {
+    let (mut sink, mut source) = stream.split();
+    let (frame_tx, frame_rx) = mpsc::channel(64);
+    let reader_task = rocket::tokio::spawn(async move {
+        let mut parser = FrameParser::default();
+        while let Some(message) = source.next().await {
+            let fed = match message {
+                Ok(ws::Message::Text(text)) => feed(&mut parser, text.as_bytes(), &frame_tx).await,
+                Ok(ws::Message::Binary(data)) => feed(&mut parser, &data, &frame_tx).await,
+                Ok(ws::Message::Close(_)) | Err(_) => break,
+                Ok(_) => true,
+            };
+            if !fed {
+                break;
+            }
+        }
+    });
+    let (out_tx, mut out_rx) = mpsc::channel::<Frame>(64);
+    let writer_task = rocket::tokio::spawn(async move {
+        while let Some(frame) = out_rx.recv().await {
+            let message = match String::from_utf8(frame.encode()) {
+                Ok(text) => ws::Message::Text(text),
+                Err(err) => ws::Message::Binary(err.into_bytes()),
+            };
+            if sink.send(message).await.is_err() {
+                return;
+            }
+        }
+        let _ = sink.close().await;
+    });
+    run_session(cache, authed, frame_rx, out_tx).await;
+    reader_task.abort();
+    let _ = rocket::tokio::time::timeout(FLUSH_TIMEOUT, writer_task).await;
+}
+
+/// 浏览器请求子协议时必须在握手中回复其中一个, 否则会关闭连接
+struct StompChannel(ws::Channel<'static>);
+
+impl<'r> Responder<'r, 'static> for StompChannel {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let protocol = req
+            .headers()
+            .get("Sec-WebSocket-Protocol")
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .find(|p| WS_PROTOCOLS.contains(p))
+            .map(str::to_string);
+        let mut response = self.0.respond_to(req)?;
+        if let Some(protocol) = protocol {
+            response.set_raw_header("Sec-WebSocket-Protocol", protocol);
+        }
+        Ok(response)
+    }
+}
+
+/// 1. stomp over websocket
+/// - url: "/msg/stomp"
+/// - 帧的处理与 [stomp] bind 的 tcp 服务相同
+/// - url 中带上 token 时 CONNECT 不需要再认证, 否则 passcode 或 login 为 token
+#[get("/stomp")]
+async fn stomp_ws(ws: ws::WebSocket, state: &State<WebCache>, auth: TokenAuth) -> StompChannel {
+    let cache = state.inner().clone();
+    let authed = auth.pass_root();
+    StompChannel(ws.channel(move |stream| {
+        Box::pin(async move {
+            handle_ws(cache, authed, stream).await;
+            Ok(())
+        })
+    }))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![stomp_ws]
+}
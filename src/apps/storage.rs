@@ -1,8 +1,15 @@
 use std::ops::DerefMut;
 use std::os::unix::fs::MetadataExt;
-use std::path::Path;
+use std::path::PathBuf;
 
 use super::auth::TokenAuth;
+use super::objects::{self, ListOptions, ObjectList};
+use super::quota;
+use super::blobs;
+use super::notify;
+use super::handles::{self, HandleKind};
+use crate::config::StorageAction;
+use super::checksum::{self, ExpectedDigest, WithChecksum};
 use super::state::WebCache;
 use super::types::ResultBase;
 use super::seekstream::FileSeekStream;
@@ -11,7 +18,7 @@ use rocket::fs::NamedFile;
 use rocket::serde::json::Json;
 use rocket::tokio::fs;
 use rocket::{data::ToByteUnit, get, head, post, Data, State};
-use rocket::tokio::io::{AsyncSeekExt, SeekFrom};
+use rocket::tokio::io::{self as io, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
 
 
 /// 1. put
@@ -33,16 +40,11 @@ async fn upload_file1(
     data: Data<'_>,
     auth: TokenAuth,
     cache: &State<WebCache>,
+    headers: super::auth::Headers,
 ) -> super::WebResult<Json<ResultBase<bool>>> {
     auth.check_pass_root()?;
-    let mut data_dir = cache.open_data_dir(bucket);
-    if !fs::try_exists(&data_dir).await.unwrap_or(false) {
-        fs::create_dir_all(&data_dir).await?
-    }
-    data_dir.push(name);
-    let mut file = fs::File::create(data_dir).await?;
-    data.open(4.gibibytes()).stream_to(&mut file).await?;
-    Ok(Json(ResultBase::ok(true)))
+    let expected = ExpectedDigest::from_headers(&headers)?;
+    upload_file(bucket, name, expected, data, cache).await
 }
 
 /// 1. put
@@ -57,45 +59,157 @@ async fn upload_file1(
 ///     - response json
 ///         - {"code":1,"msg":"ok","result":true}
 ///         - {"code":0,"msg":"error","result":false}
-#[post("/put/<bucket>/<name>", data = "<data>")]
+#[post("/put/<bucket>/<name..>", data = "<data>")]
 async fn upload_file2(
     bucket: &str,
-    name: &str,
+    name: PathBuf,
     data: Data<'_>,
     auth: TokenAuth,
     cache: &State<WebCache>,
+    headers: super::auth::Headers,
 ) -> super::WebResult<Json<ResultBase<bool>>> {
     auth.check_pass_root()?;
-    let mut data_dir = cache.open_data_dir(bucket);
-    if !fs::try_exists(&data_dir).await.unwrap_or(false) {
-        fs::create_dir_all(&data_dir).await?
-    }
-    // if fs::metadata(&data_dir).await.is_err() {
-    //     fs::create_dir_all(&data_dir).await?;
-    // }
-    data_dir.push(name);
-    // log::debug!("save file at: {}",data_dir.display());
-    let mut file = fs::File::create(data_dir).await?;
-    data.open(4.gibibytes()).stream_to(&mut file).await?;
+    let expected = ExpectedDigest::from_headers(&headers)?;
+    let name = objects::object_key(&name)?;
+    upload_file(bucket, &name, expected, data, cache).await
+}
+
+async fn upload_file(bucket:&str,name:&str,expected:ExpectedDigest,data:Data<'_>,cache:&State<WebCache>)->super::WebResult<Json<ResultBase<bool>>>{
+    put_object(cache, bucket, name, expected, data.open(upload_limit(None).bytes())).await?;
     Ok(Json(ResultBase::ok(true)))
 }
 
+/// 对象名称中的 `/` 映射为子目录, 写入前创建所需的目录
+/// 数据先写入同目录下的临时文件, 配额和摘要检查都通过后才替换原来的对象
+/// - 开启版本管理的 bucket 在替换前归档旧内容
+/// - 与请求中的 Content-MD5 / x-checksum-sha256 不一致或上传中断时只删除临时文件, 原来的对象保持不变
+/// - http 和 grpc 上传共用, 返回写入的字节数
+pub async fn put_object<R: AsyncRead + Unpin>(cache:&WebCache,bucket:&str,name:&str,expected:ExpectedDigest,mut data:R)->super::WebResult<u64>{
+    let path = cache.open_object_path(bucket, name)?;
+    let bucket_dir = cache.open_data_dir(bucket)?;
+    let (replaced, replaced_digest) = match fs::metadata(&path).await {
+        Ok(meta) if meta.is_file() => {
+            let digest = checksum::load(&bucket_dir, name, &meta).await.map(|c| c.sha256);
+            (Some(meta.len()), digest)
+        }
+        _ => (None, None),
+    };
+    let limit = quota::write_limit(cache, bucket, replaced).await?;
+    if let Some(data_dir) = path.parent() {
+        if !fs::try_exists(data_dir).await.unwrap_or(false) {
+            fs::create_dir_all(data_dir).await?
+        }
+    }
+    // log::debug!("save file at: {}",path.display());
+    let tmp = blobs::temp_path(&path, &format!("{:016x}.upload", rand::random::<u64>()));
+    let written = async {
+        let mut writer = checksum::HashWriter::new(fs::File::create(&tmp).await?);
+        let written = io::copy(&mut (&mut data).take(upload_limit(limit)), &mut writer).await?;
+        // 读取到限制的长度后还有数据说明上传不完整
+        let complete = written < upload_limit(limit) || data.read(&mut [0u8; 1]).await? == 0;
+        let (mut file, md5, sha256) = writer.finish();
+        file.flush().await?;
+        quota_exceeded(bucket, limit, complete).map_or(Ok(()), Err)?;
+        expected.verify(&md5, &sha256)?;
+        let bytes = written as i64 - replaced.unwrap_or_default() as i64;
+        quota::commit(cache, bucket, bytes, replaced.map_or(1, |_| 0)).await?;
+        Ok::<_, super::WebError>((written, md5, sha256))
+    }
+    .await;
+    let (written, md5, sha256) = match written {
+        Ok(written) => written,
+        Err(err) => {
+            // 超出配额, 摘要不一致或读取失败, 只删除临时文件
+            let _ = fs::remove_file(&tmp).await;
+            objects::remove_empty_parents(&bucket_dir, &path).await;
+            return Err(err);
+        }
+    };
+    let replace = async {
+        super::versions::archive_current(cache, bucket, name).await?;
+        fs::rename(&tmp, &path).await
+    };
+    if let Err(err) = replace.await {
+        let _ = fs::remove_file(&tmp).await;
+        let bytes = written as i64 - replaced.unwrap_or_default() as i64;
+        quota::record(cache, bucket, -bytes, -replaced.map_or(1, |_| 0)).await;
+        return Err(err.into());
+    }
+    if let Some(sha256) = replaced_digest {
+        blobs::release(cache, &sha256).await;
+    }
+    // 先保存摘要, 记录对象自己的写入时间, 去重后修改时间与 blob 共享
+    checksum::save(cache, bucket, name, md5, sha256.clone()).await?;
+    if cache.dedup {
+        blobs::dedup(cache, &path, &sha256).await?;
+    }
+    notify::publish(cache, bucket, name, written, StorageAction::Put).await;
+    Ok(written)
+}
+
+/// 对象当前的大小, 用于通知事件
+async fn object_size(cache:&WebCache,bucket:&str,name:&str) -> u64 {
+    match cache.open_object_path(bucket, name) {
+        Ok(path) => fs::metadata(path).await.map(|m| m.len()).unwrap_or_default(),
+        Err(_) => 0,
+    }
+}
+
+/// 单次上传最多读取的字节数, 受配额剩余容量限制
+fn upload_limit(quota_limit: Option<u64>) -> u64 {
+    quota_limit.unwrap_or(u64::MAX).min(4.gibibytes().as_u64())
+}
+
+/// 上传数据因为配额限制没有读取完整时返回配额错误
+fn quota_exceeded(bucket:&str,quota_limit: Option<u64>,complete: bool) -> Option<super::WebError> {
+    match quota_limit {
+        Some(limit) if !complete && limit < 4.gibibytes().as_u64() => Some(quota::exceeded(bucket, limit)),
+        _ => None,
+    }
+}
+
+/// 追加写入, 超出配额时回滚本次写入的数据
+async fn append_limited(bucket:&str,aft:&mut fs::File,exists:bool,limit:Option<u64>,data:Data<'_>,cache:&State<WebCache>)->super::WebResult<()>{
+    let orig_len = aft.metadata().await?.len();
+    let n = data.open(upload_limit(limit).bytes()).stream_to(&mut *aft).await?;
+    let committed = match quota_exceeded(bucket, limit, n.complete) {
+        Some(err) => Err(err),
+        None => quota::commit(cache, bucket, n.written as i64, if exists { 0 } else { 1 }).await,
+    };
+    if committed.is_err() {
+        aft.set_len(orig_len).await?;
+        if !exists {
+            // 新建的空文件仍然保留
+            quota::record(cache, bucket, 0, 1).await;
+        }
+    }
+    committed
+}
+
 async fn append_file(bucket:&str,name:&str,hold:Option<bool>,data:Data<'_>,cache:&State<WebCache>,auth: TokenAuth)->super::WebResult<Json<ResultBase<bool>>>{
     auth.check_pass_root()?;
+    let exists = fs::try_exists(cache.open_object_path(bucket, name)?).await.unwrap_or(false);
+    let limit = quota::write_limit(cache, bucket, exists.then_some(0)).await?;
     let af = cache.open_append_file(bucket, name).await?;
-    let count = {
-        let mut afg = af.lock().await;
-        if let Some(aft) = (&mut afg.1).deref_mut() {
-            data.open(4.gibibytes()).stream_to(aft).await?;
+    let appended = {
+        let mut afg = af.1.lock().await;
+        if let Some(aft) = afg.deref_mut() {
+            append_limited(bucket, aft, exists, limit, data, cache).await
         } else {
             log::warn!("get null file in cache.");
+            Ok(())
         }
-        afg.0.fetch_sub(1,std::sync::atomic::Ordering::Relaxed)
     };
-    if count == 0 && !hold.unwrap_or_default() {
+    // 写入完成后记录时间, 空闲清理按最后一次写入完成的时间计算
+    handles::touch(cache, HandleKind::Append, &format!("{bucket}/{name}")).await;
+    // 返回减一之前的数量, 为 1 时没有其他请求正在使用
+    let count = af.0.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+    if count == 1 && !hold.unwrap_or_default() {
         cache.close_append_file(bucket, name).await?;
     }
+    appended?;
     // cache.close_append_file(bucket, name).await?;
+    notify::publish(cache, bucket, name, object_size(cache, bucket, name).await, StorageAction::Append).await;
     Ok(Json(ResultBase::ok(true)))
 }
 
@@ -111,16 +225,17 @@ async fn append_file1(
     append_file(bucket,name,hold,data,cache,auth).await
 }
 
-#[post("/append/<bucket>/<name>?<hold>", data = "<data>")]
+#[post("/append/<bucket>/<name..>?<hold>", data = "<data>")]
 async fn append_file2(
     bucket: &str,
-    name: &str,
+    name: PathBuf,
     hold:Option<bool>,
     data: Data<'_>,
     auth: TokenAuth,
     cache: &State<WebCache>,
 ) -> super::WebResult<Json<ResultBase<bool>>> {
-    append_file(bucket,name,hold,data,cache,auth).await
+    let name = objects::object_key(&name)?;
+    append_file(bucket,&name,hold,data,cache,auth).await
 }
 
 
@@ -133,18 +248,21 @@ async fn close_append_file1(
 ) -> super::WebResult<Json<ResultBase<bool>>> {
     auth.check_pass_root()?;
     cache.close_append_file(bucket, name).await?;
+    notify::publish(cache, bucket, name, object_size(cache, bucket, name).await, StorageAction::CloseAppend).await;
     Ok(Json(ResultBase::ok(true)))
 }
 
-#[get("/closeappend/<bucket>/<name>")]
+#[get("/closeappend/<bucket>/<name..>")]
 async fn close_append_file2(
     bucket: &str,
-    name: &str,
+    name: PathBuf,
     auth: TokenAuth,
     cache: &State<WebCache>,
 ) -> super::WebResult<Json<ResultBase<bool>>> {
     auth.check_pass_root()?;
-    cache.close_append_file(bucket, name).await?;
+    let name = objects::object_key(&name)?;
+    cache.close_append_file(bucket, &name).await?;
+    notify::publish(cache, bucket, &name, object_size(cache, bucket, &name).await, StorageAction::CloseAppend).await;
     Ok(Json(ResultBase::ok(true)))
 }
 
@@ -163,11 +281,12 @@ async fn download_file1(
     name: &str,
     auth: TokenAuth,
     cache: &State<WebCache>,
-) -> super::WebResult<NamedFile> {
+) -> super::WebResult<WithChecksum<NamedFile>> {
     auth.check_pass_root()?;
-    let mut path = cache.open_data_dir(bucket);
-    path.push(name);
-    Ok(NamedFile::open(path).await?)
+    let path = cache.open_object_path(bucket, name)?;
+    let meta = fs::metadata(&path).await?;
+    let checksums = checksum::load(&cache.open_data_dir(bucket)?, name, &meta).await;
+    Ok(WithChecksum(NamedFile::open(path).await?, checksums))
 }
 
 #[head("/get?<bucket>&<name>")]
@@ -176,14 +295,14 @@ async fn head_download_file1(
     name: &str,
     auth: TokenAuth,
     cache: &State<WebCache>,
-) -> super::WebResult<FileSeekStream> {
+) -> super::WebResult<WithChecksum<FileSeekStream>> {
     auth.check_pass_root()?;
-    let mut path = cache.open_data_dir(bucket);
-    path.push(name);
-    let content_len = fs::metadata(&path).await?.len();
+    let path = cache.open_object_path(bucket, name)?;
+    let meta = fs::metadata(&path).await?;
+    let checksums = checksum::load(&cache.open_data_dir(bucket)?, name, &meta).await;
     let fp = fs::File::open(path).await?;
-    let fileseek = FileSeekStream { content_len,range1:None, fp };
-    Ok(fileseek)
+    let fileseek = FileSeekStream { content_len: meta.len(),range1:None, fp };
+    Ok(WithChecksum(fileseek, checksums))
 }
 
 /// 2. get
@@ -195,18 +314,19 @@ async fn head_download_file1(
 ///     - bucket: string, required
 ///     - name: string, required
 /// - response status: 200 body: any bytes
-#[get("/get/<bucket>/<name>")]
-async fn download_file2<'r>(
+#[get("/get/<bucket>/<name..>")]
+async fn download_file2(
     bucket: &str,
-    name: &str,
+    name: PathBuf,
     auth: TokenAuth,
     cache: &State<WebCache>,
     headers: super::auth::Headers,
-) -> super::WebResult<FileSeekStream> {
+) -> super::WebResult<WithChecksum<FileSeekStream>> {
     auth.check_pass_root()?;
-    let mut path = cache.open_data_dir(bucket);
-    path.push(name);
-    let content_len = fs::metadata(&path).await?.len();
+    let name = objects::object_key(&name)?;
+    let path = cache.open_object_path(bucket, &name)?;
+    let meta = fs::metadata(&path).await?;
+    let content_len = meta.len();
     let mut fp = fs::File::open(path).await?;
     #[cfg(debug_assertions)]
     {
@@ -238,29 +358,31 @@ async fn download_file2<'r>(
             let &(start, end) = ranges.first().unwrap();
             fp.seek(SeekFrom::Start(start)).await?;
             let fileseek = FileSeekStream { content_len, range1:Some((start,end)),fp };
-            Ok(fileseek)
+            Ok(WithChecksum(fileseek, None))
         }
     } else {
+        let checksums = checksum::load(&cache.open_data_dir(bucket)?, &name, &meta).await;
         let fileseek = FileSeekStream { content_len, range1:None,fp };
-        Ok(fileseek)
+        Ok(WithChecksum(fileseek, checksums))
     }
 
 }
 
-#[head("/get/<bucket>/<name>")]
+#[head("/get/<bucket>/<name..>")]
 async fn head_download_file2(
     bucket: &str,
-    name: &str,
+    name: PathBuf,
     auth: TokenAuth,
     cache: &State<WebCache>,
-) -> super::WebResult<FileSeekStream> {
+) -> super::WebResult<WithChecksum<FileSeekStream>> {
     auth.check_pass_root()?;
-    let mut path = cache.open_data_dir(bucket);
-    path.push(name);
-    let content_len = fs::metadata(&path).await?.len();
+    let name = objects::object_key(&name)?;
+    let path = cache.open_object_path(bucket, &name)?;
+    let meta = fs::metadata(&path).await?;
+    let checksums = checksum::load(&cache.open_data_dir(bucket)?, &name, &meta).await;
     let fp = fs::File::open(path).await?;
-    let fileseek = FileSeekStream { content_len,range1:None, fp };
-    Ok(fileseek)
+    let fileseek = FileSeekStream { content_len: meta.len(),range1:None, fp };
+    Ok(WithChecksum(fileseek, checksums))
 }
 
 #[get("/exists?<bucket>&<name>")]
@@ -271,22 +393,20 @@ async fn exists_file1(
     cache: &State<WebCache>,
 ) -> super::WebResult<Json<ResultBase<bool>>> {
     auth.check_pass_root()?;
-    let mut path = cache.open_data_dir(bucket);
-    path.push(name);
+    let path = cache.open_object_path(bucket, name)?;
     let result = ResultBase::ok(fs::metadata(path).await.is_ok());
     Ok(Json(result))
 }
 
-#[get("/exists/<bucket>/<name>")]
+#[get("/exists/<bucket>/<name..>")]
 async fn exists_file2(
     bucket: &str,
-    name: &str,
+    name: PathBuf,
     auth: TokenAuth,
     cache: &State<WebCache>,
 ) -> super::WebResult<Json<ResultBase<bool>>> {
     auth.check_pass_root()?;
-    let mut path = cache.open_data_dir(bucket);
-    path.push(name);
+    let path = cache.open_object_path(bucket, &objects::object_key(&name)?)?;
     let result = ResultBase::ok(fs::metadata(path).await.is_ok());
     Ok(Json(result))
 }
@@ -300,23 +420,21 @@ async fn fsize_file1(
     cache: &State<WebCache>,
 ) -> super::WebResult<Json<ResultBase<u64>>> {
     auth.check_pass_root()?;
-    let mut path = cache.open_data_dir(bucket);
-    path.push(name);
+    let path = cache.open_object_path(bucket, name)?;
     let fsize = fs::metadata(path).await?.size();
     let result = ResultBase::ok(fsize);
     Ok(Json(result))
 }
 
-#[get("/fsize/<bucket>/<name>")]
+#[get("/fsize/<bucket>/<name..>")]
 async fn fsize_file2(
     bucket: &str,
-    name: &str,
+    name: PathBuf,
     auth: TokenAuth,
     cache: &State<WebCache>,
 ) -> super::WebResult<Json<ResultBase<u64>>> {
     auth.check_pass_root()?;
-    let mut path = cache.open_data_dir(bucket);
-    path.push(name);
+    let path = cache.open_object_path(bucket, &objects::object_key(&name)?)?;
     let fsize = fs::metadata(path).await?.size();
     let result = ResultBase::ok(fsize);
     Ok(Json(result))
@@ -340,7 +458,7 @@ async fn create_bucket1(
     cache: &State<WebCache>,
 ) -> super::WebResult<Json<ResultBase<bool>>> {
     auth.check_pass_root()?;
-    let data_dir = cache.open_data_dir(bucket);
+    let data_dir = cache.open_data_dir(bucket)?;
     if !fs::try_exists(&data_dir).await.unwrap_or(false) {
         fs::create_dir_all(&data_dir).await?
     }
@@ -363,57 +481,75 @@ async fn create_bucket2(
     cache: &State<WebCache>,
 ) -> super::WebResult<Json<ResultBase<bool>>> {
     auth.check_pass_root()?;
-    let data_dir = cache.open_data_dir(bucket);
+    let data_dir = cache.open_data_dir(bucket)?;
     if !fs::try_exists(&data_dir).await.unwrap_or(false) {
         fs::create_dir_all(&data_dir).await?
     }
     Ok(Json(ResultBase::ok(true)))
 }
 
-async fn list_bucket(parent:impl AsRef<Path>,filter:Option<&str>)-> super::WebResult<Vec<String>>{
-    let mut entries = fs::read_dir(parent.as_ref()).await?;
-    let mut file_list = vec![];
-    if let Some(filter) = filter {
-        while let Some(entry) = entries.next_entry().await? {
-            if let Some(file_name) = entry.file_name().to_str() {
-                if file_name.starts_with(filter) {
-                    file_list.push(file_name.to_string());
-                }
-            }
-        }
-    } else {
-        while let Some(entry) = entries.next_entry().await? {
-            if let Some(file_name) = entry.file_name().to_str() {
-                file_list.push(file_name.to_string());
-            }
-        }
-    }
-    Ok(file_list)
-}
-
-#[get("/list?<bucket>&<filter>")]
+/// 9. list
+/// - url
+///     - "/storage/list"
+///     - "/storage/list/{bucket}"
+/// - method: GET
+/// - params:
+///     - prefix: string, optional, `filter` 为兼容的旧名称
+///     - recursive: bool, optional, 列出前缀下的所有对象
+///     - delimiter: string, optional, 默认 `/`, 非递归模式下合并子目录
+///     - token: string, optional, 上一页返回的 next_token
+///     - max_keys: int, optional, 默认 1000
+/// - response json
+///     - {"code":0,"msg":"ok","ok":true,"data":{"entries":[...],"next_token":"a.txt"}}
+#[allow(clippy::too_many_arguments)]
+#[get("/list?<bucket>&<filter>&<prefix>&<recursive>&<delimiter>&<token>&<max_keys>")]
 async fn list_bucket1(
     bucket: &str,
     filter: Option<&str>,
+    prefix: Option<&str>,
+    recursive: Option<bool>,
+    delimiter: Option<&str>,
+    token: Option<&str>,
+    max_keys: Option<usize>,
     auth: TokenAuth,
     cache: &State<WebCache>,
-) -> super::WebResult<Json<ResultBase<Vec<String>>>> {
+) -> super::WebResult<Json<ResultBase<ObjectList>>> {
     auth.check_pass_root()?;
-    let data_dir = cache.open_data_dir(bucket);
-    let file_list = list_bucket(&data_dir, filter).await?;
+    let data_dir = cache.open_data_dir(bucket)?;
+    let opts = ListOptions {
+        prefix: prefix.or(filter).unwrap_or_default(),
+        recursive: recursive.unwrap_or_default(),
+        delimiter,
+        token,
+        max_keys: max_keys.unwrap_or(objects::DEFAULT_MAX_KEYS),
+    };
+    let file_list = objects::list_objects(&data_dir, &opts).await?;
     Ok(Json(ResultBase::ok(file_list)))
 }
 
-#[get("/list/<bucket>?<filter>")]
+#[allow(clippy::too_many_arguments)]
+#[get("/list/<bucket>?<filter>&<prefix>&<recursive>&<delimiter>&<token>&<max_keys>")]
 async fn list_bucket2(
     bucket: &str,
     filter: Option<&str>,
+    prefix: Option<&str>,
+    recursive: Option<bool>,
+    delimiter: Option<&str>,
+    token: Option<&str>,
+    max_keys: Option<usize>,
     auth: TokenAuth,
     cache: &State<WebCache>,
-) -> super::WebResult<Json<ResultBase<Vec<String>>>> {
+) -> super::WebResult<Json<ResultBase<ObjectList>>> {
     auth.check_pass_root()?;
-    let data_dir = cache.open_data_dir(bucket);
-    let file_list = list_bucket(&data_dir, filter).await?;
+    let data_dir = cache.open_data_dir(bucket)?;
+    let opts = ListOptions {
+        prefix: prefix.or(filter).unwrap_or_default(),
+        recursive: recursive.unwrap_or_default(),
+        delimiter,
+        token,
+        max_keys: max_keys.unwrap_or(objects::DEFAULT_MAX_KEYS),
+    };
+    let file_list = objects::list_objects(&data_dir, &opts).await?;
     Ok(Json(ResultBase::ok(file_list)))
 }
 
@@ -436,28 +572,65 @@ async fn delete_bucket2(
     cache: &State<WebCache>,
 ) -> super::WebResult<Json<ResultBase<bool>>> {
     auth.check_pass_root()?;
-    let path = cache.open_data_dir(bucket);
-    fs::remove_dir_all(path).await?;
+    delete_bucket(bucket, false, cache).await?;
     Ok(Json(ResultBase::ok(true)))
 }
 
-#[get("/del/<bucket>/<name>?<exists_ok>")]
+/// 删除对象, 并清理删除后为空的子目录
+async fn delete_file(bucket:&str,name:&str,exists_ok:bool,cache:&State<WebCache>)->super::WebResult<()>{
+    let path = cache.open_object_path(bucket, name)?;
+    let bucket_dir = cache.open_data_dir(bucket)?;
+    let meta = fs::metadata(&path).await;
+    let digest = match &meta {
+        Ok(meta) => checksum::load(&bucket_dir, name, meta).await.map(|c| c.sha256),
+        Err(_) => None,
+    };
+    let removed = fs::remove_file(&path).await;
+    if removed.is_ok() {
+        let size = meta.map(|m| m.len()).unwrap_or_default();
+        quota::record(cache, bucket, -(size as i64), -1).await;
+        checksum::remove(&bucket_dir, name).await;
+        if let Some(sha256) = digest {
+            blobs::release(cache, &sha256).await;
+        }
+        notify::publish(cache, bucket, name, size, StorageAction::Delete).await;
+    }
+    if !exists_ok {
+        removed?;
+    }
+    objects::remove_empty_parents(&bucket_dir, &path).await;
+    Ok(())
+}
+
+/// 删除 bucket, 并释放 bucket 中对象引用的 blob
+async fn delete_bucket(bucket:&str,exists_ok:bool,cache:&State<WebCache>)->super::WebResult<()>{
+    let digests = blobs::bucket_digests(cache, bucket).await;
+    let path = cache.open_data_dir(bucket)?;
+    let removed = fs::remove_dir_all(path).await;
+    if removed.is_ok() {
+        notify::publish(cache, bucket, "", 0, StorageAction::DeleteBucket).await;
+    }
+    if !exists_ok {
+        removed?;
+    }
+    quota::forget(cache, bucket).await;
+    for sha256 in digests {
+        blobs::release(cache, &sha256).await;
+    }
+    Ok(())
+}
+
+#[get("/del/<bucket>/<name..>?<exists_ok>", rank = 2)]
 async fn delete_file3(
     bucket: &str,
-    name: &str,
+    name: PathBuf,
     exists_ok: Option<bool>,
     auth: TokenAuth,
     cache: &State<WebCache>,
 ) -> super::WebResult<Json<ResultBase<bool>>> {
     auth.check_pass_root()?;
-    let mut path = cache.open_data_dir(bucket);
-    path.push(name);
-    let exists_ok = exists_ok.unwrap_or(false);
-    if !exists_ok {
-        fs::remove_file(path).await?;
-    } else {
-        let _ = fs::remove_file(path).await;
-    }
+    let name = objects::object_key(&name)?;
+    delete_file(bucket, &name, exists_ok.unwrap_or(false), cache).await?;
     Ok(Json(ResultBase::ok(true)))
 }
 
@@ -472,27 +645,15 @@ async fn delete_file4(
     auth.check_pass_root()?;
     let exists_ok = exists_ok.unwrap_or(false);
     if let Some(name) = name {
-        let mut path = cache.open_data_dir(bucket);
-        path.push(name);
-        if !exists_ok {
-            fs::remove_file(path).await?;
-        } else {
-            let _ = fs::remove_file(path).await;
-        }
+        delete_file(bucket, name, exists_ok, cache).await?;
     } else {
-        let path = cache.open_data_dir(bucket);
-        if !exists_ok {
-            fs::remove_dir_all(path).await?;
-        } else {
-            let _ = fs::remove_dir_all(path).await;
-        }
-        
+        delete_bucket(bucket, exists_ok, cache).await?;
     }
     Ok(Json(ResultBase::ok(true)))
 }
 
 pub fn routes() -> Vec<rocket::Route> {
-    rocket::routes![
+    let mut routes = rocket::routes![
         upload_file1,
         upload_file2,
         download_file1,
@@ -515,5 +676,15 @@ pub fn routes() -> Vec<rocket::Route> {
         close_append_file2,
         fsize_file1,
         fsize_file2,
-    ]
+    ];
+    routes.extend(super::versions::routes());
+    routes.extend(super::lifecycle::routes());
+    routes.extend(super::quota::routes());
+    routes.extend(super::checksum::routes());
+    routes.extend(super::blobs::routes());
+    routes.extend(super::transfer::routes());
+    routes.extend(super::archive::routes());
+    routes.extend(super::tail::storage_routes());
+    routes.extend(super::handles::routes());
+    routes
 }
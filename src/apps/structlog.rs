@@ -0,0 +1,288 @@
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::auth::TokenAuth;
+use super::state::WebCache;
+use super::types::ResultBase;
+
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::tokio::fs;
+use rocket::tokio::io::{AsyncBufReadExt, AsyncSeekExt, BufReader, SeekFrom};
+use rocket::{get, State};
+use serde_json::{Map, Value};
+
+/// 服务端写入的接收时间字段, unix 时间戳(毫秒)
+pub const TS_FIELD: &str = "_ts";
+/// 服务端写入的客户端 ip 字段
+pub const IP_FIELD: &str = "_ip";
+
+/// 每页默认/最多返回的记录数
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+/// 上传日志的格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// 原样写入
+    Raw,
+    /// 按行写入, 请求中的内容整行一次写入, 不会与其他请求交错
+    Lines,
+    /// 每行一个 JSON 对象
+    Json,
+    /// 每行 key=value key2="quoted value"
+    Logfmt,
+}
+
+impl LogFormat {
+    pub fn parse(format: Option<&str>) -> super::WebResult<Self> {
+        match format.unwrap_or("raw") {
+            "raw" => Ok(Self::Raw),
+            "lines" => Ok(Self::Lines),
+            "json" | "jsonl" => Ok(Self::Json),
+            "logfmt" => Ok(Self::Logfmt),
+            format => Err(super::WebError::new(format!("unsupported log format: {format}"))),
+        }
+    }
+}
+
+/// 解析 logfmt 格式的一行, 值都保存为字符串, 没有值的 key 为 true
+fn parse_logfmt(line: &str) -> Result<Map<String, Value>, String> {
+    let mut record = Map::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '=') {
+            key.push(c);
+        }
+        if key.is_empty() {
+            return Err("empty key".to_string());
+        }
+        if chars.next_if_eq(&'=').is_none() {
+            record.insert(key, Value::Bool(true));
+            continue;
+        }
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => value.push('\n'),
+                        Some('t') => value.push('\t'),
+                        Some('r') => value.push('\r'),
+                        Some(c) => value.push(c),
+                        None => return Err(format!("unterminated quoted value of {key}")),
+                    },
+                    Some(c) => value.push(c),
+                    None => return Err(format!("unterminated quoted value of {key}")),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                value.push(c);
+            }
+        }
+        record.insert(key, Value::String(value));
+    }
+    Ok(record)
+}
+
+fn parse_record(format: LogFormat, line: &str) -> Result<Map<String, Value>, String> {
+    match format {
+        LogFormat::Json => match serde_json::from_str::<Value>(line) {
+            Ok(Value::Object(record)) => Ok(record),
+            Ok(_) => Err("not a json object".to_string()),
+            Err(err) => Err(err.to_string()),
+        },
+        LogFormat::Logfmt => parse_logfmt(line),
+        LogFormat::Raw | LogFormat::Lines => Err("plain log is not structured".to_string()),
+    }
+}
+
+/// 校验并转换上传的内容为 JSON lines, 每条记录加上接收时间和客户端 ip
+/// - 空行忽略
+/// - 任何一行无法解析时返回错误, 整个请求不写入
+pub fn encode(format: LogFormat, body: &[u8], ip: Option<IpAddr>) -> super::WebResult<Vec<u8>> {
+    let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    let mut out = Vec::with_capacity(body.len() + body.len() / 2);
+    for (idx, line) in body.split(|b| *b == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let line = std::str::from_utf8(line)
+            .map_err(|_| super::WebError::new(format!("line {}: invalid utf8", idx + 1)))?;
+        let mut record =
+            parse_record(format, line).map_err(|err| super::WebError::new(format!("line {}: {err}", idx + 1)))?;
+        record.insert(TS_FIELD.to_string(), Value::from(ts));
+        match ip {
+            Some(ip) => record.insert(IP_FIELD.to_string(), Value::String(ip.to_string())),
+            None => record.remove(IP_FIELD),
+        };
+        serde_json::to_writer(&mut out, &record).map_err(|err| super::WebError::new(err.to_string()))?;
+        out.push(b'\n');
+    }
+    Ok(out)
+}
+
+/// 查询条件
+struct Filter {
+    since: Option<u64>,
+    until: Option<u64>,
+    levels: Vec<String>,
+    fields: Vec<(String, String)>,
+}
+
+impl Filter {
+    fn new(since: Option<u64>, until: Option<u64>, level: Option<&str>, eq: Vec<&str>) -> super::WebResult<Self> {
+        let levels = level
+            .map(|level| level.split(',').map(|l| l.trim().to_lowercase()).filter(|l| !l.is_empty()).collect())
+            .unwrap_or_default();
+        let fields = eq
+            .into_iter()
+            .map(|eq| match eq.split_once(':') {
+                Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+                _ => Err(super::WebError::new(format!("invalid field filter: {eq}, expect key:value"))),
+            })
+            .collect::<super::WebResult<_>>()?;
+        Ok(Self { since, until, levels, fields })
+    }
+
+    fn matches(&self, record: &Map<String, Value>) -> bool {
+        let ts = record.get(TS_FIELD).and_then(Value::as_u64);
+        if self.since.is_some_and(|since| ts.is_none_or(|ts| ts < since)) {
+            return false;
+        }
+        if self.until.is_some_and(|until| ts.is_none_or(|ts| ts >= until)) {
+            return false;
+        }
+        if !self.levels.is_empty() {
+            let level = record.get("level").and_then(Value::as_str).map(|l| l.to_lowercase());
+            if !level.is_some_and(|level| self.levels.contains(&level)) {
+                return false;
+            }
+        }
+        self.fields.iter().all(|(key, expect)| {
+            lookup(record, key).is_some_and(|value| match value {
+                Value::String(s) => s == expect,
+                value => serde_json::from_str::<Value>(expect).is_ok_and(|expect| expect == *value),
+            })
+        })
+    }
+}
+
+/// 按照 a.b.c 查找嵌套字段, 优先匹配完整的 key
+fn lookup<'a>(record: &'a Map<String, Value>, key: &str) -> Option<&'a Value> {
+    if let Some(value) = record.get(key) {
+        return Some(value);
+    }
+    let (head, rest) = key.split_once('.')?;
+    match record.get(head)? {
+        Value::Object(child) => lookup(child, rest),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct QueryPage {
+    records: Vec<Map<String, Value>>,
+    /// 下一页的 offset, 已读到文件末尾时为空
+    next: Option<u64>,
+}
+
+/// 从 offset 开始顺序扫描日志, 返回最多 limit 条匹配的记录
+/// - 不是 JSON 对象的行跳过
+/// - 末尾没有换行的行可能正在写入, 留到下一页
+async fn query(cache: &WebCache, channel: &str, name: &str, offset: u64, limit: usize, filter: &Filter) -> super::WebResult<QueryPage> {
+    let path = cache.open_object_path(channel, name)?;
+    let mut file = fs::File::open(&path).await?;
+    let len = file.metadata().await?.len();
+    let mut pos = offset.min(len);
+    file.seek(SeekFrom::Start(pos)).await?;
+    let mut reader = BufReader::new(file);
+    let mut records = vec![];
+    let mut line = Vec::new();
+    while records.len() < limit {
+        line.clear();
+        let n = reader.read_until(b'\n', &mut line).await?;
+        if n == 0 || !line.ends_with(b"\n") {
+            break;
+        }
+        pos += n as u64;
+        if let Ok(Value::Object(record)) = serde_json::from_slice::<Value>(&line) {
+            if filter.matches(&record) {
+                records.push(record);
+            }
+        }
+    }
+    let next = (pos < len).then_some(pos);
+    Ok(QueryPage { records, next })
+}
+
+/// 1. query structured log
+/// - url
+///     - "/onlinelog/query"
+///     - "/onlinelog/query/{channel}/{name}"
+/// - method: GET
+/// - params:
+///     - channel: string, required
+///     - name: string, required
+///     - since: u64, optional, 接收时间 >= since (毫秒)
+///     - until: u64, optional, 接收时间 < until (毫秒)
+///     - level: string, optional, 逗号分隔, 忽略大小写匹配 level 字段
+///     - eq: string, optional, 可以重复, key:value, 字段等于 value, 嵌套字段使用 a.b
+///     - offset: u64, optional, 从指定的字节偏移量开始扫描, 默认 0
+///     - limit: usize, optional, 默认 100, 最大 1000
+/// - response json
+///     - {"code":0,"msg":"ok","ok":true,"data":{"records":[{...,"_ts":1718000000000,"_ip":"127.0.0.1"}],"next":1024}}
+#[allow(clippy::too_many_arguments)]
+#[get("/query?<channel>&<name>&<since>&<until>&<level>&<eq>&<offset>&<limit>")]
+async fn query_log1(
+    channel: &str,
+    name: &str,
+    since: Option<u64>,
+    until: Option<u64>,
+    level: Option<&str>,
+    eq: Vec<&str>,
+    offset: Option<u64>,
+    limit: Option<usize>,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<QueryPage>>> {
+    auth.check_pass_root()?;
+    let filter = Filter::new(since, until, level, eq)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let page = query(cache, channel, name, offset.unwrap_or_default(), limit, &filter).await?;
+    Ok(Json(ResultBase::ok(page)))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/query/<channel>/<name>?<since>&<until>&<level>&<eq>&<offset>&<limit>")]
+async fn query_log2(
+    channel: &str,
+    name: &str,
+    since: Option<u64>,
+    until: Option<u64>,
+    level: Option<&str>,
+    eq: Vec<&str>,
+    offset: Option<u64>,
+    limit: Option<usize>,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<QueryPage>>> {
+    auth.check_pass_root()?;
+    let filter = Filter::new(since, until, level, eq)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let page = query(cache, channel, name, offset.unwrap_or_default(), limit, &filter).await?;
+    Ok(Json(ResultBase::ok(page)))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![query_log1, query_log2]
+}
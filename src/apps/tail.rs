@@ -0,0 +1,316 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use super::auth::TokenAuth;
+use super::objects;
+use super::state::WebCache;
+
+use rocket::response::stream::{Event, EventStream};
+use rocket::tokio::fs;
+use rocket::tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+use rocket::{get, State};
+
+/// 每次最多读取的字节数
+const READ_CHUNK: u64 = 1024 * 1024;
+
+/// 检查文件是否有新内容的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// 追加写入没有 hold 时每次上传后都会关闭句柄, 句柄关闭后继续等待新内容的默认秒数
+const DEFAULT_APPEND_IDLE: u64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TailMode {
+    /// 每个事件是一行, 不包含换行符
+    Lines,
+    /// 每个事件是新追加的内容
+    Bytes,
+}
+
+impl TailMode {
+    fn parse(mode: Option<&str>) -> super::WebResult<Self> {
+        match mode.unwrap_or("lines") {
+            "lines" => Ok(Self::Lines),
+            "bytes" => Ok(Self::Bytes),
+            mode => Err(super::WebError::new(format!("unsupported tail mode: {mode}"))),
+        }
+    }
+}
+
+/// 写入文件的句柄, 句柄关闭后 tail 结束
+enum TailHandle {
+    /// "/storage/append" 打开的对象
+    Append(String, String),
+    /// "/onlinelog/upload" 打开的日志
+    Log(String, String),
+}
+
+impl TailHandle {
+    async fn is_open(&self, cache: &WebCache) -> bool {
+        match self {
+            TailHandle::Append(bucket, name) => cache.is_appending(bucket, name).await,
+            TailHandle::Log(channel, name) => cache.is_log_open(channel, name).await,
+        }
+    }
+}
+
+/// 倒数第 n 行的起始位置, 文件末尾的换行不计入
+async fn last_lines_offset(path: &Path, len: u64, n: usize) -> std::io::Result<u64> {
+    if n == 0 {
+        return Ok(len);
+    }
+    let mut file = fs::File::open(path).await?;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut found = 0;
+    let mut end = len;
+    while end > 0 {
+        let start = end.saturating_sub(buf.len() as u64);
+        let chunk = &mut buf[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start)).await?;
+        file.read_exact(chunk).await?;
+        for (i, b) in chunk.iter().enumerate().rev() {
+            let pos = start + i as u64;
+            if *b == b'\n' && pos + 1 != len {
+                found += 1;
+                if found == n {
+                    return Ok(pos + 1);
+                }
+            }
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+/// 开始读取的位置
+/// - offset: 从指定位置开始, 超出文件大小时从末尾开始
+/// - lines: 从倒数第 N 行开始
+/// - 都为空时从文件末尾开始, 只输出之后追加的内容
+async fn start_offset(path: &Path, offset: Option<u64>, lines: Option<usize>) -> std::io::Result<u64> {
+    let len = fs::metadata(path).await?.len();
+    match (offset, lines) {
+        (Some(offset), _) => Ok(offset.min(len)),
+        (None, Some(n)) => last_lines_offset(path, len, n).await,
+        (None, None) => Ok(len),
+    }
+}
+
+async fn read_from(path: &Path, pos: u64, len: u64) -> std::io::Result<Vec<u8>> {
+    let mut file = fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(pos)).await?;
+    let mut bytes = Vec::new();
+    file.take((len - pos).min(READ_CHUNK)).read_to_end(&mut bytes).await?;
+    Ok(bytes)
+}
+
+/// 将已读取的内容转换为事件, 事件 id 为该事件结束位置的偏移量, 可以作为 offset 继续 tail
+/// - lines: 不完整的最后一行保留在 pending 中, eof 时全部输出
+/// - bytes: 不完整的 utf8 字符保留在 pending 中
+fn drain_events(pending: &mut Vec<u8>, pos: u64, mode: TailMode, eof: bool) -> Vec<Event> {
+    let base = pos - pending.len() as u64;
+    let mut events = vec![];
+    match mode {
+        TailMode::Lines => {
+            let mut consumed = 0;
+            while let Some(idx) = pending[consumed..].iter().position(|b| *b == b'\n') {
+                let line = &pending[consumed..consumed + idx];
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                consumed += idx + 1;
+                let id = base + consumed as u64;
+                events.push(Event::data(String::from_utf8_lossy(line).into_owned()).id(id.to_string()));
+            }
+            if eof && consumed < pending.len() {
+                events.push(Event::data(String::from_utf8_lossy(&pending[consumed..]).into_owned()).id(pos.to_string()));
+                consumed = pending.len();
+            }
+            pending.drain(..consumed);
+        }
+        TailMode::Bytes => {
+            let valid = match std::str::from_utf8(pending) {
+                Ok(_) => pending.len(),
+                Err(err) if err.error_len().is_none() && !eof => err.valid_up_to(),
+                Err(_) => pending.len(),
+            };
+            if valid > 0 {
+                let id = base + valid as u64;
+                events.push(Event::data(String::from_utf8_lossy(&pending[..valid]).into_owned()).id(id.to_string()));
+                pending.drain(..valid);
+            }
+        }
+    }
+    events
+}
+
+/// 输出文件新追加的内容, 写入句柄关闭并且读取完所有内容后发送 eof 事件并结束
+/// - 句柄关闭后继续等待 idle, 期间有新内容或重新打开时继续输出, 客户端断开时结束
+/// - 文件被截断或替换为更小的文件时发送 truncate 事件, 从头开始读取
+fn tail_stream<'r>(
+    cache: &'r WebCache,
+    path: PathBuf,
+    handle: TailHandle,
+    mut pos: u64,
+    mode: TailMode,
+    idle: Duration,
+) -> EventStream![Event + 'r] {
+    EventStream! {
+        let mut interval = rocket::tokio::time::interval(POLL_INTERVAL);
+        let mut pending = Vec::new();
+        // 最近一次读到新内容或句柄打开的时间
+        let mut active = Instant::now();
+        loop {
+            // 先检查句柄再读取, 关闭前写入的内容一定会被读到
+            let open = handle.is_open(cache).await;
+            if open {
+                active = Instant::now();
+            }
+            let len = fs::metadata(&path).await.map(|m| m.len()).unwrap_or_default();
+            if len < pos {
+                pos = 0;
+                pending.clear();
+                yield Event::data("").event("truncate").id("0");
+            }
+            if len > pos {
+                match read_from(&path, pos, len).await {
+                    Ok(bytes) => {
+                        active = Instant::now();
+                        pos += bytes.len() as u64;
+                        pending.extend(bytes);
+                        for event in drain_events(&mut pending, pos, mode, false) {
+                            yield event;
+                        }
+                    }
+                    Err(err) => {
+                        yield Event::data(err.to_string()).event("error");
+                        break;
+                    }
+                }
+                continue;
+            }
+            if !open && active.elapsed() >= idle {
+                for event in drain_events(&mut pending, pos, mode, true) {
+                    yield event;
+                }
+                yield Event::data("").event("eof").id(pos.to_string());
+                break;
+            }
+            interval.tick().await;
+        }
+    }
+}
+
+async fn tail<'r>(
+    cache: &'r WebCache,
+    path: PathBuf,
+    handle: TailHandle,
+    offset: Option<u64>,
+    lines: Option<usize>,
+    mode: Option<&str>,
+    idle: u64,
+) -> super::WebResult<EventStream![Event + 'r]> {
+    let mode = TailMode::parse(mode)?;
+    let pos = start_offset(&path, offset, lines).await?;
+    Ok(tail_stream(cache, path, handle, pos, mode, Duration::from_secs(idle)))
+}
+
+/// 1. tail storage file
+/// - url
+///     - "/storage/tail"
+///     - "/storage/tail/{bucket}/{name}"
+/// - method: GET
+/// - params:
+///     - bucket: string, required
+///     - name: string, required
+///     - offset: u64, optional, 从指定的字节偏移量开始
+///     - lines: usize, optional, 从倒数第 N 行开始
+///     - mode: string, optional, lines | bytes, 默认 lines
+///     - idle: u64, optional, 追加写入的句柄关闭后继续等待新内容的秒数, 默认 30
+/// - response: sse, 每个事件是新的一行或新追加的内容, 事件 id 为读取到的偏移量
+///     - 追加写入关闭并且 idle 秒内没有新内容时发送 event: eof 并结束
+#[allow(clippy::too_many_arguments)]
+#[get("/tail?<bucket>&<name>&<offset>&<lines>&<mode>&<idle>")]
+async fn tail_file1<'r>(
+    bucket: &str,
+    name: &str,
+    offset: Option<u64>,
+    lines: Option<usize>,
+    mode: Option<&str>,
+    idle: Option<u64>,
+    auth: TokenAuth,
+    cache: &'r State<WebCache>,
+) -> super::WebResult<EventStream![Event + 'r]> {
+    auth.check_pass_root()?;
+    let path = cache.open_object_path(bucket, name)?;
+    let handle = TailHandle::Append(bucket.to_string(), name.to_string());
+    tail(cache, path, handle, offset, lines, mode, idle.unwrap_or(DEFAULT_APPEND_IDLE)).await
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/tail/<bucket>/<name..>?<offset>&<lines>&<mode>&<idle>")]
+async fn tail_file2<'r>(
+    bucket: &str,
+    name: PathBuf,
+    offset: Option<u64>,
+    lines: Option<usize>,
+    mode: Option<&str>,
+    idle: Option<u64>,
+    auth: TokenAuth,
+    cache: &'r State<WebCache>,
+) -> super::WebResult<EventStream![Event + 'r]> {
+    auth.check_pass_root()?;
+    let name = objects::object_key(&name)?;
+    let path = cache.open_object_path(bucket, &name)?;
+    let handle = TailHandle::Append(bucket.to_string(), name);
+    tail(cache, path, handle, offset, lines, mode, idle.unwrap_or(DEFAULT_APPEND_IDLE)).await
+}
+
+/// 1. tail online log
+/// - url
+///     - "/onlinelog/tail"
+///     - "/onlinelog/tail/{channel}/{name}"
+/// - method: GET
+/// - params:
+///     - channel: string, required
+///     - name: string, required
+///     - offset, lines, mode: 与 "/storage/tail" 相同
+/// - response: sse, "/onlinelog/close" 关闭日志后发送 event: eof 并结束
+#[allow(clippy::too_many_arguments)]
+#[get("/tail?<channel>&<name>&<offset>&<lines>&<mode>")]
+async fn tail_log1<'r>(
+    channel: &str,
+    name: &str,
+    offset: Option<u64>,
+    lines: Option<usize>,
+    mode: Option<&str>,
+    auth: TokenAuth,
+    cache: &'r State<WebCache>,
+) -> super::WebResult<EventStream![Event + 'r]> {
+    auth.check_pass_root()?;
+    let path = cache.open_object_path(channel, name)?;
+    let handle = TailHandle::Log(channel.to_string(), name.to_string());
+    tail(cache, path, handle, offset, lines, mode, 0).await
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/tail/<channel>/<name>?<offset>&<lines>&<mode>")]
+async fn tail_log2<'r>(
+    channel: &str,
+    name: &str,
+    offset: Option<u64>,
+    lines: Option<usize>,
+    mode: Option<&str>,
+    auth: TokenAuth,
+    cache: &'r State<WebCache>,
+) -> super::WebResult<EventStream![Event + 'r]> {
+    auth.check_pass_root()?;
+    let path = cache.open_object_path(channel, name)?;
+    let handle = TailHandle::Log(channel.to_string(), name.to_string());
+    tail(cache, path, handle, offset, lines, mode, 0).await
+}
+
+pub fn storage_routes() -> Vec<rocket::Route> {
+    rocket::routes![tail_file1, tail_file2]
+}
+
+pub fn log_routes() -> Vec<rocket::Route> {
+    rocket::routes![tail_log1, tail_log2]
+}
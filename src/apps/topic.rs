@@ -0,0 +1,33 @@
+use super::state::WebCache;
+
+use rocket::tokio::sync::broadcast;
+
+/// 每个订阅者最多缓存的消息数量, 超过时丢弃最旧的消息
+const TOPIC_CAPACITY: usize = 1024;
+
+/// 向主题发布消息, 返回收到消息的订阅者数量
+pub async fn publish(cache: &WebCache, topic: &str, msg: Vec<u8>) -> usize {
+    let mut topics = cache.topics.lock().await;
+    let Some(sender) = topics.get(topic) else {
+        return 0;
+    };
+    match sender.send(msg) {
+        Ok(receivers) => receivers,
+        Err(_) => {
+            // 所有订阅者都已经退出
+            topics.remove(topic);
+            0
+        }
+    }
+}
+
+/// 订阅主题, 只能收到订阅之后发布的消息
+pub async fn subscribe(cache: &WebCache, topic: &str) -> broadcast::Receiver<Vec<u8>> {
+    cache
+        .topics
+        .lock()
+        .await
+        .entry(topic.to_string())
+        .or_insert_with(|| broadcast::channel(TOPIC_CAPACITY).0)
+        .subscribe()
+}
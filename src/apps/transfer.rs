@@ -0,0 +1,217 @@
+use std::path::{Path, PathBuf};
+
+use super::auth::TokenAuth;
+use super::objects;
+use super::state::WebCache;
+use super::types::ResultBase;
+use super::{blobs, checksum, notify, quota, versions};
+use crate::config::StorageAction;
+
+use rocket::serde::json::Json;
+use rocket::tokio::fs;
+use rocket::{get, State};
+
+/// 把源文件放到目标位置旁的临时文件中, 之后再 rename 到目标位置
+/// - 同一文件系统内使用硬链接, 之后覆盖或追加写入时会断开链接, 不影响另一方
+/// - 硬链接失败(例如跨文件系统)时复制内容
+/// - 返回是否复制了内容
+async fn stage_file(src: &Path, tmp: &Path) -> std::io::Result<bool> {
+    match fs::hard_link(src, tmp).await {
+        Ok(_) => Ok(false),
+        Err(err) => {
+            log::debug!("hard link {} failed: {err}, copy content", src.display());
+            if let Err(err) = fs::copy(src, tmp).await {
+                let _ = fs::remove_file(tmp).await;
+                return Err(err);
+            }
+            Ok(true)
+        }
+    }
+}
+
+/// 在 bucket/name 之间复制或移动对象
+/// - 目标已存在且 overwrite 不为 true 时返回 409
+/// - 源或目标正在被追加写入时返回 409
+/// - 目标 bucket 开启版本管理时, 被覆盖的内容归档为历史版本
+async fn transfer(
+    cache: &WebCache,
+    src: (&str, &str),
+    dst: (&str, &str),
+    overwrite: bool,
+    remove_source: bool,
+) -> super::WebResult<()> {
+    let (src_bucket, src_name) = src;
+    let (dst_bucket, dst_name) = dst;
+    if src == dst {
+        return Err(super::WebError::new("source and destination are the same object"));
+    }
+    let src_path = cache.open_object_path(src_bucket, src_name)?;
+    let dst_path = cache.open_object_path(dst_bucket, dst_name)?;
+    for (bucket, name) in [src, dst] {
+        if cache.is_appending(bucket, name).await {
+            return Err(super::WebError::Conflict(format!("{bucket}/{name} is held open for append")));
+        }
+    }
+    let meta = fs::metadata(&src_path).await?;
+    if !meta.is_file() {
+        return Err(super::WebError::new(format!("object not found: {src_bucket}/{src_name}")));
+    }
+    let src_dir = cache.open_data_dir(src_bucket)?;
+    let dst_dir = cache.open_data_dir(dst_bucket)?;
+    let (replaced, replaced_digest) = match fs::metadata(&dst_path).await {
+        Ok(dst_meta) if dst_meta.is_file() => {
+            let digest = checksum::load(&dst_dir, dst_name, &dst_meta).await.map(|c| c.sha256);
+            (Some(dst_meta.len()), digest)
+        }
+        Ok(_) => return Err(super::WebError::Conflict(format!("{dst_bucket}/{dst_name} is a directory"))),
+        Err(_) => (None, None),
+    };
+    if replaced.is_some() && !overwrite {
+        return Err(super::WebError::Conflict(format!("{dst_bucket}/{dst_name} already exists")));
+    }
+    // bucket 内移动不会增加用量
+    if !(remove_source && src_bucket == dst_bucket) {
+        let limit = quota::write_limit(cache, dst_bucket, replaced).await?;
+        if limit.is_some_and(|limit| meta.len() > limit) {
+            return Err(quota::exceeded(dst_bucket, limit.unwrap_or_default()));
+        }
+    }
+    let digest = checksum::load(&src_dir, src_name, &meta).await;
+
+    if let Some(data_dir) = dst_path.parent() {
+        fs::create_dir_all(data_dir).await?;
+    }
+    // 先准备好新内容再归档和替换目标, 任何一步失败时目标保持不变
+    let tmp = blobs::temp_path(&dst_path, &format!("{:016x}.transfer", rand::random::<u64>()));
+    let copied = stage_file(&src_path, &tmp).await?;
+    let replace = async {
+        versions::archive_current(cache, dst_bucket, dst_name).await?;
+        fs::rename(&tmp, &dst_path).await
+    };
+    if let Err(err) = replace.await {
+        let _ = fs::remove_file(&tmp).await;
+        return Err(err.into());
+    }
+    let remove_source = remove_source && {
+        match fs::remove_file(&src_path).await {
+            Ok(_) => true,
+            Err(err) => {
+                log::warn!("move {src_bucket}/{src_name} remove source error: {err}");
+                false
+            }
+        }
+    };
+    log::debug!("transfer {src_bucket}/{src_name} -> {dst_bucket}/{dst_name}, remove source: {remove_source}");
+
+    let bytes = meta.len() as i64 - replaced.unwrap_or_default() as i64;
+    quota::record(cache, dst_bucket, bytes, replaced.map_or(1, |_| 0)).await;
+    if let Some(sha256) = replaced_digest {
+        blobs::release(cache, &sha256).await;
+    }
+    checksum::remove(&dst_dir, dst_name).await;
+    if let Some(checksums) = digest {
+        checksum::save(cache, dst_bucket, dst_name, checksums.md5, checksums.sha256.clone()).await?;
+        if copied && cache.dedup {
+            blobs::dedup(cache, &dst_path, &checksums.sha256).await?;
+        }
+    }
+    if remove_source {
+        quota::record(cache, src_bucket, -(meta.len() as i64), -1).await;
+        checksum::remove(&src_dir, src_name).await;
+        objects::remove_empty_parents(&src_dir, &src_path).await;
+        notify::publish(cache, src_bucket, src_name, meta.len(), StorageAction::Delete).await;
+    }
+    notify::publish(cache, dst_bucket, dst_name, meta.len(), StorageAction::Put).await;
+    Ok(())
+}
+
+/// 1. copy
+/// - url
+///     - "/storage/copy"
+///     - "/storage/copy/{bucket}/{name}"
+/// - method: GET
+/// - params:
+///     - bucket: string, required
+///     - name: string, required
+///     - to_bucket: string, optional, 默认与 bucket 相同
+///     - to_name: string, optional, 默认与 name 相同
+///     - overwrite: bool, optional, 目标已存在时是否覆盖, 默认 false
+/// - response json
+///     - {"code":0,"msg":"ok","ok":true,"data":true}
+#[get("/copy?<bucket>&<name>&<to_bucket>&<to_name>&<overwrite>")]
+async fn copy_file1(
+    bucket: &str,
+    name: &str,
+    to_bucket: Option<&str>,
+    to_name: Option<&str>,
+    overwrite: Option<bool>,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<bool>>> {
+    auth.check_pass_root()?;
+    let dst = (to_bucket.unwrap_or(bucket), to_name.unwrap_or(name));
+    transfer(cache, (bucket, name), dst, overwrite.unwrap_or_default(), false).await?;
+    Ok(Json(ResultBase::ok(true)))
+}
+
+#[get("/copy/<bucket>/<name..>?<to_bucket>&<to_name>&<overwrite>")]
+async fn copy_file2(
+    bucket: &str,
+    name: PathBuf,
+    to_bucket: Option<&str>,
+    to_name: Option<&str>,
+    overwrite: Option<bool>,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<bool>>> {
+    auth.check_pass_root()?;
+    let name = objects::object_key(&name)?;
+    let dst = (to_bucket.unwrap_or(bucket), to_name.unwrap_or(&name));
+    transfer(cache, (bucket, &name), dst, overwrite.unwrap_or_default(), false).await?;
+    Ok(Json(ResultBase::ok(true)))
+}
+
+/// 2. move
+/// - url
+///     - "/storage/move"
+///     - "/storage/move/{bucket}/{name}"
+/// - method: GET
+/// - params: 与 copy 相同
+/// - response json
+///     - {"code":0,"msg":"ok","ok":true,"data":true}
+#[get("/move?<bucket>&<name>&<to_bucket>&<to_name>&<overwrite>")]
+async fn move_file1(
+    bucket: &str,
+    name: &str,
+    to_bucket: Option<&str>,
+    to_name: Option<&str>,
+    overwrite: Option<bool>,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<bool>>> {
+    auth.check_pass_root()?;
+    let dst = (to_bucket.unwrap_or(bucket), to_name.unwrap_or(name));
+    transfer(cache, (bucket, name), dst, overwrite.unwrap_or_default(), true).await?;
+    Ok(Json(ResultBase::ok(true)))
+}
+
+#[get("/move/<bucket>/<name..>?<to_bucket>&<to_name>&<overwrite>")]
+async fn move_file2(
+    bucket: &str,
+    name: PathBuf,
+    to_bucket: Option<&str>,
+    to_name: Option<&str>,
+    overwrite: Option<bool>,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<bool>>> {
+    auth.check_pass_root()?;
+    let name = objects::object_key(&name)?;
+    let dst = (to_bucket.unwrap_or(bucket), to_name.unwrap_or(&name));
+    transfer(cache, (bucket, &name), dst, overwrite.unwrap_or_default(), true).await?;
+    Ok(Json(ResultBase::ok(true)))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![copy_file1, copy_file2, move_file1, move_file2]
+}
@@ -0,0 +1,331 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::auth::TokenAuth;
+use super::{blobs, checksum, objects, quota};
+use super::state::WebCache;
+use super::types::ResultBase;
+
+use rocket::fs::NamedFile;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::tokio::fs;
+use rocket::tokio::io::{self as io, AsyncWriteExt};
+use rocket::{get, State};
+
+/// bucket 内保存历史版本的隐藏目录
+pub const VERSIONS_DIR: &str = ".versions";
+
+/// 对象的一个历史版本
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct VersionEntry {
+    /// 版本号, 归档时的 unix 时间戳(微秒)
+    pub version_id: String,
+    pub size: u64,
+    /// 该版本内容的修改时间, unix 时间戳(秒)
+    pub mtime: u64,
+}
+
+fn unix_micros(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64
+}
+
+/// 对象历史版本所在的目录: {bucket}/.versions/{name}
+fn versions_dir(cache: &WebCache, bucket: &str, name: &str) -> std::io::Result<PathBuf> {
+    objects::check_object_key(name)?;
+    let mut path = cache.open_data_dir(bucket)?;
+    path.push(VERSIONS_DIR);
+    path.push(name);
+    Ok(path)
+}
+
+/// 版本号只允许数字, 避免拼接出 bucket 之外的路径
+fn version_path(cache: &WebCache, bucket: &str, name: &str, version_id: &str) -> super::WebResult<PathBuf> {
+    let id: u64 = version_id
+        .parse()
+        .map_err(|_| super::WebError::new(format!("invalid version id: {version_id}")))?;
+    Ok(versions_dir(cache, bucket, name)?.join(id.to_string()))
+}
+
+/// 列出对象的历史版本, 最新的版本在前
+async fn read_versions(dir: &Path) -> std::io::Result<Vec<VersionEntry>> {
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+    let mut versions = vec![];
+    while let Some(entry) = entries.next_entry().await? {
+        let Some(version_id) = entry.file_name().to_str().map(|s| s.to_string()) else {
+            continue;
+        };
+        if version_id.parse::<u64>().is_err() {
+            continue;
+        }
+        let meta = entry.metadata().await?;
+        if !meta.is_file() {
+            continue;
+        }
+        let mtime = meta.modified().map(unix_micros).unwrap_or_default() / 1_000_000;
+        versions.push(VersionEntry { version_id, size: meta.len(), mtime });
+    }
+    versions.sort_by_key(|v| std::cmp::Reverse(v.version_id.parse::<u64>().unwrap_or_default()));
+    Ok(versions)
+}
+
+/// 按照 max-versions / max-age-days 清理历史版本, 清空后删除空目录直到 bucket 目录
+async fn prune_versions(bucket_dir: &Path, dir: &Path, policy: &crate::config::ConfigVersioning) -> std::io::Result<()> {
+    let versions = read_versions(dir).await?;
+    // 天数过大时没有版本会过期
+    let expire_before = policy.max_age_days.map(|days| {
+        SystemTime::now()
+            .checked_sub(Duration::from_secs(days.saturating_mul(24 * 3600)))
+            .map_or(0, unix_micros)
+    });
+    for (idx, version) in versions.iter().enumerate() {
+        let too_many = policy.max_versions.is_some_and(|max| idx >= max);
+        let too_old = expire_before.is_some_and(|t| version.version_id.parse::<u64>().unwrap_or_default() < t);
+        if too_many || too_old {
+            log::debug!("prune version {}/{}", dir.display(), version.version_id);
+            fs::remove_file(dir.join(&version.version_id)).await?;
+        }
+    }
+    if fs::remove_dir(dir).await.is_ok() {
+        objects::remove_empty_parents(bucket_dir, dir).await;
+    }
+    Ok(())
+}
+
+/// 清理 bucket 中所有对象过期的历史版本, 由生命周期后台任务定期执行
+pub async fn prune_bucket(cache: &WebCache, bucket: &str) -> std::io::Result<()> {
+    let Some(policy) = cache.versioning_policy(bucket).filter(|p| p.max_age_days.is_some()) else {
+        return Ok(());
+    };
+    let bucket_dir = cache.open_data_dir(bucket)?;
+    let root = bucket_dir.join(VERSIONS_DIR);
+    if !fs::try_exists(&root).await.unwrap_or(false) {
+        return Ok(());
+    }
+    let mut dirs = objects::walk_objects(&root, &root)
+        .await?
+        .into_iter()
+        .filter_map(|(key, _)| Path::new(&key).parent().map(|p| root.join(p)))
+        .collect::<Vec<_>>();
+    dirs.sort();
+    dirs.dedup();
+    for dir in dirs {
+        prune_versions(&bucket_dir, &dir, policy).await?;
+    }
+    Ok(())
+}
+
+/// 覆盖写入前将当前内容归档为历史版本
+/// - bucket 未开启版本管理或对象不存在时不做任何处理
+/// - 返回新版本的版本号
+pub async fn archive_current(cache: &WebCache, bucket: &str, name: &str) -> std::io::Result<Option<String>> {
+    let Some(policy) = cache.versioning_policy(bucket) else {
+        return Ok(None);
+    };
+    let path = cache.open_object_path(bucket, name)?;
+    if !fs::metadata(&path).await.is_ok_and(|m| m.is_file()) {
+        return Ok(None);
+    }
+    let dir = versions_dir(cache, bucket, name)?;
+    fs::create_dir_all(&dir).await?;
+    let mut id = unix_micros(SystemTime::now());
+    while fs::try_exists(dir.join(id.to_string())).await.unwrap_or(false) {
+        id += 1;
+    }
+    fs::rename(&path, dir.join(id.to_string())).await?;
+    prune_versions(&cache.open_data_dir(bucket)?, &dir, policy).await?;
+    Ok(Some(id.to_string()))
+}
+
+async fn list_versions(cache: &WebCache, bucket: &str, name: &str) -> super::WebResult<Vec<VersionEntry>> {
+    let dir = versions_dir(cache, bucket, name)?;
+    Ok(read_versions(&dir).await?)
+}
+
+/// 用历史版本覆盖当前内容, 当前内容同样会被归档
+/// - 与上传相同检查配额, 重新计算摘要, 开启去重时写入 blob 存储
+async fn restore_version(cache: &WebCache, bucket: &str, name: &str, version_id: &str) -> super::WebResult<()> {
+    let src = version_path(cache, bucket, name, version_id)?;
+    let Ok(src_meta) = fs::metadata(&src).await else {
+        return Err(super::WebError::new(format!("version not found: {name}@{version_id}")));
+    };
+    let path = cache.open_object_path(bucket, name)?;
+    let bucket_dir = cache.open_data_dir(bucket)?;
+    let (replaced, replaced_digest) = match fs::metadata(&path).await {
+        Ok(meta) if meta.is_file() => {
+            let digest = checksum::load(&bucket_dir, name, &meta).await.map(|c| c.sha256);
+            (Some(meta.len()), digest)
+        }
+        _ => (None, None),
+    };
+    let limit = quota::write_limit(cache, bucket, replaced).await?;
+    if let Some(limit) = limit.filter(|limit| src_meta.len() > *limit) {
+        return Err(quota::exceeded(bucket, limit));
+    }
+    if let Some(data_dir) = path.parent() {
+        if !fs::try_exists(data_dir).await.unwrap_or(false) {
+            fs::create_dir_all(data_dir).await?
+        }
+    }
+    // 先复制到临时文件, 避免归档当前内容时历史版本被清理
+    let tmp = blobs::temp_path(&path, &format!("{:016x}.restore", rand::random::<u64>()));
+    let copied = async {
+        let mut writer = checksum::HashWriter::new(fs::File::create(&tmp).await?);
+        let restored = io::copy(&mut fs::File::open(&src).await?, &mut writer).await?;
+        let (mut file, md5, sha256) = writer.finish();
+        file.flush().await?;
+        let bytes = restored as i64 - replaced.unwrap_or_default() as i64;
+        quota::commit(cache, bucket, bytes, replaced.map_or(1, |_| 0)).await?;
+        Ok::<_, super::WebError>((restored, md5, sha256))
+    }
+    .await;
+    let (restored, md5, sha256) = match copied {
+        Ok(copied) => copied,
+        Err(err) => {
+            let _ = fs::remove_file(&tmp).await;
+            return Err(err);
+        }
+    };
+    let replace = async {
+        archive_current(cache, bucket, name).await?;
+        fs::rename(&tmp, &path).await
+    };
+    if let Err(err) = replace.await {
+        let _ = fs::remove_file(&tmp).await;
+        let bytes = restored as i64 - replaced.unwrap_or_default() as i64;
+        quota::record(cache, bucket, -bytes, -replaced.map_or(1, |_| 0)).await;
+        return Err(err.into());
+    }
+    if let Some(sha256) = replaced_digest {
+        blobs::release(cache, &sha256).await;
+    }
+    checksum::save(cache, bucket, name, md5, sha256.clone()).await?;
+    if cache.dedup {
+        blobs::dedup(cache, &path, &sha256).await?;
+    }
+    super::notify::publish(cache, bucket, name, restored, crate::config::StorageAction::Put).await;
+    Ok(())
+}
+
+/// 1. versions
+/// - url
+///     - "/storage/versions"
+///     - "/storage/versions/{bucket}/{name}"
+/// - method: GET
+/// - params:
+///     - bucket: string, required
+///     - name: string, required
+/// - response json
+///     - {"code":0,"msg":"ok","ok":true,"data":[{"version_id":"1718000000000000","size":12,"mtime":1718000000}]}
+#[get("/versions?<bucket>&<name>")]
+async fn list_versions1(
+    bucket: &str,
+    name: &str,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<Vec<VersionEntry>>>> {
+    auth.check_pass_root()?;
+    let versions = list_versions(cache, bucket, name).await?;
+    Ok(Json(ResultBase::ok(versions)))
+}
+
+#[get("/versions/<bucket>/<name..>")]
+async fn list_versions2(
+    bucket: &str,
+    name: PathBuf,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<Vec<VersionEntry>>>> {
+    auth.check_pass_root()?;
+    let versions = list_versions(cache, bucket, &objects::object_key(&name)?).await?;
+    Ok(Json(ResultBase::ok(versions)))
+}
+
+/// 2. version
+/// - url
+///     - "/storage/version"
+///     - "/storage/version/{bucket}/{name}"
+/// - method: GET
+/// - params:
+///     - bucket: string, required
+///     - name: string, required
+///     - version_id: string, required
+/// - response status: 200 body: any bytes
+#[get("/version?<bucket>&<name>&<version_id>")]
+async fn download_version1(
+    bucket: &str,
+    name: &str,
+    version_id: &str,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<NamedFile> {
+    auth.check_pass_root()?;
+    let path = version_path(cache, bucket, name, version_id)?;
+    Ok(NamedFile::open(path).await?)
+}
+
+#[get("/version/<bucket>/<name..>?<version_id>")]
+async fn download_version2(
+    bucket: &str,
+    name: PathBuf,
+    version_id: &str,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<NamedFile> {
+    auth.check_pass_root()?;
+    let path = version_path(cache, bucket, &objects::object_key(&name)?, version_id)?;
+    Ok(NamedFile::open(path).await?)
+}
+
+/// 3. restore
+/// - url
+///     - "/storage/restore"
+///     - "/storage/restore/{bucket}/{name}"
+/// - method: GET
+/// - params:
+///     - bucket: string, required
+///     - name: string, required
+///     - version_id: string, required
+/// - response json
+///     - {"code":0,"msg":"ok","ok":true,"data":true}
+#[get("/restore?<bucket>&<name>&<version_id>")]
+async fn restore_version1(
+    bucket: &str,
+    name: &str,
+    version_id: &str,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<bool>>> {
+    auth.check_pass_root()?;
+    restore_version(cache, bucket, name, version_id).await?;
+    Ok(Json(ResultBase::ok(true)))
+}
+
+#[get("/restore/<bucket>/<name..>?<version_id>")]
+async fn restore_version2(
+    bucket: &str,
+    name: PathBuf,
+    version_id: &str,
+    auth: TokenAuth,
+    cache: &State<WebCache>,
+) -> super::WebResult<Json<ResultBase<bool>>> {
+    auth.check_pass_root()?;
+    restore_version(cache, bucket, &objects::object_key(&name)?, version_id).await?;
+    Ok(Json(ResultBase::ok(true)))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![
+        list_versions1,
+        list_versions2,
+        download_version1,
+        download_version2,
+        restore_version1,
+        restore_version2,
+    ]
+}
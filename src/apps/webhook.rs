@@ -0,0 +1,424 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::ack;
+use super::auth::TokenAuth;
+use super::checksum::to_hex;
+use super::state::WebCache;
+use super::types::ResultBase;
+use crate::config::ConfigWebhook;
+
+use hmac::{Hmac, Mac};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::sync::Mutex;
+use rocket::{get, post, State};
+use sha2::Sha256;
+use tokio_util::sync::CancellationToken;
+
+/// 队列为空时检查队列的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// 重试间隔的上限
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// 正在投递的消息
+#[derive(Debug, Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct PendingDelivery {
+    /// 投递 id, 投递中的消息不会被其他消费者取到
+    pub id: u64,
+    /// 当前是第几次尝试
+    pub attempt: u32,
+    /// 下一次重试的时间
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_retry: Option<String>,
+}
+
+/// 推送的统计, 重启后清零
+#[derive(Debug, Serialize, Clone, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct DeliveryStats {
+    /// 收到 2xx 回复的消息数量
+    pub delivered: u64,
+    /// 失败的请求次数, 包括之后重试成功的请求
+    pub failed_attempts: u64,
+    /// 转入死信队列的消息数量
+    pub dead_lettered: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// 最后一次投递成功的时间
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_delivery: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending: Option<PendingDelivery>,
+}
+
+/// 推送订阅的配置和状态, 不返回 secret
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct WebhookStatus {
+    pub id: u64,
+    pub queue: String,
+    pub url: String,
+    pub has_secret: bool,
+    pub max_attempts: u32,
+    pub backoff_ms: u64,
+    pub timeout: u64,
+    pub dead_letter: String,
+    #[serde(flatten)]
+    pub stats: DeliveryStats,
+}
+
+/// 已注册的推送订阅
+#[derive(Debug)]
+pub struct Webhook {
+    config: ConfigWebhook,
+    stats: Mutex<DeliveryStats>,
+    cancel: CancellationToken,
+}
+
+impl Webhook {
+    fn dead_letter(&self) -> String {
+        self.config
+            .dead_letter
+            .clone()
+            .unwrap_or_else(|| format!("{}.dlq", self.config.queue))
+    }
+
+    async fn status(&self, id: u64) -> WebhookStatus {
+        WebhookStatus {
+            id,
+            queue: self.config.queue.clone(),
+            url: self.config.url.clone(),
+            has_secret: self.config.secret.is_some(),
+            max_attempts: self.config.max_attempts,
+            backoff_ms: self.config.backoff_ms,
+            timeout: self.config.timeout,
+            dead_letter: self.dead_letter(),
+            stats: self.stats.lock().await.clone(),
+        }
+    }
+}
+
+/// 一次请求的结果
+enum Outcome {
+    Delivered(u16),
+    /// 可以重试的失败: 网络错误, 超时, 5xx, 408, 429
+    Retry(Option<u16>, String),
+    /// 不需要重试的失败: 其他 4xx
+    Reject(u16),
+}
+
+/// 签名内容为 "{timestamp}.{body}", 结果为 "sha256={hex}"
+fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", to_hex(&mac.finalize().into_bytes()))
+}
+
+/// 第 attempt 次失败后等待的时间, 每次翻倍
+fn backoff(config: &ConfigWebhook, attempt: u32) -> Duration {
+    let factor = 1u64.checked_shl(attempt.saturating_sub(1)).unwrap_or(u64::MAX);
+    Duration::from_millis(config.backoff_ms.saturating_mul(factor)).min(MAX_BACKOFF)
+}
+
+async fn post_message(client: &reqwest::Client, hook_id: u64, config: &ConfigWebhook, delivery_id: u64, attempt: u32, msg: &[u8]) -> Outcome {
+    let timestamp = chrono::Utc::now().timestamp();
+    let mut request = client
+        .post(&config.url)
+        .header("Content-Type", "application/octet-stream")
+        .header("X-Webhook-Id", hook_id)
+        .header("X-Queue", &config.queue)
+        .header("X-Delivery-Id", delivery_id)
+        .header("X-Attempt", attempt)
+        .header("X-Timestamp", timestamp);
+    if let Some(secret) = &config.secret {
+        request = request.header("X-Signature-256", sign(secret, timestamp, msg));
+    }
+    match request.body(msg.to_vec()).send().await {
+        Ok(response) => {
+            let status = response.status();
+            let code = status.as_u16();
+            if status.is_success() {
+                Outcome::Delivered(code)
+            } else if status.is_client_error() && code != 408 && code != 429 {
+                Outcome::Reject(code)
+            } else {
+                Outcome::Retry(Some(code), format!("http status {code}"))
+            }
+        }
+        Err(err) => Outcome::Retry(None, err.to_string()),
+    }
+}
+
+/// 投递一条消息直到成功, 转入死信队列或被取消; 取消时返回 false, 消息放回队列
+async fn dispatch_one(cache: &WebCache, hook_id: u64, hook: &Webhook, client: &reqwest::Client, id: u64, msg: Vec<u8>) -> bool {
+    let config = &hook.config;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        hook.stats.lock().await.pending = Some(PendingDelivery { id, attempt, next_retry: None });
+        let outcome = rocket::tokio::select! {
+            _ = hook.cancel.cancelled() => {
+                ack::nack(cache, id, true).await;
+                return false;
+            }
+            outcome = post_message(client, hook_id, config, id, attempt, &msg) => outcome,
+        };
+        let mut stats = hook.stats.lock().await;
+        let exhausted = match outcome {
+            Outcome::Delivered(code) => {
+                stats.delivered += 1;
+                stats.last_status = Some(code);
+                stats.last_delivery = Some(chrono::Local::now().to_rfc3339());
+                stats.pending = None;
+                drop(stats);
+                ack::ack(cache, id).await;
+                return true;
+            }
+            Outcome::Reject(code) => {
+                stats.failed_attempts += 1;
+                stats.last_status = Some(code);
+                stats.last_error = Some(format!("http status {code}"));
+                true
+            }
+            Outcome::Retry(code, err) => {
+                stats.failed_attempts += 1;
+                if code.is_some() {
+                    stats.last_status = code;
+                }
+                stats.last_error = Some(err);
+                attempt >= config.max_attempts
+            }
+        };
+        if exhausted {
+            stats.dead_lettered += 1;
+            stats.pending = None;
+            drop(stats);
+            let dead_letter = hook.dead_letter();
+            log::warn!("webhook {hook_id} gives up delivery {id} after {attempt} attempts, moved to {dead_letter}");
+            // 消息已经被 nack 或 ack 时不再转入死信队列
+            if let Some(delivery) = ack::ack(cache, id).await {
+                cache.queue_push_msg(&dead_letter, delivery.msg).await;
+            }
+            return true;
+        }
+        let wait = backoff(config, attempt);
+        let next_retry = chrono::Local::now() + wait;
+        if let Some(pending) = &mut stats.pending {
+            pending.next_retry = Some(next_retry.to_rfc3339());
+        }
+        drop(stats);
+        rocket::tokio::select! {
+            _ = hook.cancel.cancelled() => {
+                ack::nack(cache, id, true).await;
+                return false;
+            }
+            _ = rocket::tokio::time::sleep(wait) => {}
+        }
+    }
+}
+
+/// 后台按顺序投递队列中的消息, 同一个推送订阅每次只投递一条
+async fn run(cache: WebCache, hook_id: u64, hook: Arc<Webhook>) {
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(hook.config.timeout.max(1)))
+        .build()
+    {
+        Ok(client) => client,
+        Err(err) => {
+            log::error!("webhook {hook_id} client error: {err}");
+            return;
+        }
+    };
+    log::info!("webhook {hook_id}: {} -> {}", hook.config.queue, hook.config.url);
+    while !hook.cancel.is_cancelled() {
+        let Some((id, msg)) = ack::pop_deliver(&cache, &hook.config.queue, None).await else {
+            rocket::tokio::select! {
+                _ = hook.cancel.cancelled() => break,
+                _ = rocket::tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+            continue;
+        };
+        if !dispatch_one(&cache, hook_id, &hook, &client, id, msg).await {
+            break;
+        }
+    }
+    log::info!("webhook {hook_id} stopped");
+}
+
+fn check_url(url: &str) -> super::WebResult<()> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(())
+    } else {
+        Err(super::WebError::new("webhook url must start with http:// or https://"))
+    }
+}
+
+/// 注册推送订阅并启动后台投递, 返回推送订阅 id
+pub async fn register(cache: &WebCache, config: ConfigWebhook) -> super::WebResult<u64> {
+    check_url(&config.url)?;
+    if config.queue.is_empty() {
+        return Err(super::WebError::new("webhook queue is required"));
+    }
+    let hook = Arc::new(Webhook {
+        config,
+        stats: Mutex::new(DeliveryStats::default()),
+        cancel: CancellationToken::new(),
+    });
+    let id = {
+        let mut webhooks = cache.webhooks.lock().await;
+        let id = webhooks.keys().next_back().map_or(1, |id| id + 1);
+        webhooks.insert(id, hook.clone());
+        id
+    };
+    rocket::tokio::spawn(run(cache.clone(), id, hook));
+    Ok(id)
+}
+
+/// 删除推送订阅, 正在投递的消息放回队列
+pub async fn unregister(cache: &WebCache, queue: &str, id: u64) -> bool {
+    let mut webhooks = cache.webhooks.lock().await;
+    match webhooks.get(&id) {
+        Some(hook) if hook.config.queue == queue => {
+            hook.cancel.cancel();
+            webhooks.remove(&id);
+            true
+        }
+        _ => false,
+    }
+}
+
+async fn list(cache: &WebCache, queue: Option<&str>) -> Vec<WebhookStatus> {
+    let webhooks = cache
+        .webhooks
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, hook)| queue.is_none_or(|queue| hook.config.queue == queue))
+        .map(|(id, hook)| (*id, hook.clone()))
+        .collect::<BTreeMap<_, _>>();
+    let mut result = Vec::with_capacity(webhooks.len());
+    for (id, hook) in webhooks {
+        result.push(hook.status(id).await);
+    }
+    result
+}
+
+/// 注册推送订阅的请求
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct WebhookRequest {
+    url: String,
+    secret: Option<String>,
+    max_attempts: Option<u32>,
+    backoff_ms: Option<u64>,
+    timeout: Option<u64>,
+    dead_letter: Option<String>,
+}
+
+impl WebhookRequest {
+    fn into_config(self, queue: &str) -> ConfigWebhook {
+        ConfigWebhook {
+            queue: queue.to_string(),
+            url: self.url,
+            secret: self.secret.filter(|s| !s.is_empty()),
+            max_attempts: self.max_attempts.unwrap_or(5).max(1),
+            backoff_ms: self.backoff_ms.unwrap_or(1000),
+            timeout: self.timeout.unwrap_or(10),
+            dead_letter: self.dead_letter.filter(|s| !s.is_empty()),
+        }
+    }
+}
+
+#[post("/queue/webhook?<queue>", data = "<request>")]
+async fn add_webhook1(
+    queue: &str,
+    request: Json<WebhookRequest>,
+    state: &State<WebCache>,
+    auth: TokenAuth,
+) -> super::WebResult<Json<ResultBase<u64>>> {
+    auth.check_pass_root()?;
+    let id = register(state, request.into_inner().into_config(queue)).await?;
+    Ok(Json(ResultBase::ok(id)))
+}
+
+#[post("/<queue>/webhook", data = "<request>")]
+async fn add_webhook2(
+    queue: &str,
+    request: Json<WebhookRequest>,
+    state: &State<WebCache>,
+    auth: TokenAuth,
+) -> super::WebResult<Json<ResultBase<u64>>> {
+    auth.check_pass_root()?;
+    let id = register(state, request.into_inner().into_config(queue)).await?;
+    Ok(Json(ResultBase::ok(id)))
+}
+
+#[get("/queue/webhook?<queue>")]
+async fn list_webhooks1(
+    queue: &str,
+    state: &State<WebCache>,
+    auth: TokenAuth,
+) -> super::WebResult<Json<ResultBase<Vec<WebhookStatus>>>> {
+    auth.check_pass_root()?;
+    Ok(Json(ResultBase::ok(list(state, Some(queue)).await)))
+}
+
+#[get("/<queue>/webhook")]
+async fn list_webhooks2(
+    queue: &str,
+    state: &State<WebCache>,
+    auth: TokenAuth,
+) -> super::WebResult<Json<ResultBase<Vec<WebhookStatus>>>> {
+    auth.check_pass_root()?;
+    Ok(Json(ResultBase::ok(list(state, Some(queue)).await)))
+}
+
+#[get("/webhooks")]
+async fn list_all_webhooks(
+    state: &State<WebCache>,
+    auth: TokenAuth,
+) -> super::WebResult<Json<ResultBase<Vec<WebhookStatus>>>> {
+    auth.check_pass_root()?;
+    Ok(Json(ResultBase::ok(list(state, None).await)))
+}
+
+#[get("/queue/webhook/del?<queue>&<id>")]
+async fn del_webhook1(
+    queue: &str,
+    id: u64,
+    state: &State<WebCache>,
+    auth: TokenAuth,
+) -> super::WebResult<Json<ResultBase<bool>>> {
+    auth.check_pass_root()?;
+    Ok(Json(ResultBase::ok(unregister(state, queue, id).await)))
+}
+
+#[get("/<queue>/webhook/del/<id>")]
+async fn del_webhook2(
+    queue: &str,
+    id: u64,
+    state: &State<WebCache>,
+    auth: TokenAuth,
+) -> super::WebResult<Json<ResultBase<bool>>> {
+    auth.check_pass_root()?;
+    Ok(Json(ResultBase::ok(unregister(state, queue, id).await)))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![
+        add_webhook1,
+        add_webhook2,
+        list_webhooks1,
+        list_webhooks2,
+        list_all_webhooks,
+        del_webhook1,
+        del_webhook2,
+    ]
+}
@@ -55,10 +55,174 @@ pub struct ConfigAuth {
     pub token: String,
 }
 
+/*
+[[storage.versioning]]
+bucket="artifacts-*"
+max-versions=10
+max-age-days=30
+*/
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ConfigVersioning {
+    /// bucket 名称, 支持 glob 通配符
+    pub bucket: String,
+    /// 每个对象最多保留的历史版本数量
+    #[serde(default,rename="max-versions",skip_serializing_if="Option::is_none")]
+    pub max_versions: Option<usize>,
+    /// 历史版本最长保留天数
+    #[serde(default,rename="max-age-days",skip_serializing_if="Option::is_none")]
+    pub max_age_days: Option<u64>,
+}
+
+/*
+[[storage.lifecycle]]
+bucket="tmp-*"
+expire-days=7
+max-objects=1000
+max-bytes=1073741824
+*/
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ConfigLifecycle {
+    /// bucket 名称, 支持 glob 通配符
+    pub bucket: String,
+    /// 删除修改时间早于 N 天的对象
+    #[serde(default,rename="expire-days",skip_serializing_if="Option::is_none")]
+    pub expire_days: Option<u64>,
+    /// 最多保留 N 个对象, 超出时删除最旧的对象
+    #[serde(default,rename="max-objects",skip_serializing_if="Option::is_none")]
+    pub max_objects: Option<usize>,
+    /// bucket 最大字节数, 超出时删除最旧的对象
+    #[serde(default,rename="max-bytes",skip_serializing_if="Option::is_none")]
+    pub max_bytes: Option<u64>,
+}
+
+/*
+[[storage.quota]]
+bucket="uploads-*"
+max-bytes=10737418240
+max-objects=10000
+*/
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ConfigQuota {
+    /// bucket 名称, 支持 glob 通配符
+    pub bucket: String,
+    /// bucket 最多占用的字节数
+    #[serde(default,rename="max-bytes",skip_serializing_if="Option::is_none")]
+    pub max_bytes: Option<u64>,
+    /// bucket 最多保存的对象数量
+    #[serde(default,rename="max-objects",skip_serializing_if="Option::is_none")]
+    pub max_objects: Option<u64>,
+}
+
+/// 触发通知的对象变化
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageAction {
+    /// 上传, 复制, 移动, 解包或恢复历史版本写入了对象
+    Put,
+    Append,
+    CloseAppend,
+    Delete,
+    DeleteBucket,
+}
+
+/*
+[[storage.notify]]
+bucket="uploads-*"
+queue="uploads-events"
+topic="uploads"
+prefix="images/"
+actions=["put","delete"]
+*/
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ConfigNotify {
+    /// bucket 名称, 支持 glob 通配符
+    pub bucket: String,
+    /// 接收事件的队列, queue 和 topic 至少设置一个
+    #[serde(default,skip_serializing_if="Option::is_none")]
+    pub queue: Option<String>,
+    /// 接收事件的发布订阅主题, 只发送给当前的订阅者
+    #[serde(default,skip_serializing_if="Option::is_none")]
+    pub topic: Option<String>,
+    /// 只通知名称以 prefix 开头的对象
+    #[serde(default,skip_serializing_if="Option::is_none")]
+    pub prefix: Option<String>,
+    /// 需要通知的变化, 为空时通知所有变化
+    #[serde(default,skip_serializing_if="Vec::is_empty")]
+    pub actions: Vec<StorageAction>,
+}
+
+/// 对象的存储方式
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// 每个对象保存为独立的文件
+    #[default]
+    Fs,
+    /// 相同内容只保存一份, 对象为共享 blob 的硬链接
+    Dedup,
+}
+
+/*
+[storage.extract]
+max-bytes=4294967296
+max-entries=10000
+*/
+fn default_extract_max_bytes() -> u64 {
+    4 * 1024 * 1024 * 1024
+}
+
+fn default_extract_max_entries() -> u64 {
+    10000
+}
+
+/// 解包上传的压缩包时的限制, bucket 有配额时同时受配额限制
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ConfigExtract {
+    /// 一次解包最多写出的字节数
+    #[serde(default="default_extract_max_bytes",rename="max-bytes")]
+    pub max_bytes: u64,
+    /// 一次解包最多处理的条目数量, 包括被忽略的条目
+    #[serde(default="default_extract_max_entries",rename="max-entries")]
+    pub max_entries: u64,
+}
+
+impl Default for ConfigExtract {
+    fn default() -> Self {
+        Self {
+            max_bytes: default_extract_max_bytes(),
+            max_entries: default_extract_max_entries(),
+        }
+    }
+}
+
+fn default_lifecycle_interval() -> u64 {
+    3600
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ConfigStorage {
     pub workspace: String,
     pub public: String,
+    #[serde(default)]
+    pub backend: StorageBackend,
+    /// 开启版本管理的 bucket, 覆盖写入时保留旧版本
+    #[serde(default,skip_serializing_if="Vec::is_empty")]
+    pub versioning: Vec<ConfigVersioning>,
+    /// bucket 生命周期规则, 由后台任务定期执行
+    #[serde(default,skip_serializing_if="Vec::is_empty")]
+    pub lifecycle: Vec<ConfigLifecycle>,
+    /// bucket 容量配额, 写入超出配额时中断上传
+    #[serde(default,skip_serializing_if="Vec::is_empty")]
+    pub quota: Vec<ConfigQuota>,
+    /// 对象变化时向队列发送事件
+    #[serde(default,skip_serializing_if="Vec::is_empty")]
+    pub notify: Vec<ConfigNotify>,
+    /// 生命周期规则的执行间隔(秒)
+    #[serde(default="default_lifecycle_interval",rename="lifecycle-interval")]
+    pub lifecycle_interval: u64,
+    /// 解包上传的压缩包时的限制
+    #[serde(default)]
+    pub extract: ConfigExtract,
 }
 
 impl Default for ConfigStorage {
@@ -66,10 +230,254 @@ impl Default for ConfigStorage {
         Self {
             workspace: "./data".to_string(),
             public: "./data/public".to_string(),
+            backend: StorageBackend::default(),
+            versioning: vec![],
+            lifecycle: vec![],
+            quota: vec![],
+            notify: vec![],
+            lifecycle_interval: default_lifecycle_interval(),
+            extract: ConfigExtract::default(),
+        }
+    }
+}
+
+/*
+[[onlinelog.rotate]]
+channel="build-*"
+max-size=104857600
+daily=true
+max-segments=10
+max-age-days=30
+compress=true
+*/
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ConfigLogRotate {
+    /// 日志 channel 名称, 支持 glob 通配符
+    pub channel: String,
+    /// 日志文件超过该大小时切分
+    #[serde(default,rename="max-size",skip_serializing_if="Option::is_none")]
+    pub max_size: Option<u64>,
+    /// 每天切分一次
+    #[serde(default)]
+    pub daily: bool,
+    /// 最多保留的历史分段数量
+    #[serde(default,rename="max-segments",skip_serializing_if="Option::is_none")]
+    pub max_segments: Option<usize>,
+    /// 历史分段最多保留的天数
+    #[serde(default,rename="max-age-days",skip_serializing_if="Option::is_none")]
+    pub max_age_days: Option<u64>,
+    /// 历史分段是否使用 gzip 压缩
+    #[serde(default="default_true")]
+    pub compress: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_rotate_interval() -> u64 {
+    60
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConfigOnlineLog {
+    /// 日志切分和保留规则
+    #[serde(default,skip_serializing_if="Vec::is_empty")]
+    pub rotate: Vec<ConfigLogRotate>,
+    /// 后台检查按天切分和清理历史分段的间隔(秒)
+    #[serde(default="default_rotate_interval",rename="rotate-interval")]
+    pub rotate_interval: u64,
+}
+
+impl Default for ConfigOnlineLog {
+    fn default() -> Self {
+        Self {
+            rotate: vec![],
+            rotate_interval: default_rotate_interval(),
+        }
+    }
+}
+
+/*
+[handles]
+idle-timeout=600
+max-open=1024
+sweep-interval=30
+*/
+fn default_sweep_interval() -> u64 {
+    30
+}
+
+/// 追加写入和在线日志打开的文件句柄
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ConfigHandles {
+    /// 超过该时间(秒)没有写入的句柄被关闭, 默认不关闭
+    #[serde(default,rename="idle-timeout",skip_serializing_if="Option::is_none")]
+    pub idle_timeout: Option<u64>,
+    /// 最多同时打开的句柄数量, 超过时关闭最久没有写入的句柄, 默认不限制
+    #[serde(default,rename="max-open",skip_serializing_if="Option::is_none")]
+    pub max_open: Option<usize>,
+    /// 后台检查空闲句柄的间隔(秒)
+    #[serde(default="default_sweep_interval",rename="sweep-interval")]
+    pub sweep_interval: u64,
+}
+
+impl Default for ConfigHandles {
+    fn default() -> Self {
+        Self {
+            idle_timeout: None,
+            max_open: None,
+            sweep_interval: default_sweep_interval(),
+        }
+    }
+}
+
+/*
+[idempotency]
+window=300
+max-keys=100000
+sweep-interval=30
+*/
+fn default_idempotency_window() -> u64 {
+    300
+}
+
+fn default_idempotency_max_keys() -> usize {
+    100000
+}
+
+/// put 的 Idempotency-Key / dedup_id, 窗口内相同的 key 只放入队列一次
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ConfigIdempotency {
+    /// 记住 key 的时间(秒)
+    #[serde(default="default_idempotency_window")]
+    pub window: u64,
+    /// 每个队列最多记住的 key 数量, 超过时忘记最早的 key, 必须大于 0
+    #[serde(default="default_idempotency_max_keys",rename="max-keys")]
+    pub max_keys: usize,
+    /// 后台清理过期 key 的间隔(秒)
+    #[serde(default="default_sweep_interval",rename="sweep-interval")]
+    pub sweep_interval: u64,
+}
+
+impl Default for ConfigIdempotency {
+    fn default() -> Self {
+        Self {
+            window: default_idempotency_window(),
+            max_keys: default_idempotency_max_keys(),
+            sweep_interval: default_sweep_interval(),
         }
     }
 }
 
+/*
+[ack]
+timeout=30
+*/
+fn default_ack_timeout() -> u64 {
+    30
+}
+
+/// 需要确认的投递(websocket, stomp, grpc, mqtt)
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ConfigAck {
+    /// 投递后超过该时间(秒)没有确认时放回队列, grpc Get 可以用 ack_timeout 覆盖
+    #[serde(default="default_ack_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for ConfigAck {
+    fn default() -> Self {
+        Self {
+            timeout: default_ack_timeout(),
+        }
+    }
+}
+
+/*
+[resp]
+bind="127.0.0.1:6379"
+*/
+/// 兼容 redis 协议(RESP)的队列服务
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConfigResp {
+    pub bind: String,
+}
+
+/*
+[stomp]
+bind="127.0.0.1:61613"
+*/
+/// STOMP 1.2 tcp 服务, websocket 使用 "/msg/stomp"
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConfigStomp {
+    pub bind: String,
+}
+
+/*
+[grpc]
+bind="127.0.0.1:50051"
+*/
+/// grpc 服务, 接口定义在 proto/ssequeue.proto
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConfigGrpc {
+    pub bind: String,
+}
+
+/*
+[mqtt]
+bind="127.0.0.1:1883"
+*/
+/// MQTT 3.1.1 / 5 服务, "queue/{name}" 主题对应队列
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConfigMqtt {
+    pub bind: String,
+}
+
+fn default_webhook_attempts() -> u32 {
+    5
+}
+
+fn default_webhook_backoff() -> u64 {
+    1000
+}
+
+fn default_webhook_timeout() -> u64 {
+    10
+}
+
+/*
+[[webhooks]]
+queue="orders"
+url="http://127.0.0.1:9000/hook"
+secret="change-me"
+max-attempts=5
+backoff-ms=1000
+timeout=10
+dead-letter="orders.dlq"
+*/
+/// 推送订阅, 后台从队列中取出消息 POST 到 url
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ConfigWebhook {
+    pub queue: String,
+    pub url: String,
+    /// 设置后请求带上 HMAC-SHA256 签名
+    #[serde(default,skip_serializing_if="Option::is_none")]
+    pub secret: Option<String>,
+    /// 每条消息最多尝试的次数, 超过后转入死信队列
+    #[serde(default="default_webhook_attempts",rename="max-attempts")]
+    pub max_attempts: u32,
+    /// 第一次重试前等待的毫秒数, 之后每次翻倍
+    #[serde(default="default_webhook_backoff",rename="backoff-ms")]
+    pub backoff_ms: u64,
+    /// 单次请求的超时(秒)
+    #[serde(default="default_webhook_timeout")]
+    pub timeout: u64,
+    /// 死信队列, 默认为 "{queue}.dlq"
+    #[serde(default,rename="dead-letter",skip_serializing_if="Option::is_none")]
+    pub dead_letter: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize, Default)]
 pub struct Config {
     pub server: ConfigServer,
@@ -78,13 +486,46 @@ pub struct Config {
     #[serde(default,skip_serializing_if="Option::is_none")]
     pub auth: Option<ConfigAuth>,
     pub storage: ConfigStorage,
+    #[serde(default)]
+    pub onlinelog: ConfigOnlineLog,
+    #[serde(default)]
+    pub handles: ConfigHandles,
+    #[serde(default)]
+    pub idempotency: ConfigIdempotency,
+    #[serde(default)]
+    pub ack: ConfigAck,
+    #[serde(default,skip_serializing_if="Option::is_none")]
+    pub resp: Option<ConfigResp>,
+    #[serde(default,skip_serializing_if="Option::is_none")]
+    pub stomp: Option<ConfigStomp>,
+    #[serde(default,skip_serializing_if="Option::is_none")]
+    pub grpc: Option<ConfigGrpc>,
+    #[serde(default,skip_serializing_if="Option::is_none")]
+    pub mqtt: Option<ConfigMqtt>,
+    /// 启动时注册的推送订阅
+    #[serde(default,skip_serializing_if="Vec::is_empty")]
+    pub webhooks: Vec<ConfigWebhook>,
 }
 
 impl Config {
     /// 从 toml 配置中加载配置
     pub fn load(config_path: impl AsRef<std::path::Path>) -> anyhow::Result<Self> {
         let config = std::fs::read_to_string(&config_path)?;
-        Ok(toml::from_str(&config)?)
+        let config: Self = toml::from_str(&config)?;
+        config.check()?;
+        Ok(config)
+    }
+    /// 检查无法用类型表达的配置约束
+    fn check(&self) -> anyhow::Result<()> {
+        for rule in &self.storage.notify {
+            if rule.queue.is_none() && rule.topic.is_none() {
+                anyhow::bail!("storage.notify for bucket {} requires queue or topic", rule.bucket);
+            }
+        }
+        if self.idempotency.max_keys == 0 {
+            anyhow::bail!("idempotency.max-keys must be greater than 0");
+        }
+        Ok(())
     }
     /// 将配置保存到 toml 文件中
     #[allow(unused)]
@@ -50,6 +50,13 @@ fn parse_cli() -> clap::Command {
                 .required(false)
                 .help("https ssl key file path"),
         )
+        .arg(
+            clap::Arg::new("gc_blobs")
+                .long("gc-blobs")
+                .required(false)
+                .action(clap::ArgAction::SetTrue)
+                .help("remove unreferenced blobs of dedup storage and exit"),
+        )
 }
 fn main() -> anyhow::Result<()> {
     let cli = parse_cli();
@@ -67,6 +74,9 @@ fn main() -> anyhow::Result<()> {
         loginit::init_from_env(None);
     }
     log::debug!("\n== config ==\n{cfg:?}\n== ==");
+    if matches.get_flag("gc_blobs") {
+        return apps::gc_blobs(&cfg);
+    }
     apps::server_up(&cfg)?;
     println!("ok");
     Ok(())
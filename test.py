@@ -4,8 +4,10 @@ import time
 import io
 
 
+config = toml.load("data/config.toml")
+
+
 def load_server():
-    config = toml.load("data/config.toml")
     https_on = "ssl" in config
     bind = config["server"]["bind"]
     prefix = "https" if https_on else "http"
@@ -136,6 +138,11 @@ class Storage:
         result = requests.get(f"{server}/storage/del/{bucket}/{name}",params=params).json()
         assert result["ok"], result["msg"]
 
+    def list(self, bucket: str, **params) -> dict:
+        result = requests.get(f"{server}/storage/list/{bucket}", params=params).json()
+        assert result["ok"], result["msg"]
+        return result["data"]
+
     def get_io(self, bucket: str, name: str) -> io.BufferedReader:
         res = requests.get(f"{server}/storage/get/{bucket}/{name}", stream=True)
         assert res.status_code == 200, f"get {bucket}/{name} failed"
@@ -201,6 +208,137 @@ def test_last_first():
     print("first:", first)
 
 
+def test_put_idempotency():
+    print("== put idempotency ==")
+    dedup_queue = "dedup-test"
+    key = f"job-{time.time()}"
+    headers = {"Idempotency-Key": key}
+    first = requests.post(f"{server}/msg/{dedup_queue}/put", data="job", headers=headers).json()
+    assert first["data"]["duplicate"] is False, first
+    retry = requests.post(f"{server}/msg/{dedup_queue}/put", params={"dedup_id": key}, data="job").json()
+    assert retry["data"] == {"id": first["data"]["id"], "duplicate": True}, retry
+    assert Msg(dedup_queue).get()["data"] == "job"
+    assert Msg(dedup_queue).get()["data"] is None, "duplicate put should not be queued"
+    window = config.get("idempotency", {}).get("window", 300)
+    if window <= 3:
+        # 超出窗口的 key 不再去重, 不需要等待后台清理
+        time.sleep(window + 0.5)
+        again = requests.post(f"{server}/msg/{dedup_queue}/put", data="job", headers=headers).json()
+        assert again["data"]["duplicate"] is False, again
+        assert Msg(dedup_queue).get()["data"] == "job"
+
+
+def test_message_group():
+    print("== message group ==")
+    group_queue = "group-test"
+    for data, group in [("a1", "a"), ("a2", "a"), ("b1", "b")]:
+        result = requests.post(f"{server}/msg/{group_queue}/put", params={"group": group}, data=data).json()
+        assert result["ok"], result["msg"]
+    # a2 等待 a1 被取出后才放入队列
+    result = [Msg(group_queue).get()["data"] for _ in range(3)]
+    assert result == ["a1", "b1", "a2"], result
+    # 内容相同的消息按所在的组区分, 取出不带组的消息不会放入组内的下一条消息
+    for data, group in [("x", None), ("x", "g"), ("y", "g")]:
+        result = requests.post(f"{server}/msg/{group_queue}/put", params={"group": group}, data=data).json()
+        assert result["ok"], result["msg"]
+    assert Msg(group_queue).get()["data"] == "x"
+    assert Msg(group_queue).pick()["data"] == "x"
+    assert Msg(group_queue).pick(1)["data"] is None
+    result = [Msg(group_queue).get()["data"] for _ in range(3)]
+    assert result == ["x", "y", None], result
+
+
+def test_webhook():
+    print("== webhook ==")
+    import hashlib
+    import hmac
+    import threading
+    from http.server import BaseHTTPRequestHandler, HTTPServer
+
+    received = []
+
+    class Hook(BaseHTTPRequestHandler):
+        def do_POST(self):
+            body = self.rfile.read(int(self.headers["Content-Length"]))
+            received.append((self.headers, body))
+            # "fail" 第一次返回 500, 之后成功; "reject" 一直返回 400
+            retry = body == b"fail" and int(self.headers["X-Attempt"]) == 1
+            code = 400 if body == b"reject" else 500 if retry else 200
+            self.send_response(code)
+            self.send_header("Content-Length", "0")
+            self.end_headers()
+
+        def log_message(self, *args):
+            pass
+
+    hook_server = HTTPServer(("127.0.0.1", 0), Hook)
+    threading.Thread(target=hook_server.serve_forever, daemon=True).start()
+    url = f"http://127.0.0.1:{hook_server.server_port}/hook"
+    hook_queue = "webhook-test"
+    result = requests.post(
+        f"{server}/msg/{hook_queue}/webhook",
+        json={"url": url, "secret": "s3cret", "backoff_ms": 100, "max_attempts": 3},
+    ).json()
+    assert result["ok"], result["msg"]
+    hook_id = result["data"]
+    try:
+        for data in ["hello", "fail", "reject"]:
+            assert Msg(hook_queue).put(data)["ok"]
+        time.sleep(2)
+        bodies = [body for _, body in received]
+        assert bodies == [b"hello", b"fail", b"fail", b"reject"], bodies
+        headers, body = received[0]
+        signed = f"{headers['X-Timestamp']}.".encode() + body
+        signature = hmac.new(b"s3cret", signed, hashlib.sha256).hexdigest()
+        assert headers["X-Signature-256"] == f"sha256={signature}", headers
+        result = requests.get(f"{server}/msg/{hook_queue}/webhook").json()
+        status = [s for s in result["data"] if s["id"] == hook_id][0]
+        assert status["delivered"] == 2 and status["dead_lettered"] == 1, status
+        assert "secret" not in status, status
+        result = Msg(f"{hook_queue}.dlq").get()
+        assert result["data"] == "reject", result
+    finally:
+        requests.get(f"{server}/msg/{hook_queue}/webhook/del/{hook_id}")
+        hook_server.shutdown()
+
+
+def test_call():
+    print("== call / reply ==")
+    import json
+    import threading
+
+    rpc_queue = "rpc-test"
+
+    def worker():
+        result = Msg(rpc_queue).get(timeout=5)
+        request = json.loads(result["data"])
+        assert request["reply_to"] == f"_reply.{request['correlation_id']}", request
+        requests.post(f"{server}/msg/_reply/{request['correlation_id']}", data=request["data"].upper())
+
+    threading.Thread(target=worker).start()
+    result = requests.post(f"{server}/msg/{rpc_queue}/call", params={"timeout": 5}, data="ping").json()
+    assert result["data"] == "PING", result
+    result = requests.post(f"{server}/msg/{rpc_queue}/call", params={"timeout": 1}, data="nobody").json()
+    assert result["ok"] and result["data"] is None, result
+    time.sleep(0.2)
+    assert Msg(rpc_queue).get()["data"] is None, "timed out request should be removed"
+    result = requests.post(f"{server}/msg/_reply/unknown", data="late").json()
+    assert not result["ok"], result
+
+
+def test_multi_get():
+    print("== multi get ==")
+    Msg("multi.low").put("low")
+    Msg("multi.high").put("high")
+    params = {"queue": "multi.high,multi.low", "order": "priority"}
+    result = requests.get(f"{server}/msg/get", params=params).json()
+    assert result["data"] == {"queue": "multi.high", "data": "high"}, result
+    result = requests.get(f"{server}/msg/get", params={"queue": "multi.*", "timeout": 1}).json()
+    assert result["data"] == {"queue": "multi.low", "data": "low"}, result
+    result = requests.get(f"{server}/msg/get", params={"queue": "multi.*"}).json()
+    assert result["ok"] and result["data"] is None, result
+
+
 def test_upload_file():
     print("== upload file ==")
     s = Storage()
@@ -241,6 +379,277 @@ def test_download_stream():
         print(line.decode(), end="")
 
 
+def test_nested_keys():
+    print("== nested keys ==")
+    s = Storage()
+    result = s.put("test-nested", "a/b/c.txt", b"hello")
+    assert result["ok"], result["msg"]
+    assert s.get("test-nested", "a/b/c.txt") == b"hello"
+    names = [e["name"] for e in s.list("test-nested")["entries"]]
+    assert "a/" in names, names
+    entries = s.list("test-nested", recursive="true")["entries"]
+    assert entries[0]["name"] == "a/b/c.txt" and entries[0]["size"] == 5, entries
+    s.remove_file("test-nested", "a/b/c.txt")
+
+
+def test_checksum():
+    print("== checksum ==")
+    import base64
+    import hashlib
+    data = b"hello checksum"
+    md5 = base64.b64encode(hashlib.md5(data).digest()).decode()
+    result = requests.post(
+        f"{server}/storage/put/test/file-checksum", data=data, headers={"Content-MD5": md5}
+    ).json()
+    assert result["ok"], result["msg"]
+    res = requests.head(f"{server}/storage/get/test/file-checksum")
+    assert res.headers["x-checksum-sha256"] == hashlib.sha256(data).hexdigest()
+    result = requests.post(
+        f"{server}/storage/put/test/file-checksum", data=b"corrupted", headers={"Content-MD5": md5}
+    ).json()
+    assert not result["ok"], result
+    # 校验失败的覆盖写入不会影响原来的内容
+    assert Storage().get("test", "file-checksum") == data
+    Storage().remove_file("test", "file-checksum", exists_ok=True)
+
+
+def test_copy_move():
+    print("== copy / move ==")
+    storage = Storage()
+    storage.put("test", "copy/src.txt", b"copy me")
+    result = requests.get(
+        f"{server}/storage/copy/test/copy/src.txt", params={"to_name": "copy/dst.txt"}
+    ).json()
+    assert result["ok"], result["msg"]
+    res = requests.get(f"{server}/storage/copy/test/copy/src.txt", params={"to_name": "copy/dst.txt"})
+    assert res.status_code == 409, res.text
+    result = requests.get(
+        f"{server}/storage/move/test/copy/dst.txt", params={"to_name": "copy/moved.txt"}
+    ).json()
+    assert result["ok"], result["msg"]
+    assert storage.get("test", "copy/moved.txt") == b"copy me"
+    storage.remove_file("test", "copy/src.txt", exists_ok=True)
+    storage.remove_file("test", "copy/moved.txt", exists_ok=True)
+
+
+def test_archive():
+    print("== archive / extract ==")
+    import tarfile
+    storage = Storage()
+    storage.put("test", "archive/a.txt", b"aaa")
+    storage.put("test", "archive/sub/b.txt", b"bbb")
+    res = requests.get(
+        f"{server}/storage/archive/test", params={"format": "tar.gz", "prefix": "archive/"}
+    )
+    assert res.status_code == 200, res.text
+    with tarfile.open(fileobj=io.BytesIO(res.content), mode="r:gz") as tar:
+        assert tar.getnames() == ["archive/a.txt", "archive/sub/b.txt"], tar.getnames()
+    result = requests.post(
+        f"{server}/storage/extract/test", params={"prefix": "extracted/"}, data=res.content
+    ).json()
+    assert result["ok"], result["msg"]
+    assert storage.get("test", "extracted/archive/sub/b.txt") == b"bbb"
+    for name in ["archive/a.txt", "archive/sub/b.txt", "extracted/archive/a.txt", "extracted/archive/sub/b.txt"]:
+        storage.remove_file("test", name, exists_ok=True)
+
+
+def rule_bucket(section):
+    """按 [[storage.<section>]] 的第一条规则生成测试用的 bucket, 没有配置时返回 None"""
+    rules = config.get("storage", {}).get(section)
+    if not rules:
+        print(f"skip storage.{section}: not configured")
+        return None
+    return rules[0]["bucket"].replace("*", "test")
+
+
+def test_versions():
+    print("== versions / restore ==")
+    import hashlib
+    bucket = rule_bucket("versioning")
+    if bucket is None:
+        return
+    storage = Storage()
+    storage.remove_file(bucket, "ver.txt", exists_ok=True)
+    for data in [b"version 1", b"version 2"]:
+        result = storage.put(bucket, "ver.txt", data)
+        assert result["ok"], result["msg"]
+    versions = requests.get(f"{server}/storage/versions/{bucket}/ver.txt").json()["data"]
+    assert len(versions) == 1 and versions[0]["size"] == 9, versions
+    version_id = versions[0]["version_id"]
+    res = requests.get(f"{server}/storage/version/{bucket}/ver.txt", params={"version_id": version_id})
+    assert res.content == b"version 1", res.content
+    result = requests.get(
+        f"{server}/storage/restore/{bucket}/ver.txt", params={"version_id": version_id}
+    ).json()
+    assert result["ok"], result["msg"]
+    assert storage.get(bucket, "ver.txt") == b"version 1"
+    # 恢复后重新保存了摘要, 恢复前的内容成为新的版本
+    res = requests.head(f"{server}/storage/get/{bucket}/ver.txt")
+    assert res.headers["x-checksum-sha256"] == hashlib.sha256(b"version 1").hexdigest()
+    versions = requests.get(f"{server}/storage/versions/{bucket}/ver.txt").json()["data"]
+    assert versions[0]["size"] == 9, versions
+    res = requests.get(f"{server}/storage/version/{bucket}/ver.txt", params={"version_id": versions[0]["version_id"]})
+    assert res.content == b"version 2", res.content
+    result = requests.get(
+        f"{server}/storage/restore/{bucket}/ver.txt", params={"version_id": "0"}
+    ).json()
+    assert not result["ok"], result
+    storage.remove_file(bucket, "ver.txt", exists_ok=True)
+
+
+def test_lifecycle_report():
+    print("== lifecycle report ==")
+    bucket = rule_bucket("lifecycle")
+    if bucket is None:
+        return
+    rule = config["storage"]["lifecycle"][0]
+    if "max-objects" not in rule:
+        print("skip lifecycle report: max-objects not configured")
+        return
+    storage = Storage()
+    names = [f"life-{i}.txt" for i in range(rule["max-objects"] + 1)]
+    for name in names:
+        assert storage.put(bucket, name, b"lifecycle")["ok"]
+        time.sleep(1.1)
+    reports = requests.get(f"{server}/storage/lifecycle/report/{bucket}").json()["data"]
+    actions = reports[0]["actions"]
+    # 后台任务可能已经执行了删除
+    if actions:
+        assert [(a["name"], a["reason"]) for a in actions] == [(names[0], "max-objects")], actions
+        assert reports[0]["freed_bytes"] == 9, reports
+    interval = config["storage"].get("lifecycle-interval", 3600)
+    if interval <= 5:
+        time.sleep(interval + 1)
+        kept = [e["name"] for e in storage.list(bucket)["entries"]]
+        assert kept == names[1:], kept
+    for name in names:
+        storage.remove_file(bucket, name, exists_ok=True)
+
+
+def test_quota():
+    print("== quota ==")
+    bucket = rule_bucket("quota")
+    if bucket is None:
+        return
+    rule = config["storage"]["quota"][0]
+    storage = Storage()
+    max_bytes = rule.get("max-bytes")
+    if max_bytes is not None:
+        res = requests.post(f"{server}/storage/put/{bucket}/full.bin", data=b"x" * (max_bytes + 1))
+        assert res.status_code == 413 and not res.json()["ok"], res.text
+        assert storage.list(bucket)["entries"] == [], "rejected upload should be rolled back"
+        assert storage.put(bucket, "full.bin", b"x" * max_bytes)["ok"]
+        res = requests.post(f"{server}/storage/append/{bucket}/full.bin", data=b"y")
+        assert res.status_code == 413, res.text
+        storage.close_append(bucket, "full.bin")
+        assert storage.get(bucket, "full.bin") == b"x" * max_bytes
+        usage = requests.get(f"{server}/storage/usage/{bucket}").json()["data"]
+        assert usage["usage"] == {"bytes": max_bytes, "objects": 1}, usage
+        # 覆盖写入时释放被替换对象的空间
+        assert storage.put(bucket, "full.bin", b"z" * max_bytes)["ok"]
+        storage.remove_file(bucket, "full.bin")
+    max_objects = rule.get("max-objects")
+    if max_objects is not None:
+        names = [f"obj-{i}" for i in range(max_objects)]
+        for name in names:
+            assert storage.put(bucket, name, b"o")["ok"]
+        res = requests.post(f"{server}/storage/put/{bucket}/obj-extra", data=b"o")
+        assert res.status_code == 413, res.text
+        for name in names:
+            storage.remove_file(bucket, name)
+
+
+def test_notify():
+    print("== notify ==")
+    import json
+    rules = [r for r in config.get("storage", {}).get("notify", []) if "queue" in r]
+    if not rules:
+        print("skip storage.notify: no queue configured")
+        return
+    rule = rules[0]
+    bucket = rule["bucket"].replace("*", "test")
+    name = rule.get("prefix", "") + "notify.txt"
+    events = Msg(rule["queue"])
+    while events.get()["data"] is not None:
+        pass
+    storage = Storage()
+    assert storage.put(bucket, name, b"notify")["ok"]
+    storage.remove_file(bucket, name)
+    if rule.get("prefix"):
+        # 名称不匹配 prefix 的对象不发送事件
+        assert storage.put(bucket, "other.txt", b"other")["ok"]
+        storage.remove_file(bucket, "other.txt")
+    expected = [a for a in ["put", "delete"] if a in rule.get("actions", ["put", "delete"])]
+    received = []
+    while (data := events.get()["data"]) is not None:
+        received.append(json.loads(data))
+    assert [e["action"] for e in received] == expected, received
+    for event in received:
+        assert event["bucket"] == bucket and event["name"] == name and event["size"] == 6, event
+
+
+def sse_events(res):
+    """逐个读取 sse 事件, 返回 (event, id, data)"""
+    event, event_id, data = "message", None, []
+    for line in res.iter_lines(decode_unicode=True):
+        if line:
+            field, _, value = line.partition(":")
+            if field == "event":
+                event = value
+            elif field == "id":
+                event_id = value
+            elif field == "data":
+                data.append(value)
+        elif data or event != "message":
+            yield event, event_id, "\n".join(data)
+            event, event_id, data = "message", None, []
+
+
+def test_tail():
+    print("== tail ==")
+    storage = Storage()
+    storage.remove_file("test", "tail.log", exists_ok=True)
+    assert storage.append("test", "tail.log", b"line 1\n")["ok"]
+    res = requests.get(
+        f"{server}/storage/tail/test/tail.log", params={"lines": 1, "idle": 2}, stream=True, timeout=10
+    )
+    assert res.status_code == 200, res.text
+    events = sse_events(res)
+    assert next(events) == ("message", "7", "line 1")
+    assert storage.append("test", "tail.log", b"line 2\nline")["ok"]
+    assert next(events) == ("message", "14", "line 2")
+    # 没有换行符的内容在关闭后作为最后一行输出
+    storage.close_append("test", "tail.log")
+    assert [e for e in events] == [("message", "18", "line"), ("eof", "18", "")]
+    res.close()
+    storage.remove_file("test", "tail.log")
+
+
+def test_handles():
+    print("== handles ==")
+    limits = config.get("handles", {})
+    storage = Storage()
+    keys = [f"handle-{i}.log" for i in range(3)]
+    for name in keys:
+        result = requests.post(f"{server}/storage/append/test/{name}", params={"hold": "true"}, data=b"held\n").json()
+        assert result["ok"], result["msg"]
+        time.sleep(0.01)
+    handles = requests.get(f"{server}/storage/handles").json()["data"]
+    held = [h["key"] for h in handles if h["kind"] == "append" and h["key"].startswith("test/handle-")]
+    # 最近写入的在前, max-open 较小时更早的句柄可能已被关闭
+    assert held == [f"test/{name}" for name in reversed(keys)][: limits.get("max-open", len(keys))], handles
+    assert all(h["references"] == 0 for h in handles), handles
+    idle_timeout = limits.get("idle-timeout")
+    if idle_timeout is not None and idle_timeout <= 5:
+        time.sleep(idle_timeout + limits.get("sweep-interval", 30) + 1)
+        handles = requests.get(f"{server}/storage/handles").json()["data"]
+        assert not [h for h in handles if h["key"].startswith("test/handle-")], handles
+    for name in keys:
+        storage.close_append("test", name)
+        assert storage.get("test", name) == b"held\n"
+        storage.remove_file("test", name)
+
+
 def test_upload_log():
     print("== upload log ==")
     data = [f"lines {i}" for i in range(10)]
@@ -251,6 +660,500 @@ def test_upload_log():
     print(result.text)
 
 
+def test_query_log():
+    print("== query structured log ==")
+    lines = ['{"level":"info","msg":"start","svc":{"name":"api"}}', 'level=error msg="disk full" code=28']
+    for fmt, line in zip(["json", "logfmt"], lines):
+        result = requests.post(
+            f"{server}/onlinelog/upload/testlog/struct.log", params={"format": fmt}, data=line
+        )
+        assert result.status_code == 200, result.text
+    result = requests.post(
+        f"{server}/onlinelog/upload/testlog/struct.log", params={"format": "json"}, data="not json"
+    ).json()
+    assert not result["ok"], result
+    result = requests.get(
+        f"{server}/onlinelog/query/testlog/struct.log", params={"level": "error", "eq": "code:28"}
+    ).json()
+    assert result["ok"], result["msg"]
+    assert [r["msg"] for r in result["data"]["records"]][-1] == "disk full", result
+    assert all("_ts" in r and "_ip" in r for r in result["data"]["records"]), result
+    requests.get(f"{server}/onlinelog/close/testlog/struct.log")
+
+
+def test_read_log():
+    print("== read / grep log ==")
+    data = "\n".join(f"line {i}" for i in range(1, 21)) + "\n"
+    result = requests.post(f"{server}/onlinelog/upload/testlog/read.log", data=data)
+    assert result.status_code == 200, result.text
+    result = requests.get(f"{server}/onlinelog/read/testlog/read.log", params={"last": 2}).json()
+    assert [l["line"] for l in result["data"]["lines"]][-1] >= 20, result
+    result = requests.get(
+        f"{server}/onlinelog/grep/testlog", params={"name": "read.log", "pattern": "^line 1[0-9]$", "regex": True}
+    ).json()
+    assert result["ok"], result["msg"]
+    assert len(result["data"]["matches"]) >= 10, result
+    result = requests.get(f"{server}/onlinelog/list/testlog").json()
+    assert "read.log" in [log["name"] for log in result["data"][0]["logs"]], result
+    # 不指定 channel 时只列出日志 channel, 不包含普通的存储 bucket
+    Storage().put("test", "not-a-log.txt", b"data")
+    channels = [c["channel"] for c in requests.get(f"{server}/onlinelog/list").json()["data"]]
+    assert "testlog" in channels and "test" not in channels, channels
+    Storage().remove_file("test", "not-a-log.txt", exists_ok=True)
+    requests.get(f"{server}/onlinelog/close/testlog/read.log")
+
+
+def test_rotate_log():
+    print("== rotate log ==")
+    rules = config.get("onlinelog", {}).get("rotate")
+    if not rules or "max-size" not in rules[0]:
+        print("skip onlinelog.rotate: max-size not configured")
+        return
+    rule = rules[0]
+    channel = rule["channel"].replace("*", "test")
+    bucket = rule["channel"].replace("*", "bucket")
+    storage = Storage()
+    line = "x" * rule["max-size"]
+    uploads = rule.get("max-segments", 2) + 2
+    for i in range(uploads):
+        result = requests.post(
+            f"{server}/onlinelog/upload/{channel}/rot.log", params={"format": "lines"}, data=f"{i} {line}"
+        )
+        assert result.status_code == 200, result.text
+    # 超过 max-size 后切分, 当前文件只有最后一次写入的内容
+    result = requests.post(f"{server}/onlinelog/upload/{channel}/rot.log", params={"format": "lines"}, data="end")
+    assert result.status_code == 200, result.text
+    requests.get(f"{server}/onlinelog/close/{channel}/rot.log")
+    assert storage.get(channel, "rot.log") == b"end\n"
+    logs = requests.get(f"{server}/onlinelog/list/{channel}").json()["data"][0]["logs"]
+    assert [log["name"] for log in logs] == ["rot.log"], logs
+    result = requests.get(f"{server}/onlinelog/grep/{channel}", params={"pattern": "x"}).json()
+    assert result["data"]["matches"] == [], result
+    names = [e["name"] for e in storage.list(channel)["entries"]]
+    assert len(names) > 1 and all(n.startswith("rot.log.") for n in names if n != "rot.log"), names
+    interval = config["onlinelog"].get("rotate-interval", 60)
+    if interval <= 5:
+        # 同名的普通 bucket 不会被后台任务切分
+        assert storage.put(bucket, "rot.log", line.encode() * 2)["ok"]
+        time.sleep(interval * 2 + 1)
+        names = [e["name"] for e in storage.list(channel)["entries"]]
+        assert "rot.log" in names and all(n.count(".gz") <= 1 for n in names), names
+        if "max-segments" in rule:
+            assert len(names) <= rule["max-segments"] + 1, names
+        assert [e["name"] for e in storage.list(bucket)["entries"]] == ["rot.log"]
+        storage.remove_file(bucket, "rot.log")
+    for name in [e["name"] for e in storage.list(channel)["entries"]]:
+        storage.remove_file(channel, name, exists_ok=True)
+
+
+def connect_tcp(name):
+    """连接 [resp] / [stomp] / [mqtt] 配置的地址, 没有开启时返回 None"""
+    import socket
+    if name not in config:
+        print(f"skip {name}: not configured")
+        return None
+    host, port = config[name]["bind"].rsplit(":", 1)
+    sock = socket.create_connection((host, int(port)), timeout=5)
+    return sock
+
+
+class Resp:
+    def __init__(self, sock):
+        self.sock = sock
+        self.reader = sock.makefile("rb")
+
+    def call(self, *args):
+        out = f"*{len(args)}\r\n".encode()
+        for arg in args:
+            arg = arg if isinstance(arg, bytes) else str(arg).encode()
+            out += f"${len(arg)}\r\n".encode() + arg + b"\r\n"
+        self.sock.sendall(out)
+        return self.read()
+
+    def read(self):
+        line = self.reader.readline().rstrip(b"\r\n")
+        kind, rest = line[:1], line[1:]
+        if kind == b"+":
+            return rest.decode()
+        if kind == b"-":
+            return Exception(rest.decode())
+        if kind == b":":
+            return int(rest)
+        if kind == b"$":
+            n = int(rest)
+            if n < 0:
+                return None
+            data = self.reader.read(n + 2)
+            return data[:-2]
+        if kind == b"*":
+            n = int(rest)
+            return None if n < 0 else [self.read() for _ in range(n)]
+        raise ValueError(line)
+
+
+class WebSocket:
+    def __init__(self, path):
+        import base64
+        import os
+        import socket
+        host, port = config["server"]["bind"].rsplit(":", 1)
+        self.sock = socket.create_connection((host, int(port)), timeout=5)
+        key = base64.b64encode(os.urandom(16)).decode()
+        self.sock.sendall(
+            f"GET {path} HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n"
+            f"Sec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n".encode()
+        )
+        head = b""
+        while not head.endswith(b"\r\n\r\n"):
+            head += self.sock.recv(1)
+        assert b" 101 " in head.split(b"\r\n")[0], head
+
+    def send(self, obj, opcode=1):
+        import json
+        import os
+        import struct
+        data = obj if isinstance(obj, bytes) else json.dumps(obj).encode()
+        header = bytes([0x80 | opcode])
+        if len(data) < 126:
+            header += bytes([0x80 | len(data)])
+        else:
+            header += bytes([0x80 | 126]) + struct.pack(">H", len(data))
+        mask = os.urandom(4)
+        self.sock.sendall(header + mask + bytes(b ^ mask[i % 4] for i, b in enumerate(data)))
+
+    def read_exact(self, n):
+        buf = b""
+        while len(buf) < n:
+            chunk = self.sock.recv(n - len(buf))
+            if not chunk:
+                raise EOFError
+            buf += chunk
+        return buf
+
+    def recv(self, timeout=5):
+        """读取一个 json 文本帧, 超时返回 None"""
+        import json
+        import socket
+        import struct
+        self.sock.settimeout(timeout)
+        try:
+            header = self.read_exact(2)
+        except socket.timeout:
+            return None
+        self.sock.settimeout(5)
+        n = header[1] & 0x7F
+        if n == 126:
+            n = struct.unpack(">H", self.read_exact(2))[0]
+        elif n == 127:
+            n = struct.unpack(">Q", self.read_exact(8))[0]
+        data = self.read_exact(n)
+        if header[0] & 0x0F != 1:
+            return self.recv(timeout)
+        return json.loads(data)
+
+    def close(self):
+        self.send(b"", opcode=8)
+        self.sock.close()
+
+
+def test_websocket():
+    print("== websocket ==")
+    if "ssl" in config:
+        print("skip websocket: ssl")
+        return
+    ws_queue = "ws-test"
+    msg = Msg(ws_queue)
+    while msg.get()["data"] is not None:
+        pass
+    ws = WebSocket(f"/msg/{ws_queue}/ws")
+    for data in ["w1", "w2"]:
+        assert msg.put(data)["ok"]
+    first = ws.recv()
+    assert first["type"] == "message" and first["queue"] == ws_queue and first["data"] == "w1", first
+    # 默认 credit 为 1, 确认之前不会投递下一条
+    assert ws.recv(timeout=0.5) is None
+    ws.send({"op": "nack", "id": first["id"], "ref": 1})
+    assert ws.recv() == {"type": "ok", "op": "nack", "ref": 1}
+    # 放回队列的消息重新投递
+    again = ws.recv()
+    assert again["data"] == "w1" and again["id"] != first["id"], again
+    ws.send({"op": "ack", "id": again["id"], "ref": 2})
+    assert ws.recv() == {"type": "ok", "op": "ack", "ref": 2}
+    second = ws.recv()
+    assert second["data"] == "w2", second
+    ws.send({"op": "ack", "id": first["id"], "ref": 3})
+    result = ws.recv()
+    assert result["type"] == "error" and result["ref"] == 3, result
+    timeout = config.get("ack", {}).get("timeout", 30)
+    if timeout <= 5:
+        # 超时没有确认的消息放回队列, 之后的 ack 不再生效
+        redelivered = ws.recv(timeout=timeout + 2)
+        assert redelivered["data"] == "w2" and redelivered["id"] != second["id"], redelivered
+        ws.send({"op": "ack", "id": second["id"]})
+        assert ws.recv()["type"] == "error"
+    # 连接断开时未确认的消息放回队列
+    ws.close()
+    time.sleep(0.2)
+    assert msg.get()["data"] == "w2"
+    assert msg.get()["data"] is None
+
+
+def test_resp():
+    print("== resp ==")
+    sock = connect_tcp("resp")
+    if sock is None:
+        return
+    client = Resp(sock)
+    token = config.get("auth", {}).get("token")
+    result = client.call("AUTH", token or "anything")
+    if token:
+        assert result == "OK", result
+    else:
+        # 没有配置 token 时与 redis 相同返回错误
+        assert isinstance(result, Exception) and str(result).startswith("ERR"), result
+    resp_queue = "resp-test"
+    while client.call("RPOP", resp_queue) is not None:
+        pass
+    assert client.call("LPUSH", resp_queue, "r1", "r2") == 2
+    assert client.call("LLEN", resp_queue) == 2
+    assert client.call("BRPOP", resp_queue, 1) == [resp_queue.encode(), b"r1"]
+    assert client.call("BRPOP", resp_queue, "0.5") == [resp_queue.encode(), b"r2"]
+    assert client.call("BRPOP", resp_queue, "0.2") is None
+    result = client.call("BRPOP", resp_queue, "inf")
+    assert isinstance(result, Exception) and "out of range" in str(result), result
+    assert client.call("LLEN", resp_queue) == 0
+    sock.close()
+
+
+class Stomp:
+    def __init__(self, sock):
+        self.sock = sock
+        self.buf = b""
+
+    def send(self, command, headers=None, body=b""):
+        head = "".join(f"{k}:{v}\n" for k, v in (headers or {}).items())
+        self.sock.sendall(f"{command}\n{head}\n".encode() + body + b"\0")
+
+    def recv(self):
+        while b"\0" not in self.buf:
+            chunk = self.sock.recv(65536)
+            assert chunk, "connection closed"
+            self.buf += chunk
+        frame, self.buf = self.buf.split(b"\0", 1)
+        head, body = frame.lstrip(b"\n").split(b"\n\n", 1)
+        lines = head.decode().split("\n")
+        return lines[0], dict(line.split(":", 1) for line in lines[1:]), body
+
+
+def test_stomp():
+    print("== stomp ==")
+    sock = connect_tcp("stomp")
+    if sock is None:
+        return
+    client = Stomp(sock)
+    headers = {"accept-version": "1.2", "host": "/"}
+    token = config.get("auth", {}).get("token")
+    if token:
+        headers["passcode"] = token
+    client.send("CONNECT", headers)
+    command, headers, _ = client.recv()
+    assert command == "CONNECTED" and headers["version"] == "1.2", (command, headers)
+    stomp_queue = "stomp-test"
+    while Msg(stomp_queue).get()["data"] is not None:
+        pass
+    for data in [b"s1", b"s2"]:
+        client.send("SEND", {"destination": f"/queue/{stomp_queue}", "receipt": data.decode()}, data)
+        assert client.recv()[:2] == ("RECEIPT", {"receipt-id": data.decode()})
+    client.send("SUBSCRIBE", {"id": "0", "destination": f"/queue/{stomp_queue}", "ack": "client-individual", "prefetch-count": "1"})
+    command, headers, body = client.recv()
+    assert command == "MESSAGE" and body == b"s1", (command, headers, body)
+    # nack 放回队列, 下一次最先取出
+    client.send("NACK", {"id": headers["ack"]})
+    command, headers, body = client.recv()
+    assert command == "MESSAGE" and body == b"s1", (command, headers, body)
+    client.send("ACK", {"id": headers["ack"]})
+    command, headers, body = client.recv()
+    assert command == "MESSAGE" and body == b"s2", (command, headers, body)
+    client.send("ACK", {"id": headers["ack"], "receipt": "done"})
+    assert client.recv()[:2] == ("RECEIPT", {"receipt-id": "done"})
+    client.send("DISCONNECT", {"receipt": "bye"})
+    assert client.recv()[:2] == ("RECEIPT", {"receipt-id": "bye"})
+    sock.close()
+    assert Msg(stomp_queue).get()["data"] is None
+
+
+class Mqtt:
+    def __init__(self, sock):
+        self.sock = sock
+        self.reader = sock.makefile("rb")
+
+    @staticmethod
+    def string(value):
+        value = value.encode() if isinstance(value, str) else value
+        return len(value).to_bytes(2, "big") + value
+
+    def send(self, header, body):
+        size, n = b"", len(body)
+        while True:
+            b, n = n & 0x7F, n >> 7
+            size += bytes([b | (0x80 if n else 0)])
+            if not n:
+                break
+        self.sock.sendall(bytes([header]) + size + body)
+
+    def recv(self):
+        header = self.reader.read(1)
+        assert header, "connection closed"
+        n = shift = 0
+        while True:
+            b = self.reader.read(1)[0]
+            n |= (b & 0x7F) << shift
+            shift += 7
+            if b < 0x80:
+                break
+        return header[0] >> 4, header[0] & 0x0F, self.reader.read(n)
+
+    def connect(self, v5=False, properties=b""):
+        token = config.get("auth", {}).get("token")
+        flags = 0x02 | (0x40 | 0x80 if token else 0)
+        body = self.string("MQTT") + bytes([5 if v5 else 4, flags]) + (60).to_bytes(2, "big")
+        if v5:
+            body += bytes([len(properties)]) + properties
+        body += self.string("test-py")
+        if token:
+            body += self.string("token") + self.string(token)
+        self.send(0x10, body)
+        return self.recv()
+
+
+def test_mqtt():
+    print("== mqtt ==")
+    sock = connect_tcp("mqtt")
+    if sock is None:
+        return
+    client = Mqtt(sock)
+    assert client.connect() == (2, 0, b"\x00\x00")
+    mqtt_queue = "mqtt-test"
+    while Msg(mqtt_queue).get()["data"] is not None:
+        pass
+    topic = f"queue/{mqtt_queue}"
+    client.send(0x82, (1).to_bytes(2, "big") + client.string(topic) + b"\x01")
+    assert client.recv() == (9, 0, b"\x00\x01\x01")
+    # QoS 1 发布, 放入队列后回复 PUBACK
+    client.send(0x32, client.string(topic) + (2).to_bytes(2, "big") + b"m1")
+    assert client.recv() == (4, 0, b"\x00\x02")
+    kind, flags, body = client.recv()
+    assert kind == 3 and (flags >> 1) & 3 == 1, (kind, flags)
+    assert body[:len(topic) + 2] == client.string(topic), body
+    packet_id, payload = body[len(topic) + 2:len(topic) + 4], body[len(topic) + 4:]
+    assert payload == b"m1", payload
+    client.send(0x40, packet_id)
+    client.send(0xE0, b"")
+    sock.close()
+    assert Msg(mqtt_queue).get()["data"] is None
+
+    # MQTT 5 的 Receive Maximum 为 0 是协议错误
+    sock = connect_tcp("mqtt")
+    kind, _, body = Mqtt(sock).connect(v5=True, properties=b"\x21\x00\x00")
+    assert kind == 2 and body[1] == 0x82, body
+    sock.close()
+
+
+def pb_encode(*fields):
+    """编码 protobuf 消息, fields 为 (字段编号, int / str / bytes), 只用到 varint 和 length-delimited"""
+    def varint(n):
+        out = b""
+        while True:
+            b, n = n & 0x7F, n >> 7
+            out += bytes([b | (0x80 if n else 0)])
+            if not n:
+                return out
+
+    out = b""
+    for number, value in fields:
+        if isinstance(value, bool) or isinstance(value, int):
+            out += varint(number << 3) + varint(int(value))
+        else:
+            value = value.encode() if isinstance(value, str) else value
+            out += varint(number << 3 | 2) + varint(len(value)) + value
+    return out
+
+
+def pb_decode(data):
+    """解码 protobuf 消息, 返回 {字段编号: int / bytes}"""
+    fields = {}
+    pos = 0
+
+    def varint():
+        nonlocal pos
+        n = shift = 0
+        while True:
+            b = data[pos]
+            pos += 1
+            n |= (b & 0x7F) << shift
+            shift += 7
+            if b < 0x80:
+                return n
+
+    while pos < len(data):
+        key = varint()
+        if key & 7 == 0:
+            fields[key >> 3] = varint()
+        else:
+            n = varint()
+            fields[key >> 3] = data[pos:pos + n]
+            pos += n
+    return fields
+
+
+def test_grpc():
+    print("== grpc ==")
+    if "grpc" not in config:
+        print("skip grpc: not configured")
+        return
+    try:
+        import grpc
+    except ImportError:
+        print("skip grpc: grpcio is not installed")
+        return
+    channel = grpc.insecure_channel(config["grpc"]["bind"])
+    token = config.get("auth", {}).get("token")
+    metadata = [("token", token)] if token else None
+
+    def unary(method, *fields):
+        call = channel.unary_unary(method, request_serializer=lambda r: r, response_deserializer=pb_decode)
+        return call(pb_encode(*fields), metadata=metadata, timeout=5)
+
+    grpc_queue = "grpc-test"
+    while 1 in unary("/ssequeue.v1.Queue/Get", (1, grpc_queue)):
+        pass
+    reply = unary("/ssequeue.v1.Queue/Put", (1, grpc_queue), (2, b"g1"))
+    assert reply.get(1) == 1, reply
+    reply = unary("/ssequeue.v1.Queue/Get", (1, grpc_queue), (3, True))
+    message = pb_decode(reply[1])
+    assert message[2] == grpc_queue.encode() and message[3] == b"g1", message
+    assert unary("/ssequeue.v1.Queue/Ack", (1, message[1])).get(1) == 1
+    # 已经确认的消息再次确认时 found 为 false
+    assert unary("/ssequeue.v1.Queue/Ack", (1, message[1])).get(1) is None
+
+    upload = channel.stream_unary("/ssequeue.v1.Storage/Upload", request_serializer=lambda r: r, response_deserializer=pb_decode)
+    content = bytes(range(256)) * 1024
+    chunks = [pb_encode((1, "test"), (2, "grpc/obj.bin"), (3, content[:100000])), pb_encode((3, content[100000:]))]
+    assert upload(iter(chunks), metadata=metadata, timeout=5).get(1) == len(content)
+    download = channel.unary_stream("/ssequeue.v1.Storage/Download", request_serializer=lambda r: r, response_deserializer=pb_decode)
+    request = pb_encode((1, "test"), (2, "grpc/obj.bin"), (3, 1000), (4, 150000))
+    data = b"".join(chunk.get(1, b"") for chunk in download(request, metadata=metadata, timeout=5))
+    assert data == content[1000:151000], len(data)
+    # 隐藏目录不能作为 bucket
+    try:
+        upload(iter([pb_encode((1, ".blobs"), (2, "x.bin"), (3, b"x"))]), metadata=metadata, timeout=5)
+        assert False, "hidden bucket accepted"
+    except grpc.RpcError as err:
+        assert err.code() == grpc.StatusCode.INVALID_ARGUMENT, err
+    Storage().remove_file("test", "grpc/obj.bin", exists_ok=True)
+    channel.close()
+
+
 def release():
     test_ping()
     test_put()
@@ -259,6 +1162,11 @@ def release():
     test_listen()
     test_pick()
     test_last_first()
+    test_put_idempotency()
+    test_message_group()
+    test_webhook()
+    test_call()
+    test_multi_get()
 
 
 def release_storage():
@@ -266,10 +1174,36 @@ def release_storage():
     test_download_file()
     test_download_stream()
     test_append_file()
+    test_nested_keys()
+    test_checksum()
+    test_copy_move()
+    test_archive()
+    test_versions()
+    test_lifecycle_report()
+    test_quota()
+    test_notify()
+    test_tail()
+    test_handles()
+
+
+def release_log():
+    test_query_log()
+    test_read_log()
+    test_rotate_log()
+
+
+def release_protocols():
+    test_websocket()
+    test_resp()
+    test_stomp()
+    test_grpc()
+    test_mqtt()
 
 if __name__ == "__main__":
     release()
     release_storage()
+    release_log()
+    release_protocols()
     # test_upload_file()
     # test_append_file()
     # test_download_file()